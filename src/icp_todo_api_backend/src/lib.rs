@@ -1,23 +1,615 @@
+use candid::types::{Serializer, Type};
 use candid::CandidType;
 use ic_cdk::api::caller as caller_api;
+#[cfg(not(test))]
+use ic_cdk::api::stable::CanisterStableMemory;
+#[cfg(test)]
+use ic_cdk::api::stable::StableMemoryError;
+use ic_cdk::api::stable::{StableMemory, StableWriter};
+use candid::types::principal::PrincipalError;
 use ic_cdk::export::{candid, Principal};
 use serde::{Deserialize, Serialize};
-use std::collections::BTreeMap;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet};
 use std::cell::RefCell;
 use ic_cdk_macros::*;
 
 type PrincipalName = String;
 
+/// The text of a task. Texts longer than [COMPRESSION_THRESHOLD] bytes are
+/// kept deflated in memory; on the wire a [TaskText] is always plain `text`.
+#[derive(Clone, PartialEq, Eq)]
+pub struct TaskText {
+    compressed: bool,
+    bytes: Vec<u8>,
+}
+
+impl TaskText {
+    /// Returns the plain text, inflating it if it is stored compressed.
+    fn text(&self) -> Cow<'_, str> {
+        if self.compressed {
+            let bytes = miniz_oxide::inflate::decompress_to_vec(&self.bytes)
+                .expect("stored task text is not valid deflate data");
+            Cow::Owned(String::from_utf8(bytes).expect("stored task text is not UTF-8"))
+        } else {
+            String::from_utf8_lossy(&self.bytes)
+        }
+    }
+}
+
+impl From<String> for TaskText {
+    fn from(text: String) -> Self {
+        if text.len() > COMPRESSION_THRESHOLD.with(|ct| *ct) {
+            let deflated = miniz_oxide::deflate::compress_to_vec(text.as_bytes(), 6);
+            if deflated.len() < text.len() {
+                return TaskText {
+                    compressed: true,
+                    bytes: deflated,
+                };
+            }
+        }
+        TaskText {
+            compressed: false,
+            bytes: text.into_bytes(),
+        }
+    }
+}
+
+impl CandidType for TaskText {
+    fn _ty() -> Type {
+        Type::Text
+    }
+
+    fn idl_serialize<S: Serializer>(&self, serializer: S) -> Result<(), S::Error> {
+        serializer.serialize_text(&self.text())
+    }
+}
+
+impl Serialize for TaskText {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.text())
+    }
+}
+
+impl<'de> Deserialize<'de> for TaskText {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(TaskText::from)
+    }
+}
+
 #[derive(Clone, CandidType, Serialize, Deserialize)]
 pub struct Todo {
     id: u128,
+    task: TaskText,
+    tags: Vec<String>,
+    // Opaque references to externally stored assets (URLs or content hashes)
+    attachments: Vec<String>,
+    priority: u8,
+    due_at: Option<u64>,
+    completed: bool,
+    // Repeat interval in nanoseconds; completing the todo spawns the next occurrence.
+    recurrence: Option<u64>,
+    created_at: u64,
+    // When the todo last changed; its creation time until then
+    updated_at: u64,
+    last_op: TodoOp,
+    // Id of this todo in an external system the client syncs from
+    external_key: Option<String>,
+    // Locked todos can't be edited, completed or deleted
+    locked: bool,
+    // Archived todos are kept but left out of the active views
+    archived: bool,
+    // Display color as `#RRGGBB`
+    color: Option<String>,
+}
+
+/// A [Todo] with flags derived from it at the time of the query.
+#[derive(Clone, CandidType, Serialize, Deserialize)]
+pub struct AnnotatedTodo {
+    todo: Todo,
+    // Open and past its due date
+    is_overdue: bool,
+    // Open and due within [DUE_SOON_SECS]
+    is_due_soon: bool,
+    // Seconds since the todo was created
+    age_secs: u64,
+}
+
+/// The kind of the most recent change made to a [Todo].
+#[derive(Clone, Copy, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub enum TodoOp {
+    Added,
+    Updated,
+    /// The todo went from open to completed.
+    Completed,
+}
+
+impl Todo {
+    /// Whether the todo is past its due date at [now].
+    fn is_overdue(&self, now: u64) -> bool {
+        self.due_at.is_some_and(|due_at| due_at < now)
+    }
+
+    /// Records a change of kind [op] made to the todo just now.
+    fn touch(&mut self, op: TodoOp) {
+        self.last_op = op;
+        self.updated_at = now();
+    }
+
+    fn new(id: u128, task: String) -> Self {
+        Todo {
+            id,
+            task: task.into(),
+            tags: vec![],
+            attachments: vec![],
+            priority: 0,
+            due_at: None,
+            completed: false,
+            recurrence: None,
+            created_at: now(),
+            updated_at: now(),
+            last_op: TodoOp::Added,
+            external_key: None,
+            locked: false,
+            archived: false,
+            color: None,
+        }
+    }
+
+    /// Returns the occurrence that follows this recurring todo, due one
+    /// interval after this one.
+    fn next_occurrence(&self, id: u128, interval: u64) -> Self {
+        Todo {
+            id,
+            task: self.task.clone(),
+            tags: self.tags.clone(),
+            attachments: self.attachments.clone(),
+            priority: self.priority,
+            due_at: self.due_at.map(|due_at| due_at.saturating_add(interval)),
+            completed: false,
+            recurrence: Some(interval),
+            created_at: now(),
+            updated_at: now(),
+            last_op: TodoOp::Added,
+            external_key: None,
+            locked: false,
+            archived: false,
+            color: self.color.clone(),
+        }
+    }
+}
+
+/// Which optional parts of a [Todo] `get_todos_projected` returns.
+#[derive(Clone, Copy, CandidType, Serialize, Deserialize)]
+pub struct Projection {
+    include_tags: bool,
+    include_attachments: bool,
+    // priority, due date and recurrence
+    include_schedule: bool,
+}
+
+/// A lighter [Todo] for list views; parts not asked for are `None`.
+#[derive(Clone, CandidType, Serialize, Deserialize)]
+pub struct TodoView {
+    id: u128,
+    task: TaskText,
+    completed: bool,
+    tags: Option<Vec<String>>,
+    attachments: Option<Vec<String>>,
+    priority: Option<u8>,
+    due_at: Option<Option<u64>>,
+    recurrence: Option<Option<u64>>,
+}
+
+impl TodoView {
+    fn project(todo: &Todo, projection: Projection) -> Self {
+        let schedule = projection.include_schedule;
+        TodoView {
+            id: todo.id,
+            task: todo.task.clone(),
+            completed: todo.completed,
+            tags: projection.include_tags.then(|| todo.tags.clone()),
+            attachments: projection.include_attachments.then(|| todo.attachments.clone()),
+            priority: schedule.then_some(todo.priority),
+            due_at: schedule.then_some(todo.due_at),
+            recurrence: schedule.then_some(todo.recurrence),
+        }
+    }
+}
+
+/// A partial update of a [Todo]: only the `Some` fields are applied.
+#[derive(Clone, CandidType, Serialize, Deserialize)]
+pub struct TodoPatch {
+    id: u128,
+    task: Option<String>,
+    priority: Option<u8>,
+    due_at: Option<Option<u64>>,
+    completed: Option<bool>,
+}
+
+/// The new task for a [Todo], as `update_todo` takes it. This is the shape
+/// [Todo] first had, so clients built against it keep working as [Todo]
+/// grows.
+#[derive(Clone, CandidType, Serialize, Deserialize)]
+pub struct TodoUpdate {
+    id: u128,
+    task: String,
+}
+
+/// The todo field a list is ordered by.
+#[derive(Clone, Copy, CandidType, Serialize, Deserialize)]
+pub enum SortKey {
+    Id,
+    Priority,
+    /// Undated todos come after dated ones.
+    DueAt,
+    CreatedAt,
+}
+
+/// An ordering over a user's todos; ties break by ascending id.
+#[derive(Clone, Copy, CandidType, Serialize, Deserialize)]
+pub struct SortSpec {
+    key: SortKey,
+    descending: bool,
+}
+
+/// Per-user settings.
+#[derive(Clone, Default, CandidType, Serialize, Deserialize)]
+pub struct UserPrefs {
+    // Anyone may read this user's todos through `get_public_todos`
+    public: bool,
+    // Order applied by `get_todos`; insertion order when unset
+    default_sort: Option<SortSpec>,
+    on_full: FullPolicy,
+    // Task bodies for `add_from_template`, by name
+    templates: BTreeMap<String, String>,
+    // Focus mode: `get_todos` shows only this many open todos
+    focus: Option<u64>,
+}
+
+/// What adding a todo does when the user's list is at its limit.
+#[derive(Clone, Copy, Default, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub enum FullPolicy {
+    /// The add is rejected.
+    #[default]
+    Reject,
+    /// The oldest completed, unlocked todo is deleted to make room.
+    DropOldestCompleted,
+}
+
+/// Progress over a user's todos, as a pair to keep the reply deterministic.
+#[derive(Clone, CandidType, Serialize, Deserialize)]
+pub struct CompletionRate {
+    completed: u64,
+    total: u64,
+    // Completions today and on the six days before, deleted todos included
+    completed_last_7_days: u64,
+}
+
+/// A user's todos partitioned for a board view. Every todo lands in
+/// exactly one group.
+#[derive(Clone, Default, CandidType, Serialize, Deserialize)]
+pub struct GroupedTodos {
+    active: Vec<Todo>,
+    completed: Vec<Todo>,
+    overdue: Vec<Todo>,
+}
+
+/// Selects todos for bulk edits. Every field that is set must match;
+/// an empty filter matches every todo.
+#[derive(Clone, CandidType, Serialize, Deserialize)]
+pub struct Filter {
+    tag: Option<String>,
+    overdue: Option<bool>,
+    completed: Option<bool>,
+}
+
+impl Filter {
+    /// Whether [todo] passes this filter at [now].
+    fn matches(&self, todo: &Todo, now: u64) -> bool {
+        self.tag.as_ref().is_none_or(|tag| todo.tags.contains(tag))
+            && self.overdue.is_none_or(|overdue| todo.is_overdue(now) == overdue)
+            && self.completed.is_none_or(|completed| todo.completed == completed)
+    }
+}
+
+/// What happened to a todo in a [ChangeEntry].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub enum ChangeKind {
+    Added,
+    Updated,
+    Deleted,
+}
+
+/// One entry of a user's change feed.
+#[derive(Clone, CandidType, Serialize, Deserialize)]
+pub struct ChangeEntry {
+    seq: u64,
+    kind: ChangeKind,
+    todo_id: u128,
+    at: u64,
+}
+
+/// A user's change feed: the most recent [MAX_CHANGES_PER_USER] entries,
+/// plus the seq the next entry will get.
+#[derive(Clone, Default, CandidType, Serialize, Deserialize)]
+pub struct ChangeLog {
+    next_seq: u64,
+    entries: Vec<ChangeEntry>,
+}
+
+/// Reply of `changes_since`. When [truncated] is set, entries the cursor
+/// asked for were already dropped and the client must do a full sync.
+#[derive(Clone, CandidType, Serialize, Deserialize)]
+pub struct ChangesSince {
+    changes: Vec<ChangeEntry>,
+    latest_seq: u64,
+    truncated: bool,
+    tombstones: Vec<DeletedRef>,
+}
+
+/// A todo deleted at [at], remembered for [TOMBSTONE_RETENTION_SECS].
+#[derive(Clone, CandidType, Serialize, Deserialize)]
+pub struct DeletedRef {
+    id: u128,
+    at: u64,
+}
+
+/// How fresh todo ids are chosen.
+#[derive(Clone, Copy, Default, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub enum IdStrategy {
+    /// Ids come from the global [NEXT_TODO] counter.
+    #[default]
+    Counter,
+    /// Ids are a digest of (principal, seq, task), so replaying the same
+    /// imports yields the same ids.
+    Hashed,
+}
+
+/// How much the canister writes to its debug output.
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, CandidType, Serialize, Deserialize)]
+pub enum LogLevel {
+    #[default]
+    Off,
+    /// Failed mutating calls only.
+    Error,
+    /// Every mutating call and its outcome.
+    Info,
+    /// As [LogLevel::Info], plus the caller's hash.
+    Debug,
+}
+
+#[derive(Clone, Default, CandidType, Serialize, Deserialize)]
+pub struct Config {
+    read_only: bool,
+    seed_first_todo: Option<String>,
+    id_strategy: IdStrategy,
+    min_todo_chars: usize,
+    access_log: bool,
+    // Cycles every mutating call must attach; 0 disables the throttle
+    write_cycles: u128,
+    log_level: LogLevel,
+    // Seconds between automatic state checkpoints to stable memory; 0 disables them
+    checkpoint_secs: u64,
+    // Canister told through `notify_due(owner, todo_id)` when a todo falls due; none disables it
+    notifier_canister: Option<Principal>,
+    max_tags_per_todo: usize,
+    max_tag_chars: usize,
+    // Leaky bucket for `add_todo`: burst size and adds regained per minute; 0 capacity disables it
+    add_bucket_capacity: u64,
+    add_refill_per_min: u64,
+    // `clear_completed` archives instead of deleting
+    clear_archives: bool,
+    // Let the anonymous identity keep a todo list of its own
+    allow_anonymous: bool,
+    // Tasks containing any of these, ignoring case, are rejected
+    blocklist: Vec<String>,
+    // Canister-wide todo ceiling for adds and imports; 0 for the default, see [canister_cap]
+    max_total_todos: u64,
+}
+
+/// A user's `add_todo` allowance, in thousandths of an add so refills
+/// stay exact in integer math.
+#[derive(Clone, Copy)]
+struct Bucket {
+    milli_tokens: u64,
+    last_refill: u64,
+}
+
+/// Canister-wide volume, current and cumulative.
+#[derive(Clone, CandidType, Serialize, Deserialize)]
+pub struct Metrics {
+    users: u64,
+    todos: u64,
+    lifetime_created: u128,
+    lifetime_deleted: u128,
+}
+
+/// One mutating call recorded by the access log. No task content is kept,
+/// and the caller is only stored as a salted hash.
+#[derive(Clone, CandidType, Serialize, Deserialize)]
+pub struct AccessLogEntry {
+    method: String,
+    at: u64,
+    principal_hash: u64,
+}
+
+/// One install or upgrade of the canister's code.
+#[derive(Clone, CandidType, Serialize, Deserialize)]
+pub struct UpgradeEntry {
+    // The crate version of the installed code
+    version: String,
+    at: u64,
+}
+
+/// A slice of the users in an `admin_export_chunk` export, and where the
+/// next slice starts.
+#[derive(Clone, CandidType, Serialize, Deserialize)]
+pub struct ExportChunk {
+    state: CanisterState,
+    next_cursor: Option<PrincipalName>,
+}
+
+/// How much data [admin_restore] brought back.
+#[derive(Clone, CandidType, Serialize, Deserialize)]
+pub struct RestoreCounts {
+    users: u64,
+    todos: u64,
+}
+
+/// A user's completion streak and the daily counts behind it.
+#[derive(Clone, CandidType, Serialize, Deserialize)]
+pub struct Streak {
+    // Consecutive days with a completion, ending today or yesterday
+    current: u64,
+    // (day since the epoch, todos completed that day), oldest first
+    days: Vec<(u64, u64)>,
+}
+
+/// When a user's todos were first created and last changed.
+#[derive(Clone, CandidType, Serialize, Deserialize)]
+pub struct ActivityWindow {
+    first_created_at: Option<u64>,
+    last_updated_at: Option<u64>,
+}
+
+/// How many lines `import_todotxt` turned into todos, and how many it
+/// skipped as invalid.
+#[derive(Clone, CandidType, Serialize, Deserialize)]
+pub struct ImportCounts {
+    imported: u64,
+    skipped: u64,
+}
+
+/// Counts over one user's list, cached so polling queries needn't scan it.
+#[derive(Clone, Copy, Default)]
+struct UserStats {
+    todos: u64,
+    completed: u64,
+    // Task characters, compressed tasks counted at full length
+    chars_used: u64,
+}
+
+/// What importing a todo.txt file would do, per `preview_import_todotxt`.
+#[derive(Clone, CandidType, Serialize, Deserialize)]
+pub struct ImportPreview {
+    valid: u64,
+    invalid: u64,
+    // The valid entries wouldn't fit the user's or the canister's limit
+    would_exceed_quota: bool,
+}
+
+/// What [SEARCH_CACHE] keeps per todo: its lowercased task and the fields
+/// [USER_STATS] and [EXTERNAL_KEYS] count, so one todo can be reindexed
+/// without reading the rest of the list.
+#[derive(Clone)]
+struct IndexEntry {
+    lowered: String,
+    chars: u64,
+    completed: bool,
+    external_key: Option<String>,
+}
+
+impl IndexEntry {
+    fn of(todo: &Todo) -> Self {
+        let text = todo.task.text();
+        IndexEntry {
+            lowered: text.to_lowercase(),
+            chars: text.chars().count() as u64,
+            completed: todo.completed,
+            external_key: todo.external_key.clone(),
+        }
+    }
+}
+
+/// An import in progress, and the tasks staged for it so far.
+struct ImportSession {
+    session_id: u64,
+    staged: Vec<String>,
+}
+
+/// One todo.txt line, split into the fields [Todo] keeps.
+struct TodoTxtLine {
     task: String,
+    completed: bool,
+    priority: u8,
+    // `+project` and `@context` words, sigil included
+    tags: Vec<String>,
+}
+
+/// What [repair_state] fixed.
+#[derive(Clone, CandidType, Serialize, Deserialize)]
+pub struct RepairReport {
+    // Todos given a fresh id because another of the user's todos had theirs
+    ids_reassigned: u64,
+    counter_before: u128,
+    counter_after: u128,
+}
+
+/// Resource usage of the canister, in one reply.
+#[derive(Clone, CandidType, Serialize, Deserialize)]
+pub struct SelfReport {
+    cycles: u128,
+    stable_pages: u64,
+    heap_bytes: u64,
+    users: u64,
+    todos: u64,
+}
+
+/// What the caller may do, for showing the matching controls.
+#[derive(Clone, Copy, CandidType, Serialize, Deserialize)]
+pub enum Role {
+    Admin,
+    /// A principal with a todo list of its own or linked as an alias.
+    User,
+    /// The anonymous identity, or a principal that never added a todo.
+    Anonymous,
 }
 
+/// Liveness summary for operators and monitoring.
 #[derive(Clone, CandidType, Serialize, Deserialize)]
-struct CanisterState {
+pub struct Health {
+    read_only: bool,
+    // The last upgrade could not decode the saved state and started empty,
+    // and no snapshot was saved since
+    data_lost: bool,
+    // A snapshot that failed to decode is kept for `restore_lost_snapshot`
+    lost_snapshot: bool,
+}
+
+/// All persisted state, as saved to stable memory and exported. Only the
+/// first four fields were in the first snapshot format; every field added
+/// since is optional, so snapshots saved by older builds still decode.
+#[derive(Clone, CandidType, Serialize, Deserialize)]
+pub struct CanisterState {
     counter: u128,
     todos: BTreeMap<PrincipalName, Vec<Todo>>,
+    admin: Option<Principal>,
+    config: Config,
+    user_limits: Option<BTreeMap<PrincipalName, usize>>,
+    aliases: Option<BTreeMap<PrincipalName, PrincipalName>>,
+    pending_links: Option<BTreeMap<PrincipalName, PrincipalName>>,
+    lifetime_created: Option<u128>,
+    lifetime_deleted: Option<u128>,
+    access_log: Option<Vec<AccessLogEntry>>,
+    log_salt: Option<Vec<u8>>,
+    prefs: Option<BTreeMap<PrincipalName, UserPrefs>>,
+    changes: Option<BTreeMap<PrincipalName, ChangeLog>>,
+    pins: Option<BTreeMap<PrincipalName, Vec<u128>>>,
+    accepted_transfers: Option<BTreeMap<PrincipalName, PrincipalName>>,
+    shares: Option<BTreeMap<PrincipalName, Vec<PrincipalName>>>,
+    tombstones: Option<BTreeMap<PrincipalName, Vec<DeletedRef>>>,
+    task_history: Option<BTreeMap<PrincipalName, BTreeMap<u128, Vec<TaskText>>>>,
+    versions: Option<BTreeMap<PrincipalName, u64>>,
+    completions: Option<BTreeMap<PrincipalName, BTreeMap<u64, u64>>>,
+    upgrades: Option<Vec<UpgradeEntry>>,
+    // Each user's todo ids in list order, for exports that list todos by
+    // id; none in checkpoints, whose lists are already in list order
+    todo_order: Option<BTreeMap<PrincipalName, Vec<u128>>>,
+    // A snapshot an upgrade failed to decode; see [LOST_SNAPSHOT]
+    lost_snapshot: Option<Vec<u8>>,
 }
 //2286474 IDID
 
@@ -31,139 +623,7423 @@ thread_local! {
     //     2 MB = 500 x 1000 x 4 = 2,000,000
 
     // Define dapp limits - important for security assurance
-    static MAX_USERS: usize = 1_000;
-    static MAX_TODO_PER_USER: usize = 500;
-    static MAX_TODO_CHARS: usize = 1000;
+    static MAX_USERS: usize = const { 1_000 };
+    static MAX_TODO_PER_USER: usize = const { 500 };
+    static MAX_TODO_CHARS: usize = const { 1000 };
+    // The 2 GB target above; the limits may never promise more task data than this
+    static MEMORY_BUDGET_BYTES: u128 = const { 2_000_000_000 };
+    static MAX_EXTERNAL_KEY_CHARS: usize = const { 100 };
+    static MAX_ATTACHMENTS_PER_TODO: usize = const { 5 };
+    static MAX_ATTACHMENT_CHARS: usize = const { 256 };
+
+    // Task texts longer than this many bytes are stored deflated
+    static COMPRESSION_THRESHOLD: usize = const { 256 };
+    static MAX_ACCESS_LOG: usize = const { 10_000 };
+    static MAX_UPGRADE_HISTORY: usize = const { 100 };
+    static MAX_CHANGES_PER_USER: usize = const { 1_000 };
+    static MAX_TOMBSTONES_PER_USER: usize = const { 1_000 };
+    static TOMBSTONE_RETENTION_SECS: u64 = const { 30 * 24 * 60 * 60 };
+    static MAX_HISTORY_PER_TODO: usize = const { 20 };
+    static MAX_IDS_PER_BATCH: usize = const { 100 };
+    static MAX_TEMPLATES_PER_USER: usize = const { 20 };
+    static MAX_TASKS_PER_IMPORT_CHUNK: usize = const { 500 };
+    // How many days of completion counts `my_streak` keeps
+    static STREAK_HISTORY_DAYS: u64 = const { 90 };
+    // Also caps `admin_modified_between`
+    static MAX_ADMIN_SEARCH_RESULTS: usize = const { 100 };
+    static MAX_PINS_PER_USER: usize = const { 10 };
+    // How often due dates are scanned for the notifier canister
+    static DUE_SCAN_SECS: u64 = const { 60 };
+    // How close a due date must be for `get_todos_annotated` to call it due soon
+    static DUE_SOON_SECS: u64 = const { 24 * 60 * 60 };
+    // Raw argument bytes accepted by endpoints carrying a single task:
+    //     MAX_TODO_CHARS x (4 bytes per char) + Candid framing
+    static MAX_ARG_BYTES: usize = const { 8 * 1024 };
+    // Raw argument bytes accepted by endpoints carrying many tasks at once:
+    //     MAX_TASKS_PER_IMPORT_CHUNK x MAX_TODO_CHARS x (4 bytes per char) + framing,
+    //     which is the 2 MiB ingress message limit
+    static MAX_BATCH_ARG_BYTES: usize = const { 2 * 1024 * 1024 };
 
-    pub static NEXT_TODO: RefCell<u128> = RefCell::new(0);
-    pub static TODO_BY_USER: RefCell<BTreeMap<PrincipalName, Vec<Todo>>> = RefCell::new(BTreeMap::new());
+    pub static NEXT_TODO: RefCell<u128> = const { RefCell::new(0) };
+    pub static TODO_BY_USER: RefCell<BTreeMap<PrincipalName, Vec<Todo>>> = const { RefCell::new(BTreeMap::new()) };
+    pub static ADMIN: RefCell<Option<Principal>> = const { RefCell::new(None) };
+    pub static CONFIG: RefCell<Config> = const { RefCell::new(Config {
+        read_only: false,
+        seed_first_todo: None,
+        id_strategy: IdStrategy::Counter,
+        min_todo_chars: 0,
+        access_log: false,
+        write_cycles: 0,
+        log_level: LogLevel::Off,
+        checkpoint_secs: 0,
+        notifier_canister: None,
+        max_tags_per_todo: 10,
+        max_tag_chars: 50,
+        add_bucket_capacity: 0,
+        add_refill_per_min: 0,
+        clear_archives: false,
+        allow_anonymous: false,
+        blocklist: Vec::new(),
+        max_total_todos: 0,
+    }) };
+    pub static USER_LIMITS: RefCell<BTreeMap<PrincipalName, usize>> = const { RefCell::new(BTreeMap::new()) };
+    // alias -> primary, for principals whose todos live under another principal
+    pub static ALIASES: RefCell<BTreeMap<PrincipalName, PrincipalName>> = const { RefCell::new(BTreeMap::new()) };
+    // alias -> primary, for links offered by the primary but not yet accepted
+    pub static PENDING_LINKS: RefCell<BTreeMap<PrincipalName, PrincipalName>> = const { RefCell::new(BTreeMap::new()) };
+    pub static LIFETIME_CREATED: RefCell<u128> = const { RefCell::new(0) };
+    pub static LIFETIME_DELETED: RefCell<u128> = const { RefCell::new(0) };
+    pub static ACCESS_LOG: RefCell<Vec<AccessLogEntry>> = const { RefCell::new(Vec::new()) };
+    pub static LOG_SALT: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+    pub static PREFS: RefCell<BTreeMap<PrincipalName, UserPrefs>> = const { RefCell::new(BTreeMap::new()) };
+    pub static CHANGES: RefCell<BTreeMap<PrincipalName, ChangeLog>> = const { RefCell::new(BTreeMap::new()) };
+    pub static TOMBSTONES: RefCell<BTreeMap<PrincipalName, Vec<DeletedRef>>> = const { RefCell::new(BTreeMap::new()) };
+    // Prior task texts per todo, oldest first
+    pub static TASK_HISTORY: RefCell<BTreeMap<PrincipalName, BTreeMap<u128, Vec<TaskText>>>> = const { RefCell::new(BTreeMap::new()) };
+    // Per-user list version, bumped on every write
+    pub static VERSIONS: RefCell<BTreeMap<PrincipalName, u64>> = const { RefCell::new(BTreeMap::new()) };
+    // Todos completed per day (days since the epoch) per user
+    pub static COMPLETIONS: RefCell<BTreeMap<PrincipalName, BTreeMap<u64, u64>>> = const { RefCell::new(BTreeMap::new()) };
+    // Installs and upgrades, oldest first
+    pub static UPGRADES: RefCell<Vec<UpgradeEntry>> = const { RefCell::new(Vec::new()) };
+    // Stand in for the system time, the message caller, the debug log, stable memory and
+    // notifier calls in tests; see [now], [caller], [print_log], [stable_memory] and [notify_due]
+    #[cfg(test)]
+    static FAKE_NOW: RefCell<Option<u64>> = const { RefCell::new(None) };
+    #[cfg(test)]
+    static FAKE_CALLER: RefCell<Option<Principal>> = const { RefCell::new(None) };
+    #[cfg(test)]
+    static FAKE_LOG: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    #[cfg(test)]
+    static FAKE_STABLE: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+    #[cfg(test)]
+    static FAKE_NOTIFIED: RefCell<Vec<(Principal, Principal, u128)>> = const { RefCell::new(Vec::new()) };
+    // Pinned todo ids per user, in pin order
+    pub static PINS: RefCell<BTreeMap<PrincipalName, Vec<u128>>> = const { RefCell::new(BTreeMap::new()) };
+    // recipient -> sender, for whole-list transfers the recipient agreed to receive. Keyed by
+    // recipient so only the recipient can replace its own consent
+    pub static ACCEPTED_TRANSFERS: RefCell<BTreeMap<PrincipalName, PrincipalName>> = const { RefCell::new(BTreeMap::new()) };
+    // owner -> viewers allowed to read the owner's todos
+    pub static SHARES: RefCell<BTreeMap<PrincipalName, Vec<PrincipalName>>> = const { RefCell::new(BTreeMap::new()) };
+    // viewer -> owners, the reverse of [SHARES]. Heap only; rebuilt on upgrade and restore.
+    pub static SHARED_WITH: RefCell<BTreeMap<PrincipalName, Vec<PrincipalName>>> = const { RefCell::new(BTreeMap::new()) };
+    // Lowercased task texts and the other indexed fields, by todo id. Heap only;
+    // rebuilt on upgrade and restore.
+    static SEARCH_CACHE: RefCell<BTreeMap<PrincipalName, BTreeMap<u128, IndexEntry>>> = const { RefCell::new(BTreeMap::new()) };
+    // Ids passed to [record_change] since the user was last reindexed. Heap only.
+    static UNINDEXED: RefCell<BTreeMap<PrincipalName, BTreeSet<u128>>> = const { RefCell::new(BTreeMap::new()) };
+    // external key -> todo id per user, kept alongside [SEARCH_CACHE]. Heap only.
+    pub static EXTERNAL_KEYS: RefCell<BTreeMap<PrincipalName, BTreeMap<String, u128>>> = const { RefCell::new(BTreeMap::new()) };
+    // Per-user counts for the polling queries, kept alongside [SEARCH_CACHE]. Heap only.
+    static USER_STATS: RefCell<BTreeMap<PrincipalName, UserStats>> = const { RefCell::new(BTreeMap::new()) };
+    // Sum of [UserStats::todos] over all users. Heap only.
+    static TOTAL_TODOS: RefCell<u64> = const { RefCell::new(0) };
+    // Set when post_upgrade fell back to empty state. Heap only; cleared once a new
+    // snapshot is saved or by an `admin_restore`.
+    pub static DATA_LOST: RefCell<bool> = const { RefCell::new(false) };
+    // The snapshot post_upgrade failed to decode, kept for `restore_lost_snapshot`
+    pub static LOST_SNAPSHOT: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+    // The running checkpoint timer, if [Config::checkpoint_secs] enables one. Heap only.
+    pub static CHECKPOINT_TIMER: RefCell<Option<ic_cdk_timers::TimerId>> = const { RefCell::new(None) };
+    // The running due-date scan and the time it has covered up to. Heap only.
+    pub static DUE_SCAN_TIMER: RefCell<Option<ic_cdk_timers::TimerId>> = const { RefCell::new(None) };
+    pub static DUE_SCANNED_UNTIL: RefCell<u64> = const { RefCell::new(0) };
+    // Per-user `add_todo` rate limiter state. Heap only; buckets start full after an upgrade.
+    static ADD_BUCKETS: RefCell<BTreeMap<PrincipalName, Bucket>> = const { RefCell::new(BTreeMap::new()) };
+    // At most one open import per user; lost on upgrade along with its staged tasks
+    static IMPORT_SESSIONS: RefCell<BTreeMap<PrincipalName, ImportSession>> = const { RefCell::new(BTreeMap::new()) };
 }
 
+/// Returns the principal calling the current method. Tests pick it with
+/// [set_caller].
 fn caller() -> Principal {
+    #[cfg(test)]
+    if let Some(fake) = FAKE_CALLER.with(|fake_ref| *fake_ref.borrow()) {
+        return fake;
+    }
     caller_api()
 }
 
-#[init]
-fn init() {}
+/// Makes [user] the caller of every following call on this thread.
+#[cfg(test)]
+fn set_caller(user: Principal) {
+    FAKE_CALLER.with(|fake_ref| *fake_ref.borrow_mut() = Some(user));
+}
 
-#[update(name = "whoami")]
-fn whoami() -> String {
-    caller_api().to_string()
+/// Returns the current time in nanoseconds since the epoch. Every time
+/// read goes through here, so tests can fake the clock with [FAKE_NOW].
+fn now() -> u64 {
+    #[cfg(test)]
+    if let Some(fake) = FAKE_NOW.with(|fake_ref| *fake_ref.borrow()) {
+        return fake;
+    }
+    ic_cdk::api::time()
 }
 
-/// Returns the current number of users.
-fn user_count() -> usize {
-    TODO_BY_USER.with(|todo_ref| todo_ref.borrow().keys().len())
+/// Fixes the clock read by [now] at [time] on this thread.
+#[cfg(test)]
+fn set_now(time: u64) {
+    FAKE_NOW.with(|fake_ref| *fake_ref.borrow_mut() = Some(time));
 }
 
-fn is_id_sane(id: u128) -> bool {
-    MAX_TODO_PER_USER.with(|max_todo_per_user| id < (*max_todo_per_user as u128) * (user_count() as u128))
+/// Returns the stable memory state is saved to. Tests get a heap buffer
+/// instead; see [HeapStableMemory].
+#[cfg(not(test))]
+fn stable_memory() -> CanisterStableMemory {
+    CanisterStableMemory::default()
 }
 
-/// Returns (a future of) this [caller]'s todos.
-/// Panics: 
-///     [caller] is the anonymous identity
-///     [caller] is not a registered user
-#[query(name = "get_todos")]
-fn get_todos() -> Vec<Todo> {
-    let user = caller();
-    let user_str = user.to_string();
-    TODO_BY_USER.with(|todo_ref| {
-        todo_ref
-            .borrow()
-            .get(&user_str)
-            .cloned()
-            .unwrap_or_default()
-    })
+#[cfg(test)]
+fn stable_memory() -> HeapStableMemory {
+    HeapStableMemory
 }
 
-/// Delete this [caller]'s todo with given id. If none of the 
-/// existing todos have this id, do nothing. 
-/// [id]: the id of the todo to be deleted
-///
-/// Returns: 
-///      Future of unit
-/// Panics: 
-///      [caller] is the anonymous identity
-///      [caller] is not a registered user
-///      [id] is unreasonable; see [is_id_sane]
-#[update(name = "delete_todo")]
-fn delete_todo(todo_id: u128) {
-    let user = caller();
-    assert!(is_id_sane(todo_id));
+/// Stable memory kept in [FAKE_STABLE], for tests.
+#[cfg(test)]
+struct HeapStableMemory;
 
-    let user_str = user.to_string();
-    // shared ownership borrowing
-    TODO_BY_USER.with(|todo_ref| {
-        let mut writer = todo_ref.borrow_mut();
-        if let Some(v) = writer.get_mut(&user_str) {
-            v.retain(|item| item.id != todo_id);
+#[cfg(test)]
+impl StableMemory for HeapStableMemory {
+    fn stable_size(&self) -> u32 {
+        self.stable64_size() as u32
+    }
+
+    fn stable64_size(&self) -> u64 {
+        FAKE_STABLE.with(|stable_ref| (stable_ref.borrow().len() >> 16) as u64)
+    }
+
+    fn stable_grow(&self, new_pages: u32) -> Result<u32, StableMemoryError> {
+        self.stable64_grow(new_pages as u64).map(|old_pages| old_pages as u32)
+    }
+
+    fn stable64_grow(&self, new_pages: u64) -> Result<u64, StableMemoryError> {
+        FAKE_STABLE.with(|stable_ref| {
+            let mut stable = stable_ref.borrow_mut();
+            let old_pages = (stable.len() >> 16) as u64;
+            stable.resize(((old_pages + new_pages) << 16) as usize, 0);
+            Ok(old_pages)
+        })
+    }
+
+    fn stable_write(&self, offset: u32, buf: &[u8]) {
+        self.stable64_write(offset as u64, buf)
+    }
+
+    fn stable64_write(&self, offset: u64, buf: &[u8]) {
+        let offset = offset as usize;
+        FAKE_STABLE.with(|stable_ref| stable_ref.borrow_mut()[offset..offset + buf.len()].copy_from_slice(buf))
+    }
+
+    fn stable_read(&self, offset: u32, buf: &mut [u8]) {
+        self.stable64_read(offset as u64, buf)
+    }
+
+    fn stable64_read(&self, offset: u64, buf: &mut [u8]) {
+        let offset = offset as usize;
+        FAKE_STABLE.with(|stable_ref| buf.copy_from_slice(&stable_ref.borrow()[offset..offset + buf.len()]))
+    }
+}
+
+/// Returns the key under which [p]'s data is stored. Every map keyed by
+/// [PrincipalName] must go through this so one principal maps to one key.
+fn principal_key(p: &Principal) -> PrincipalName {
+    p.to_text()
+}
+
+/// Returns the key of the todo list [p] acts on: the primary's key if [p]
+/// is a linked alias, otherwise [p]'s own key.
+fn owner_key(p: &Principal) -> PrincipalName {
+    let key = principal_key(p);
+    ALIASES
+        .with(|aliases_ref| aliases_ref.borrow().get(&key).cloned())
+        .unwrap_or(key)
+}
+
+/// Re-derives the canonical key for a stored [PrincipalName], which may
+/// have been written in a different case or grouping. Keys that aren't
+/// principals at all are kept as they are.
+fn normalize_key(key: &str) -> PrincipalName {
+    match Principal::from_text(key) {
+        Ok(p) | Err(PrincipalError::AbnormalGrouped(p)) => principal_key(&p),
+        Err(_) => key.to_string(),
+    }
+}
+
+/// Re-keys [map] by [normalize_key], folding the value of each key that
+/// resolves to an already seen principal into that principal's value
+/// with [merge]. Returns the keys that absorbed another.
+fn merge_keys<V>(map: &mut BTreeMap<PrincipalName, V>, merge: impl Fn(&mut V, V)) -> Vec<PrincipalName> {
+    let mut merged: BTreeMap<PrincipalName, V> = BTreeMap::new();
+    let mut absorbing = vec![];
+    for (key, value) in std::mem::take(map) {
+        match merged.entry(normalize_key(&key)) {
+            Entry::Vacant(entry) => {
+                entry.insert(value);
+            }
+            Entry::Occupied(mut entry) => {
+                absorbing.push(entry.key().clone());
+                merge(entry.get_mut(), value);
+            }
+        }
+    }
+    *map = merged;
+    absorbing
+}
+
+/// Appends the entries of [names] missing from [into].
+fn merge_names(into: &mut Vec<PrincipalName>, names: Vec<PrincipalName>) {
+    for name in names {
+        if !into.contains(&name) {
+            into.push(name);
+        }
+    }
+}
+
+/// Merges entries whose keys resolve to the same principal, in every map
+/// keyed by [PrincipalName], so a user never ends up with two todo lists
+/// or two sets of per-user data. Principals stored as values are
+/// normalized too.
+fn merge_duplicate_keys() {
+    let merged_lists =
+        TODO_BY_USER.with(|todos_ref| merge_keys(&mut todos_ref.borrow_mut(), |todos, more| todos.extend(more)));
+    USER_LIMITS.with(|limits_ref| {
+        merge_keys(&mut limits_ref.borrow_mut(), |limit, other| *limit = (*limit).max(other))
+    });
+    // Where both keys hold prefs or a pending offer, the first one seen wins.
+    PREFS.with(|prefs_ref| merge_keys(&mut prefs_ref.borrow_mut(), |_, _| {}));
+    for links in [&ALIASES, &PENDING_LINKS, &ACCEPTED_TRANSFERS] {
+        links.with(|links_ref| {
+            let mut links = links_ref.borrow_mut();
+            for other in links.values_mut() {
+                *other = normalize_key(other);
+            }
+            merge_keys(&mut links, |_, _| {});
+        });
+    }
+    PINS.with(|pins_ref| {
+        merge_keys(&mut pins_ref.borrow_mut(), |pins, more| {
+            for id in more {
+                if !pins.contains(&id) {
+                    pins.push(id);
+                }
+            }
+        })
+    });
+    SHARES.with(|shares_ref| {
+        let mut shares = shares_ref.borrow_mut();
+        for viewers in shares.values_mut() {
+            let names = std::mem::take(viewers);
+            merge_names(viewers, names.iter().map(|name| normalize_key(name)).collect());
         }
+        merge_keys(&mut shares, merge_names);
+    });
+    // The merged entries are renumbered after the kept ones, so a client's
+    // cursor still sees them as new.
+    CHANGES.with(|changes_ref| {
+        merge_keys(&mut changes_ref.borrow_mut(), |log, other| {
+            log.next_seq = log.next_seq.max(other.next_seq);
+            for mut entry in other.entries {
+                log.next_seq += 1;
+                entry.seq = log.next_seq;
+                log.entries.push(entry);
+            }
+            let max_changes = MAX_CHANGES_PER_USER.with(|mcpu| *mcpu);
+            if log.entries.len() > max_changes {
+                let excess = log.entries.len() - max_changes;
+                log.entries.drain(..excess);
+            }
+        })
+    });
+    TOMBSTONES.with(|tombstones_ref| {
+        merge_keys(&mut tombstones_ref.borrow_mut(), |kept, more| {
+            kept.extend(more);
+            kept.sort_by_key(|t| t.at);
+        })
+    });
+    TASK_HISTORY.with(|history_ref| {
+        merge_keys(&mut history_ref.borrow_mut(), |histories, more| {
+            for (todo_id, versions) in more {
+                histories.entry(todo_id).or_default().extend(versions);
+            }
+        })
+    });
+    VERSIONS.with(|versions_ref| {
+        merge_keys(&mut versions_ref.borrow_mut(), |version, other| *version = (*version).max(other))
+    });
+    // A merged list differs from both halves.
+    for user_str in &merged_lists {
+        bump_version(user_str);
+    }
+    COMPLETIONS.with(|completions_ref| {
+        merge_keys(&mut completions_ref.borrow_mut(), |days, more| {
+            for (day, count) in more {
+                *days.entry(day).or_default() += count;
+            }
+        })
     });
 }
 
-/// Returns (a future of) this [caller]'s todos.
-/// Panics: 
-///     [caller] is the anonymous identity
-///     [caller] is not a registered user
-///     [todo.task] exceeds [MAX_TODO_CHARS]
-///     [todo.id] is unreasonable; see [is_id_sane]
-#[update(name = "update_todo")]
-fn update_todo(todos: Todo) {
+/// Traps unless [caller] is the admin recorded at install time.
+fn assert_admin() {
     let user = caller();
-    assert!(todos.task.chars().count() <= MAX_TODO_CHARS.with(|mnc| *mnc));
-    assert!(is_id_sane(todos.id));
+    assert!(ADMIN.with(|admin_ref| *admin_ref.borrow() == Some(user)));
+}
 
-    let user_str = user.to_string();
-    TODO_BY_USER.with(|todos_ref| {
-        let mut writer = todos_ref.borrow_mut();
-        if let Some(old_todo) = writer
-            .get_mut(&user_str)
-            .and_then(|td| td.iter_mut().find(|t| t.id == todos.id))
-        {
-            old_todo.task = todos.task;
+/// Traps for the anonymous identity unless [Config::allow_anonymous] is set.
+fn assert_not_anonymous() {
+    let allow_anonymous = CONFIG.with(|config_ref| config_ref.borrow().allow_anonymous);
+    assert!(allow_anonymous || caller() != Principal::anonymous());
+}
+
+/// Rejects mutating calls while the canister is in maintenance mode, or
+/// when they don't attach [Config::write_cycles]; otherwise accepts that
+/// many cycles as the write fee.
+fn check_writable() -> Result<(), String> {
+    let (read_only, write_cycles) =
+        CONFIG.with(|config_ref| (config_ref.borrow().read_only, config_ref.borrow().write_cycles));
+    if read_only {
+        return Err("maintenance mode".to_string());
+    }
+    if write_cycles > 0 {
+        if ic_cdk::api::call::msg_cycles_available128() < write_cycles {
+            return Err("insufficient cycles attached".to_string());
         }
-    })
+        ic_cdk::api::call::msg_cycles_accept128(write_cycles);
+    }
+    Ok(())
 }
 
-/// Add new todo for this [caller].
-///      [todo]: (encrypted) content of this todo
-///
-/// Returns: 
-///      Future of unit
-/// Panics: 
-///      [caller] is the anonymous identity
-///      [caller] is not a registered user
-///      [todo] exceeds [MAX_TODO_CHARS]
-///      User already has [MAX_TODOS_PER_USER] todos
-///      [todo] would be for a new user and [MAX_USERS] is exceeded
-#[update(name = "add_todo")]
-fn add_todo(task: String) {
-    let user = caller();
-    assert!(task.chars().count() <= MAX_TODO_CHARS.with(|mtc| *mtc));
+/// Guard rejecting oversized argument blobs before any Candid decoding
+/// work is spent on them.
+fn check_arg_size() -> Result<(), String> {
+    check_arg_bytes(ic_cdk::api::call::arg_data_raw_size(), MAX_ARG_BYTES.with(|mab| *mab))
+}
 
-    let user_str = user.to_string();
-    let todo_id = NEXT_TODO.with(|counter_ref| {
-        let mut writer = counter_ref.borrow_mut();
-        *writer += 1;
-        *writer
+/// Like [check_arg_size], for endpoints taking a batch of tasks; see
+/// [MAX_BATCH_ARG_BYTES].
+fn check_batch_arg_size() -> Result<(), String> {
+    check_arg_bytes(ic_cdk::api::call::arg_data_raw_size(), MAX_BATCH_ARG_BYTES.with(|mbab| *mbab))
+}
+
+/// Rejects a raw argument of [size] bytes if it exceeds [max_bytes].
+fn check_arg_bytes(size: usize, max_bytes: usize) -> Result<(), String> {
+    if size > max_bytes {
+        return Err("argument too large".to_string());
+    }
+    Ok(())
+}
+
+/// Methods whose raw argument may reach [MAX_BATCH_ARG_BYTES]; every other
+/// method is held to [MAX_ARG_BYTES].
+const BATCH_ARG_METHODS: [&str; 5] =
+    ["admin_restore", "import_chunk", "import_todotxt", "preview_import_todotxt", "reorder_todos"];
+
+/// Whether an ingress message calling [method] with a raw argument of
+/// [size] bytes fits that method's limit.
+fn accepts_message(method: &str, size: usize) -> bool {
+    let max_bytes = if BATCH_ARG_METHODS.contains(&method) {
+        MAX_BATCH_ARG_BYTES.with(|mbab| *mbab)
+    } else {
+        MAX_ARG_BYTES.with(|mab| *mab)
+    };
+    check_arg_bytes(size, max_bytes).is_ok()
+}
+
+// Oversized ingress messages are dropped before any endpoint decodes them;
+// the guards still cover calls from other canisters, which skip this hook.
+#[inspect_message]
+fn inspect_message() {
+    if accepts_message(&ic_cdk::api::call::method_name(), ic_cdk::api::call::arg_data_raw_size()) {
+        ic_cdk::api::call::accept_message();
+    }
+}
+
+/// Whether [color] is a hex color of the form `#RRGGBB`.
+fn is_hex_color(color: &str) -> bool {
+    color.len() == 7
+        && color.starts_with('#')
+        && color[1..].chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Rejects tasks shorter than [Config::min_todo_chars] once trimmed.
+fn check_min_length(task: &str) -> Result<(), String> {
+    let min_chars = CONFIG.with(|config_ref| config_ref.borrow().min_todo_chars);
+    if task.trim().chars().count() < min_chars {
+        return Err("task too short".to_string());
+    }
+    Ok(())
+}
+
+/// Rejects adding [n] todos for [user_str] once the canister would hold
+/// more than its [canister_cap]. A new user's welcome todo counts
+/// towards [n]. Under [FullPolicy::DropOldestCompleted], adds past the
+/// user's limit replace a todo instead of growing the total, so they
+/// don't count.
+fn check_canister_room(user_str: &str, n: u64) -> Result<(), String> {
+    let (max_total, seeds) = CONFIG.with(|config_ref| {
+        let config = config_ref.borrow();
+        (config.max_total_todos, config.seed_first_todo.is_some())
     });
+    let max_total = canister_cap(max_total);
+    let held = TODO_BY_USER.with(|todos_ref| todos_ref.borrow().get(user_str).map(Vec::len));
+    let n = n + u64::from(seeds && held.is_none());
+    let on_full = PREFS.with(|prefs_ref| prefs_ref.borrow().get(user_str).map(|p| p.on_full).unwrap_or_default());
+    let n = match on_full {
+        FullPolicy::DropOldestCompleted => n.min(todo_limit(user_str).saturating_sub(held.unwrap_or(0)) as u64),
+        _ => n,
+    };
+    if TOTAL_TODOS.with(|total_ref| *total_ref.borrow()) + n > max_total {
+        return Err("canister full".to_string());
+    }
+    Ok(())
+}
 
-    let user_count = user_count();
-    TODO_BY_USER.with(|todos_ref| {
-        let mut writer = todos_ref.borrow_mut();
-        let user_todos = writer.entry(user_str).or_insert_with(|| {
-            // caller unknown ==> check invariants
-            // A. can we add a new user?
-            assert!(MAX_USERS.with(|mu| user_count < *mu));
-            vec![]
-        });
+/// Rejects completing [user_str]'s todo [todo_id] when that would spawn
+/// its next occurrence into a canister already at
+/// [Config::max_total_todos].
+fn check_spawn_room(user_str: &str, todo_id: u128) -> Result<(), String> {
+    let spawns = TODO_BY_USER.with(|todos_ref| {
+        todos_ref
+            .borrow()
+            .get(user_str)
+            .and_then(|todos| todos.iter().find(|t| t.id == todo_id))
+            .is_some_and(|t| !t.completed && t.recurrence.is_some())
+    });
+    if spawns {
+        check_canister_room(user_str, 1)?;
+    }
+    Ok(())
+}
 
-        assert!(user_todos.len() < MAX_TODO_PER_USER.with(|mtpu| *mtpu));
+/// Rejects tasks containing a [Config::blocklist] term, ignoring case.
+fn check_blocklist(task: &str) -> Result<(), String> {
+    let task = task.to_lowercase();
+    let blocked = CONFIG.with(|config_ref| {
+        config_ref
+            .borrow()
+            .blocklist
+            .iter()
+            .any(|term| !term.is_empty() && task.contains(&term.to_lowercase()))
+    });
+    if blocked {
+        return Err("blocked content".to_string());
+    }
+    Ok(())
+}
 
-        user_todos.push(Todo {
-            id: todo_id,
-            task: task,
-        });
+/// Records a mutating call in the access log when [Config::access_log] is
+/// on, dropping the oldest entries beyond [MAX_ACCESS_LOG]. Nothing is
+/// recorded until [draw_log_salt] has drawn a salt.
+fn log_access(method: &str, user: &Principal) {
+    if !CONFIG.with(|config_ref| config_ref.borrow().access_log) {
+        return;
+    }
+    let Some(principal_hash) = principal_hash(user) else {
+        return;
+    };
+    let entry = AccessLogEntry {
+        method: method.to_string(),
+        at: now(),
+        principal_hash,
+    };
+    ACCESS_LOG.with(|log_ref| {
+        let mut log = log_ref.borrow_mut();
+        log.push(entry);
+        let max_log = MAX_ACCESS_LOG.with(|mal| *mal);
+        if log.len() > max_log {
+            let excess = log.len() - max_log;
+            log.drain(..excess);
+        }
     });
-}
\ No newline at end of file
+}
+
+/// Runs a mutating endpoint and reports its outcome through
+/// [print_log], as far as [Config::log_level] asks for; the caller's
+/// hash is left out until a salt is drawn. The anonymous identity is turned
+/// away first unless [Config::allow_anonymous] is set.
+fn logged<T>(method: &str, call: impl FnOnce() -> Result<T, String>) -> Result<T, String> {
+    assert_not_anonymous();
+    let result = call();
+    let level = CONFIG.with(|config_ref| config_ref.borrow().log_level);
+    let wanted = if result.is_ok() { LogLevel::Info } else { LogLevel::Error };
+    if level < wanted {
+        return result;
+    }
+    let hash = if level == LogLevel::Debug { principal_hash(&caller()) } else { None };
+    match (&result, hash) {
+        (Ok(_), Some(hash)) => print_log(format!("{} by {:016x}: ok", method, hash)),
+        (Ok(_), None) => print_log(format!("{}: ok", method)),
+        (Err(err), Some(hash)) => print_log(format!("{} by {:016x}: failed: {}", method, hash, err)),
+        (Err(err), None) => print_log(format!("{}: failed: {}", method, err)),
+    }
+    result
+}
+
+/// Writes [line] to the canister's debug log. Tests collect the lines in
+/// [FAKE_LOG] instead.
+fn print_log(line: String) {
+    #[cfg(test)]
+    FAKE_LOG.with(|log_ref| log_ref.borrow_mut().push(line));
+    #[cfg(not(test))]
+    ic_cdk::api::print(line);
+}
+
+/// Returns the first 8 bytes of SHA-256 over [LOG_SALT] and [user], so
+/// log entries can be correlated without naming the principal; none while
+/// there is no salt yet, since an unsalted hash names the principal.
+fn principal_hash(user: &Principal) -> Option<u64> {
+    let mut hasher = Sha256::new();
+    LOG_SALT.with(|salt_ref| {
+        let salt = salt_ref.borrow();
+        (!salt.is_empty()).then(|| hasher.update(salt.as_slice()))
+    })?;
+    hasher.update(user.as_slice());
+    let digest = hasher.finalize();
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    Some(u64::from_be_bytes(bytes))
+}
+
+// Installing or upgrading to a build whose own limits exceed the memory
+// budget traps, so such a build never goes live.
+#[init]
+fn init() {
+    assert!(within_memory_budget(0));
+    install(caller());
+    record_upgrade();
+    schedule_timers();
+}
+
+/// Appends the running code's version to [UPGRADES], dropping the oldest
+/// entries beyond [MAX_UPGRADE_HISTORY].
+fn record_upgrade() {
+    UPGRADES.with(|upgrades_ref| {
+        let mut upgrades = upgrades_ref.borrow_mut();
+        upgrades.push(UpgradeEntry {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            at: now(),
+        });
+        let max_history = MAX_UPGRADE_HISTORY.with(|muh| *muh);
+        if upgrades.len() > max_history {
+            let excess = upgrades.len() - max_history;
+            upgrades.drain(..excess);
+        }
+    });
+}
+
+/// Makes [admin] the admin and has a fresh access-log salt drawn.
+fn install(admin: Principal) {
+    ADMIN.with(|admin_ref| *admin_ref.borrow_mut() = Some(admin));
+    LOG_SALT.with(|salt_ref| salt_ref.borrow_mut().clear());
+    draw_log_salt(std::time::Duration::ZERO);
+}
+
+/// Fills [LOG_SALT] from the management canister's `raw_rand` after
+/// [delay]. Anything derived from canister id or time could be rebuilt by
+/// whoever reads the log, so the salt must be random, and getting random
+/// bytes takes a call that init and post_upgrade can't make themselves. A
+/// failed draw is retried a minute later.
+fn draw_log_salt(delay: std::time::Duration) {
+    ic_cdk_timers::set_timer(delay, || {
+        ic_cdk::spawn(async {
+            match ic_cdk::api::management_canister::main::raw_rand().await {
+                Ok((bytes,)) => LOG_SALT.with(|salt_ref| *salt_ref.borrow_mut() = bytes),
+                Err((code, message)) => {
+                    print_log(format!("drawing the access-log salt failed: {:?} {}", code, message));
+                    draw_log_salt(std::time::Duration::from_secs(60));
+                }
+            }
+        })
+    });
+}
+
+/// Returns a copy of all persisted state.
+fn snapshot_state() -> CanisterState {
+    snapshot_of(&|_| true)
+}
+
+/// Like [snapshot_state], with each per-user map cut down to the users
+/// [keep] accepts. [keep] is asked about every key of every such map.
+fn snapshot_of(keep: &dyn Fn(&PrincipalName) -> bool) -> CanisterState {
+    CanisterState {
+        counter: NEXT_TODO.with(|counter_ref| *counter_ref.borrow()),
+        todos: TODO_BY_USER.with(|todos_ref| kept(&todos_ref.borrow(), keep)),
+        admin: ADMIN.with(|admin_ref| *admin_ref.borrow()),
+        config: CONFIG.with(|config_ref| config_ref.borrow().clone()),
+        user_limits: Some(USER_LIMITS.with(|limits_ref| kept(&limits_ref.borrow(), keep))),
+        aliases: Some(ALIASES.with(|aliases_ref| kept(&aliases_ref.borrow(), keep))),
+        pending_links: Some(PENDING_LINKS.with(|pending_ref| kept(&pending_ref.borrow(), keep))),
+        lifetime_created: Some(LIFETIME_CREATED.with(|created_ref| *created_ref.borrow())),
+        lifetime_deleted: Some(LIFETIME_DELETED.with(|deleted_ref| *deleted_ref.borrow())),
+        access_log: Some(ACCESS_LOG.with(|log_ref| log_ref.borrow().clone())),
+        log_salt: Some(LOG_SALT.with(|salt_ref| salt_ref.borrow().clone())),
+        prefs: Some(PREFS.with(|prefs_ref| kept(&prefs_ref.borrow(), keep))),
+        changes: Some(CHANGES.with(|changes_ref| kept(&changes_ref.borrow(), keep))),
+        pins: Some(PINS.with(|pins_ref| kept(&pins_ref.borrow(), keep))),
+        accepted_transfers: Some(ACCEPTED_TRANSFERS.with(|transfers_ref| kept(&transfers_ref.borrow(), keep))),
+        shares: Some(SHARES.with(|shares_ref| kept(&shares_ref.borrow(), keep))),
+        tombstones: Some(TOMBSTONES.with(|tombstones_ref| kept(&tombstones_ref.borrow(), keep))),
+        task_history: Some(TASK_HISTORY.with(|history_ref| kept(&history_ref.borrow(), keep))),
+        versions: Some(VERSIONS.with(|versions_ref| kept(&versions_ref.borrow(), keep))),
+        completions: Some(COMPLETIONS.with(|completions_ref| kept(&completions_ref.borrow(), keep))),
+        upgrades: Some(UPGRADES.with(|upgrades_ref| upgrades_ref.borrow().clone())),
+        todo_order: None,
+        lost_snapshot: Some(LOST_SNAPSHOT.with(|lost_ref| lost_ref.borrow().clone())),
+    }
+}
+
+/// Copies the entries of [map] whose user [keep] accepts.
+fn kept<V: Clone>(
+    map: &BTreeMap<PrincipalName, V>,
+    keep: &dyn Fn(&PrincipalName) -> bool,
+) -> BTreeMap<PrincipalName, V> {
+    map.iter()
+        .filter(|(user, _)| keep(user))
+        .map(|(user, value)| (user.clone(), value.clone()))
+        .collect()
+}
+
+/// Replaces all persisted state with [state].
+fn load_state(state: CanisterState) {
+    NEXT_TODO.with(|counter_ref| *counter_ref.borrow_mut() = state.counter);
+    TODO_BY_USER.with(|todos_ref| *todos_ref.borrow_mut() = state.todos);
+    ADMIN.with(|admin_ref| *admin_ref.borrow_mut() = state.admin);
+    CONFIG.with(|config_ref| *config_ref.borrow_mut() = state.config);
+    USER_LIMITS.with(|limits_ref| *limits_ref.borrow_mut() = state.user_limits.unwrap_or_default());
+    ALIASES.with(|aliases_ref| *aliases_ref.borrow_mut() = state.aliases.unwrap_or_default());
+    PENDING_LINKS.with(|pending_ref| *pending_ref.borrow_mut() = state.pending_links.unwrap_or_default());
+    LIFETIME_CREATED.with(|created_ref| *created_ref.borrow_mut() = state.lifetime_created.unwrap_or_default());
+    LIFETIME_DELETED.with(|deleted_ref| *deleted_ref.borrow_mut() = state.lifetime_deleted.unwrap_or_default());
+    ACCESS_LOG.with(|log_ref| *log_ref.borrow_mut() = state.access_log.unwrap_or_default());
+    LOG_SALT.with(|salt_ref| *salt_ref.borrow_mut() = state.log_salt.unwrap_or_default());
+    PREFS.with(|prefs_ref| *prefs_ref.borrow_mut() = state.prefs.unwrap_or_default());
+    CHANGES.with(|changes_ref| *changes_ref.borrow_mut() = state.changes.unwrap_or_default());
+    PINS.with(|pins_ref| *pins_ref.borrow_mut() = state.pins.unwrap_or_default());
+    ACCEPTED_TRANSFERS.with(|transfers_ref| *transfers_ref.borrow_mut() = state.accepted_transfers.unwrap_or_default());
+    SHARES.with(|shares_ref| *shares_ref.borrow_mut() = state.shares.unwrap_or_default());
+    TOMBSTONES.with(|tombstones_ref| *tombstones_ref.borrow_mut() = state.tombstones.unwrap_or_default());
+    TASK_HISTORY.with(|history_ref| *history_ref.borrow_mut() = state.task_history.unwrap_or_default());
+    VERSIONS.with(|versions_ref| *versions_ref.borrow_mut() = state.versions.unwrap_or_default());
+    COMPLETIONS.with(|completions_ref| *completions_ref.borrow_mut() = state.completions.unwrap_or_default());
+    UPGRADES.with(|upgrades_ref| *upgrades_ref.borrow_mut() = state.upgrades.unwrap_or_default());
+    LOST_SNAPSHOT.with(|lost_ref| *lost_ref.borrow_mut() = state.lost_snapshot.unwrap_or_default());
+}
+
+/// Writes a snapshot of all persisted state to stable memory, [LOST_SNAPSHOT]
+/// included, and clears [DATA_LOST] once it is written.
+fn save_state() -> Result<(), String> {
+    let mut writer = StableWriter::with_memory(stable_memory(), 0);
+    candid::write_args(&mut writer, (snapshot_state(),)).map_err(|err| err.to_string())?;
+    DATA_LOST.with(|lost_ref| *lost_ref.borrow_mut() = false);
+    Ok(())
+}
+
+/// (Re)starts the timers to match the current [Config].
+fn schedule_timers() {
+    schedule_checkpoints();
+    schedule_due_scan();
+}
+
+/// (Re)starts the checkpoint timer to match [Config::checkpoint_secs].
+fn schedule_checkpoints() {
+    let secs = CONFIG.with(|config_ref| config_ref.borrow().checkpoint_secs);
+    CHECKPOINT_TIMER.with(|timer_ref| {
+        let mut timer = timer_ref.borrow_mut();
+        if let Some(timer_id) = timer.take() {
+            ic_cdk_timers::clear_timer(timer_id);
+        }
+        if secs > 0 {
+            *timer = Some(ic_cdk_timers::set_timer_interval(
+                std::time::Duration::from_secs(secs),
+                || {
+                    if let Err(err) = save_state() {
+                        print_log(format!("checkpoint failed: {}", err));
+                    }
+                },
+            ));
+        }
+    });
+}
+
+/// Returns the whole of stable memory.
+fn read_stable_bytes() -> Vec<u8> {
+    let memory = stable_memory();
+    let mut bytes = vec![0; (memory.stable64_size() << 16) as usize];
+    memory.stable64_read(0, &mut bytes);
+    bytes
+}
+
+/// Decodes a snapshot written by [save_state].
+fn decode_state(bytes: &[u8]) -> Result<CanisterState, String> {
+    let mut de = candid::de::IDLDeserialize::new(bytes).map_err(|err| format!("{:?}", err))?;
+    de.get_value::<CanisterState>().map_err(|err| format!("{:?}", err))
+}
+/// Decodes the snapshot [save_state] left in stable memory.
+fn read_state() -> Result<CanisterState, String> {
+    decode_state(&read_stable_bytes())
+}
+
+/// Loads the snapshot in stable memory. Memory that doesn't start with a
+/// candid message holds no snapshot yet, as after upgrading from a build
+/// that never saved one: the state stays empty with [admin] as admin. A
+/// snapshot that can't be decoded is kept in [LOST_SNAPSHOT], so the next
+/// save carries it along, and the state stays empty with [admin] as admin
+/// and [DATA_LOST] set.
+fn restore_state(admin: Principal) {
+    let bytes = read_stable_bytes();
+    if !bytes.starts_with(b"DIDL") {
+        ADMIN.with(|admin_ref| *admin_ref.borrow_mut() = Some(admin));
+        return;
+    }
+    match decode_state(&bytes) {
+        Ok(state) => load_state(state),
+        Err(err) => {
+            print_log(format!("failed to restore canister state, starting empty: {}", err));
+            ADMIN.with(|admin_ref| *admin_ref.borrow_mut() = Some(admin));
+            LOST_SNAPSHOT.with(|lost_ref| *lost_ref.borrow_mut() = bytes);
+            DATA_LOST.with(|lost_ref| *lost_ref.borrow_mut() = true);
+        }
+    }
+}
+
+/// Saves the state for [post_upgrade]. A snapshot an earlier upgrade
+/// failed to decode is saved along with it; see [LOST_SNAPSHOT].
+/// (Re)starts the due-date scan if [Config::notifier_canister] is set.
+/// Only todos falling due after the scan starts are reported.
+fn schedule_due_scan() {
+    let notifier = CONFIG.with(|config_ref| config_ref.borrow().notifier_canister);
+    DUE_SCAN_TIMER.with(|timer_ref| {
+        let mut timer = timer_ref.borrow_mut();
+        let was_running = timer.take().map(ic_cdk_timers::clear_timer).is_some();
+        if notifier.is_some() {
+            if !was_running {
+                DUE_SCANNED_UNTIL.with(|until_ref| *until_ref.borrow_mut() = now());
+            }
+            let secs = DUE_SCAN_SECS.with(|dss| *dss);
+            *timer = Some(ic_cdk_timers::set_timer_interval(
+                std::time::Duration::from_secs(secs),
+                scan_due_todos,
+            ));
+        }
+    });
+}
+
+/// Tells the notifier canister about every open todo whose due date
+/// passed since the previous scan. Each call is made on its own, so a
+/// failing one is only logged.
+fn scan_due_todos() {
+    let Some(notifier) = CONFIG.with(|config_ref| config_ref.borrow().notifier_canister) else {
+        return;
+    };
+    let now = now();
+    let since = DUE_SCANNED_UNTIL.with(|until_ref| std::mem::replace(&mut *until_ref.borrow_mut(), now));
+    let due: Vec<(PrincipalName, u128)> = TODO_BY_USER.with(|todos_ref| {
+        todos_ref
+            .borrow()
+            .iter()
+            .flat_map(|(owner, todos)| {
+                todos
+                    .iter()
+                    .filter(|t| !t.completed && t.due_at.is_some_and(|due_at| since < due_at && due_at <= now))
+                    .map(move |t| (owner.clone(), t.id))
+            })
+            .collect()
+    });
+    for (owner, todo_id) in due {
+        let Ok(owner) = Principal::from_text(&owner) else {
+            continue;
+        };
+        notify_due(notifier, owner, todo_id);
+    }
+}
+
+/// Calls `notify_due` on [notifier] for [owner]'s todo [todo_id] without
+/// waiting for the reply. Tests collect the calls in [FAKE_NOTIFIED]
+/// instead.
+fn notify_due(notifier: Principal, owner: Principal, todo_id: u128) {
+    #[cfg(test)]
+    FAKE_NOTIFIED.with(|notified_ref| notified_ref.borrow_mut().push((notifier, owner, todo_id)));
+    #[cfg(not(test))]
+    ic_cdk::spawn(async move {
+        let result: Result<(), _> = ic_cdk::call(notifier, "notify_due", (owner, todo_id)).await;
+        if let Err((code, message)) = result {
+            print_log(format!("notify_due for todo {} failed: {:?} {}", todo_id, code, message));
+        }
+    });
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    save_state().expect("failed to save canister state");
+}
+
+/// Restores the state saved by [pre_upgrade]. If it can't be decoded, the
+/// canister comes up empty with the upgrading principal as admin rather
+/// than trapping, which would leave it un-upgradeable; `health` then
+/// reports the data loss, and the snapshot is kept for
+/// `restore_lost_snapshot`.
+#[post_upgrade]
+fn post_upgrade() {
+    assert!(within_memory_budget(0));
+    restore_state(caller());
+    record_upgrade();
+    merge_duplicate_keys();
+    reindex_search_all();
+    reindex_shares();
+    schedule_timers();
+    if LOG_SALT.with(|salt_ref| salt_ref.borrow().is_empty()) {
+        draw_log_salt(std::time::Duration::ZERO);
+    }
+}
+
+/// Returns the canister's installs and upgrades, oldest first; only the
+/// last [MAX_UPGRADE_HISTORY] are kept.
+///
+/// Panics:
+///      [caller] is not the admin
+#[query(name = "upgrade_history")]
+fn upgrade_history() -> Vec<UpgradeEntry> {
+    assert_admin();
+    UPGRADES.with(|upgrades_ref| upgrades_ref.borrow().clone())
+}
+
+/// Returns current user and todo counts alongside the cumulative number
+/// of todos ever created and deleted.
+///
+/// Panics:
+///      [caller] is not the admin
+#[query(name = "metrics")]
+fn metrics() -> Metrics {
+    assert_admin();
+    TODO_BY_USER.with(|todos_ref| {
+        let todos = todos_ref.borrow();
+        Metrics {
+            users: todos.len() as u64,
+            todos: todos.values().map(|t| t.len() as u64).sum(),
+            lifetime_created: LIFETIME_CREATED.with(|created_ref| *created_ref.borrow()),
+            lifetime_deleted: LIFETIME_DELETED.with(|deleted_ref| *deleted_ref.borrow()),
+        }
+    })
+}
+
+/// Returns the number of task characters stored across all users, to
+/// gauge how close the canister is to the memory budget in [MAX_USERS]'s
+/// header comment. Compressed tasks are counted at full length.
+///
+/// Panics:
+///      [caller] is not the admin
+#[query(name = "total_chars")]
+fn total_chars() -> u64 {
+    assert_admin();
+    USER_STATS.with(|stats_ref| stats_ref.borrow().values().map(|s| s.chars_used).sum())
+}
+
+/// Returns the cycle balance, stable memory size in 64 KiB pages, Wasm
+/// heap size in bytes, and user and todo counts.
+///
+/// Panics:
+///      [caller] is not the admin
+#[query(name = "self_report")]
+fn self_report() -> SelfReport {
+    assert_admin();
+    let (users, todos) = TODO_BY_USER.with(|todos_ref| {
+        let todos = todos_ref.borrow();
+        (todos.len() as u64, todos.values().map(|t| t.len() as u64).sum())
+    });
+    SelfReport {
+        cycles: cycle_balance(),
+        stable_pages: stable_memory().stable64_size(),
+        heap_bytes: heap_bytes(),
+        users,
+        todos,
+    }
+}
+
+/// The canister's cycle balance; 0 when not running as Wasm.
+fn cycle_balance() -> u128 {
+    #[cfg(target_arch = "wasm32")]
+    {
+        ic_cdk::api::canister_balance128()
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        0
+    }
+}
+
+/// Size of the Wasm linear memory; 0 when not running as Wasm.
+fn heap_bytes() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    {
+        (core::arch::wasm32::memory_size(0) * 65536) as u64
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        0
+    }
+}
+
+/// Returns the access log, oldest entry first.
+///
+/// Panics:
+///      [caller] is not the admin
+#[query(name = "admin_access_log")]
+fn admin_access_log() -> Vec<AccessLogEntry> {
+    assert_admin();
+    ACCESS_LOG.with(|log_ref| log_ref.borrow().clone())
+}
+
+/// Returns all canister state, for backups. Each user's todos are listed
+/// by id rather than in list order, so exporting unchanged data always
+/// yields the same bytes; the list order goes in
+/// [CanisterState::todo_order] and a restored backup is put back in it.
+/// The access-log salt is left out, since with it the log's hashes could
+/// be matched against known principals, and so is a lost snapshot; see
+/// `restore_lost_snapshot`.
+///
+/// Panics:
+///      [caller] is not the admin
+#[query(name = "admin_export_all")]
+fn admin_export_all() -> CanisterState {
+    assert_admin();
+    export_of(snapshot_state())
+}
+
+/// Turns [state] into a backup the way [admin_export_all] describes.
+fn export_of(mut state: CanisterState) -> CanisterState {
+    state.log_salt = None;
+    state.lost_snapshot = None;
+    let todo_order = state
+        .todos
+        .iter_mut()
+        .map(|(user, todos)| (user.clone(), canonical_order(todos)))
+        .collect();
+    state.todo_order = Some(todo_order);
+    state
+}
+
+/// Sorts exported [todos] by id and returns their ids in the list order
+/// they had; see [admin_export_all].
+fn canonical_order(todos: &mut [Todo]) -> Vec<u128> {
+    let order = todos.iter().map(|t| t.id).collect();
+    todos.sort_by_key(|t| t.id);
+    order
+}
+
+/// Puts each user's [todos] back in the list order [canonical_order]
+/// recorded in [order]. Todos missing from it go last, keeping their
+/// relative order.
+fn apply_list_order(todos: &mut BTreeMap<PrincipalName, Vec<Todo>>, order: &BTreeMap<PrincipalName, Vec<u128>>) {
+    for (user, todo_ids) in order {
+        let Some(todos) = todos.get_mut(user) else {
+            continue;
+        };
+        let positions: BTreeMap<u128, usize> = todo_ids.iter().enumerate().map(|(i, id)| (*id, i)).collect();
+        todos.sort_by_key(|t| positions.get(&t.id).copied().unwrap_or(usize::MAX));
+    }
+}
+
+/// Returns the state of up to [max_users] users, starting at
+/// [start_principal] in key order, for exports too large for one
+/// `admin_export_all` reply. Each chunk is an `admin_export_all` snapshot
+/// whose per-user maps hold only its users; the first chunk also carries
+/// the access log, upgrade history and lifetime counters, which later
+/// chunks leave out. Pass the returned cursor back in until it is none;
+/// merging the chunks' maps then gives the full `admin_export_all`.
+///      [start_principal]: the cursor of the previous chunk, or none to start
+///      [max_users]: the maximum number of users in this chunk
+///
+/// Panics:
+///      [caller] is not the admin
+///      [max_users] is 0, which would never advance the cursor
+#[query(name = "admin_export_chunk", guard = "check_arg_size")]
+fn admin_export_chunk(start_principal: Option<String>, max_users: u64) -> ExportChunk {
+    assert_admin();
+    assert!(max_users > 0);
+    // Keeping no one still visits every key, which lists the users.
+    let users = RefCell::new(BTreeSet::new());
+    snapshot_of(&|user| {
+        users.borrow_mut().insert(user.clone());
+        false
+    });
+    let users = users.into_inner();
+    let mut remaining = match &start_principal {
+        Some(start) => users.range(start.clone()..),
+        None => users.range::<PrincipalName, _>(..),
+    };
+    let chunk: BTreeSet<&PrincipalName> = remaining.by_ref().take(max_users as usize).collect();
+    let next_cursor = remaining.next().cloned();
+    let mut state = export_of(snapshot_of(&|user| chunk.contains(user)));
+    if start_principal.is_some() {
+        state.access_log = None;
+        state.upgrades = None;
+        state.lifetime_created = None;
+        state.lifetime_deleted = None;
+    }
+    ExportChunk { state, next_cursor }
+}
+
+/// Replace all canister state with a snapshot from [admin_export_all].
+/// The canister must be in maintenance mode so the restore can't race
+/// live writes; it stays in maintenance mode afterwards, and the current
+/// admin, access-log salt and lost snapshot are kept. Restoring also ends
+/// the data-loss state `health` reports.
+///      [state]: the snapshot to restore
+///
+/// Returns:
+///      Future of the number of users and todos restored, or an error if
+///      the canister is not in maintenance mode
+/// Panics:
+///      [caller] is not the admin
+///      [state] holds more than [MAX_USERS] users
+///      A user in [state] holds more todos than its limit allows
+///      [state]'s todo cap exceeds the memory budget (see
+///      [within_memory_budget]) or is below one of its user limits
+#[update(name = "admin_restore", guard = "check_batch_arg_size")]
+fn admin_restore(state: CanisterState) -> Result<RestoreCounts, String> {
+    assert_admin();
+    admin_restore_as("admin_restore", state)
+}
+
+/// Does the work of [admin_restore], access-logged as a call to [method]
+/// once the restored log is in place.
+fn admin_restore_as(method: &str, mut state: CanisterState) -> Result<RestoreCounts, String> {
+    if !CONFIG.with(|config_ref| config_ref.borrow().read_only) {
+        return Err("restore requires maintenance mode".to_string());
+    }
+    assert!(state.todos.len() <= MAX_USERS.with(|mu| *mu));
+    let user_limits = state.user_limits.clone().unwrap_or_default();
+    assert!(within_memory_budget(state.config.max_total_todos));
+    assert!(fits_canister_cap(&user_limits, state.config.max_total_todos));
+    for (user, todos) in &state.todos {
+        let limit = user_limits
+            .get(user)
+            .copied()
+            .unwrap_or_else(|| MAX_TODO_PER_USER.with(|mtpu| *mtpu));
+        assert!(todos.len() <= limit);
+    }
+
+    let counts = RestoreCounts {
+        users: state.todos.len() as u64,
+        todos: state.todos.values().map(|todos| todos.len() as u64).sum(),
+    };
+    state.admin = ADMIN.with(|admin_ref| *admin_ref.borrow());
+    state.log_salt = Some(LOG_SALT.with(|salt_ref| salt_ref.borrow().clone()));
+    state.lost_snapshot = Some(LOST_SNAPSHOT.with(|lost_ref| lost_ref.borrow().clone()));
+    state.config.read_only = true;
+    apply_list_order(&mut state.todos, &state.todo_order.take().unwrap_or_default());
+    load_state(state);
+    DATA_LOST.with(|lost_ref| *lost_ref.borrow_mut() = false);
+    merge_duplicate_keys();
+    reindex_search_all();
+    reindex_shares();
+    schedule_timers();
+    log_access(method, &caller());
+    Ok(counts)
+}
+
+/// Replace all canister state with the snapshot an earlier upgrade failed
+/// to decode, once a build that can decode it is installed. As with
+/// `admin_restore`, the canister must be and stays in maintenance mode,
+/// and the current admin and access-log salt are kept. Writes made since
+/// that upgrade are replaced; the kept snapshot is dropped.
+///
+/// Returns:
+///      Future of the number of users and todos restored, or an error if
+///      the canister is not in maintenance mode, if no snapshot was lost,
+///      or if this build can't decode it either
+/// Panics:
+///      [caller] is not the admin
+#[update(name = "restore_lost_snapshot")]
+fn restore_lost_snapshot() -> Result<RestoreCounts, String> {
+    assert_admin();
+    if !CONFIG.with(|config_ref| config_ref.borrow().read_only) {
+        return Err("restore requires maintenance mode".to_string());
+    }
+    let bytes = LOST_SNAPSHOT.with(|lost_ref| lost_ref.borrow().clone());
+    if bytes.is_empty() {
+        return Err("no lost snapshot".to_string());
+    }
+    let counts = admin_restore_as("restore_lost_snapshot", decode_state(&bytes)?)?;
+    LOST_SNAPSHOT.with(|lost_ref| lost_ref.borrow_mut().clear());
+    Ok(counts)
+}
+
+/// Write the current state to stable memory right away, as the
+/// checkpoint timer and `pre_upgrade` do. The last checkpoint is what
+/// `restore_checkpoint` goes back to, and what `post_upgrade` restores
+/// when an upgrade skips a trapping `pre_upgrade` (`install_code`'s
+/// `skip_pre_upgrade`).
+///
+/// Returns:
+///      Future of unit, or an error if the state could not be written
+/// Panics:
+///      [caller] is not the admin
+#[update(name = "checkpoint_now")]
+fn checkpoint_now() -> Result<(), String> {
+    assert_admin();
+    log_access("checkpoint_now", &caller());
+    save_state()
+}
+
+/// Replace all canister state with the last checkpoint in stable memory,
+/// undoing every write since; see [checkpoint_now]. As with
+/// `admin_restore`, the canister must be and stays in maintenance mode,
+/// and the current admin and access-log salt are kept.
+///
+/// Returns:
+///      Future of the number of users and todos restored, or an error if
+///      the canister is not in maintenance mode or stable memory holds no
+///      checkpoint that can be decoded
+/// Panics:
+///      [caller] is not the admin
+#[update(name = "restore_checkpoint")]
+fn restore_checkpoint() -> Result<RestoreCounts, String> {
+    assert_admin();
+    if !CONFIG.with(|config_ref| config_ref.borrow().read_only) {
+        return Err("restore requires maintenance mode".to_string());
+    }
+    admin_restore_as("restore_checkpoint", read_state()?)
+}
+
+/// Turn maintenance mode on or off. While on, every mutating endpoint
+/// returns `Err("maintenance mode")` and queries keep working.
+///      [read_only]: whether writes should be frozen
+///
+/// Panics:
+///      [caller] is not the admin
+#[update(name = "set_read_only")]
+fn set_read_only(read_only: bool) {
+    assert_admin();
+    log_access("set_read_only", &caller());
+    CONFIG.with(|config_ref| config_ref.borrow_mut().read_only = read_only);
+}
+
+#[update(name = "whoami")]
+fn whoami() -> String {
+    caller_api().to_string()
+}
+
+/// Returns the role of this [caller]; see [Role].
+#[query(name = "my_role")]
+fn my_role() -> Role {
+    let user = caller();
+    if ADMIN.with(|admin_ref| *admin_ref.borrow() == Some(user)) {
+        return Role::Admin;
+    }
+    if user == Principal::anonymous() {
+        return Role::Anonymous;
+    }
+    let user_str = owner_key(&user);
+    if TODO_BY_USER.with(|todos_ref| todos_ref.borrow().contains_key(&user_str)) {
+        Role::User
+    } else {
+        Role::Anonymous
+    }
+}
+
+/// Returns the current canister configuration.
+#[query(name = "get_config")]
+fn get_config() -> Config {
+    CONFIG.with(|config_ref| config_ref.borrow().clone())
+}
+
+/// Replace the canister configuration.
+///      [config]: the new configuration
+///
+/// Panics:
+///      [caller] is not the admin
+///      [config.seed_first_todo] exceeds [MAX_TODO_CHARS]
+///      [config.max_total_todos] exceeds the memory budget (see
+///      [within_memory_budget]) or is below a user limit override
+#[update(name = "set_config", guard = "check_arg_size")]
+fn set_config(config: Config) {
+    assert_admin();
+    log_access("set_config", &caller());
+    if let Some(seed) = &config.seed_first_todo {
+        assert!(seed.chars().count() <= MAX_TODO_CHARS.with(|mtc| *mtc));
+    }
+    assert!(within_memory_budget(config.max_total_todos));
+    assert!(USER_LIMITS.with(|limits_ref| fits_canister_cap(&limits_ref.borrow(), config.max_total_todos)));
+    CONFIG.with(|config_ref| *config_ref.borrow_mut() = config);
+    schedule_timers();
+}
+
+/// Grant [user] a todo cap different from the global [MAX_TODO_PER_USER].
+///      [user]: the principal whose cap is overridden
+///      [limit]: the maximum number of todos [user] may hold
+///
+/// Panics:
+///      [caller] is not the admin
+///      [limit] exceeds the canister's todo cap; see [canister_cap]
+#[update(name = "set_user_limit")]
+fn set_user_limit(user: Principal, limit: usize) {
+    assert_admin();
+    log_access("set_user_limit", &caller());
+    let max_total_todos = CONFIG.with(|config_ref| config_ref.borrow().max_total_todos);
+    assert!(limit as u64 <= canister_cap(max_total_todos));
+    USER_LIMITS.with(|limits_ref| limits_ref.borrow_mut().insert(principal_key(&user), limit));
+}
+
+/// The most todos the canister holds: [max_total_todos] if set (see
+/// [Config::max_total_todos]), and otherwise what [MAX_USERS] users at
+/// [MAX_TODO_PER_USER] each add up to. [check_canister_room] enforces it,
+/// so raised user limits never grow the total past it.
+fn canister_cap(max_total_todos: u64) -> u64 {
+    if max_total_todos > 0 {
+        return max_total_todos;
+    }
+    (MAX_USERS.with(|mu| *mu) * MAX_TODO_PER_USER.with(|mtpu| *mtpu)) as u64
+}
+
+/// Whether [canister_cap] todos of [MAX_TODO_CHARS] chars at 4 bytes per
+/// char fit in [MEMORY_BUDGET_BYTES], per the budget described above
+/// [MAX_USERS]. The default cap uses up the whole budget.
+fn within_memory_budget(max_total_todos: u64) -> bool {
+    let max_chars = MAX_TODO_CHARS.with(|mtc| *mtc) as u128;
+    canister_cap(max_total_todos) as u128 * max_chars * 4 <= MEMORY_BUDGET_BYTES.with(|mbb| *mbb)
+}
+
+/// Whether none of [limits] exceeds [canister_cap].
+fn fits_canister_cap(limits: &BTreeMap<PrincipalName, usize>, max_total_todos: u64) -> bool {
+    let cap = canister_cap(max_total_todos);
+    limits.values().all(|limit| *limit as u64 <= cap)
+}
+
+/// Returns the maximum number of todos [user_str] may hold: its override
+/// if one was set, otherwise [MAX_TODO_PER_USER].
+fn todo_limit(user_str: &str) -> usize {
+    USER_LIMITS
+        .with(|limits_ref| limits_ref.borrow().get(user_str).copied())
+        .unwrap_or_else(|| MAX_TODO_PER_USER.with(|mtpu| *mtpu))
+}
+
+/// Returns how many more todos [insert_todo] can add for [user_str] before
+/// the user's todo limit stops it: the free slots, plus the completed todos
+/// [FullPolicy::DropOldestCompleted] may drop, less a new user's welcome
+/// todo.
+fn todo_room(user_str: &str) -> usize {
+    let limit = todo_limit(user_str);
+    let on_full = PREFS.with(|prefs_ref| prefs_ref.borrow().get(user_str).map(|p| p.on_full).unwrap_or_default());
+    TODO_BY_USER.with(|todos_ref| match todos_ref.borrow().get(user_str) {
+        Some(todos) => {
+            let free = limit.saturating_sub(todos.len());
+            match on_full {
+                FullPolicy::Reject => free,
+                FullPolicy::DropOldestCompleted => {
+                    free + todos.iter().filter(|t| t.completed && !t.locked).count()
+                }
+            }
+        }
+        None => {
+            let seeds = CONFIG.with(|config_ref| config_ref.borrow().seed_first_todo.is_some());
+            limit.saturating_sub(usize::from(seeds))
+        }
+    })
+}
+
+/// Advances [NEXT_TODO] and returns the fresh id.
+fn next_todo_id() -> u128 {
+    NEXT_TODO.with(|counter_ref| {
+        let mut writer = counter_ref.borrow_mut();
+        *writer += 1;
+        *writer
+    })
+}
+
+/// Returns the first 16 bytes of SHA-256 over (principal, seq, task, salt).
+fn hashed_todo_id(user_str: &str, seq: usize, task: &str, salt: u32) -> u128 {
+    let mut hasher = Sha256::new();
+    hasher.update(user_str.as_bytes());
+    hasher.update((seq as u64).to_be_bytes());
+    hasher.update(task.as_bytes());
+    hasher.update(salt.to_be_bytes());
+    let digest = hasher.finalize();
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&digest[..16]);
+    u128::from_be_bytes(bytes)
+}
+
+/// Picks the id for a todo about to be appended to [existing] according
+/// to [Config::id_strategy]. Hashed ids use the list position as seq and
+/// are re-hashed with an increasing salt until they are unused.
+fn assign_todo_id(user_str: &str, existing: &[Todo], task: &str) -> u128 {
+    match CONFIG.with(|config_ref| config_ref.borrow().id_strategy) {
+        IdStrategy::Counter => next_todo_id(),
+        IdStrategy::Hashed => {
+            let seq = existing.len();
+            let mut salt = 0;
+            loop {
+                let id = hashed_todo_id(user_str, seq, task, salt);
+                if !existing.iter().any(|t| t.id == id) {
+                    return id;
+                }
+                salt += 1;
+            }
+        }
+    }
+}
+
+/// Adds [n] to the cumulative count of created todos.
+fn record_created(n: usize) {
+    LIFETIME_CREATED.with(|created_ref| *created_ref.borrow_mut() += n as u128);
+}
+
+/// Adds [n] to the cumulative count of deleted todos.
+fn record_deleted(n: usize) {
+    LIFETIME_DELETED.with(|deleted_ref| *deleted_ref.borrow_mut() += n as u128);
+}
+
+/// Returns the current number of users.
+fn user_count() -> usize {
+    TODO_BY_USER.with(|todo_ref| todo_ref.borrow().keys().len())
+}
+
+/// Hashed ids span the whole `u128` range, so they are always sane.
+fn is_id_sane(id: u128) -> bool {
+    if CONFIG.with(|config_ref| config_ref.borrow().id_strategy) == IdStrategy::Hashed {
+        return true;
+    }
+    MAX_TODO_PER_USER.with(|max_todo_per_user| id < (*max_todo_per_user as u128) * (user_count() as u128))
+}
+
+/// Returns (a future of) this [caller]'s todos that aren't archived,
+/// ordered by the [UserPrefs::default_sort] if one is set. In focus mode
+/// (see [UserPrefs::focus]) only the open todos most worth doing next are
+/// returned: highest priority first, then earliest due; `get_all_todos`
+/// still returns everything. A [caller] that never added a todo gets an
+/// empty list.
+/// Panics: 
+///     [caller] is the anonymous identity, unless [Config::allow_anonymous] is set
+#[query(name = "get_todos")]
+fn get_todos() -> Vec<Todo> {
+    let user_str = owner_key(&caller());
+    let focus = PREFS.with(|prefs_ref| prefs_ref.borrow().get(&user_str).and_then(|prefs| prefs.focus));
+    let Some(focus) = focus else {
+        return get_all_todos();
+    };
+    let mut todos: Vec<Todo> = get_all_todos().into_iter().filter(|t| !t.completed).collect();
+    todos.sort_by(|a, b| {
+        b.priority
+            .cmp(&a.priority)
+            .then_with(|| (a.due_at.is_none(), a.due_at).cmp(&(b.due_at.is_none(), b.due_at)))
+            .then_with(|| a.id.cmp(&b.id))
+    });
+    todos.truncate(usize::try_from(focus).unwrap_or(usize::MAX));
+    todos
+}
+
+/// Returns (a future of) this [caller]'s todos that aren't archived,
+/// ordered by the [UserPrefs::default_sort] if one is set, whether or not
+/// focus mode is on.
+/// Panics:
+///     [caller] is the anonymous identity, unless [Config::allow_anonymous] is set
+#[query(name = "get_all_todos")]
+fn get_all_todos() -> Vec<Todo> {
+    assert_not_anonymous();
+    let user = caller();
+    let user_str = owner_key(&user);
+    let mut todos = unarchived_todos(&user_str);
+    let default_sort = PREFS.with(|prefs_ref| {
+        prefs_ref.borrow().get(&user_str).and_then(|prefs| prefs.default_sort)
+    });
+    if let Some(spec) = default_sort {
+        sort_todos(&mut todos, spec);
+    }
+    todos
+}
+
+/// Returns (a future of) the same todos as [get_todos], each with
+/// whether it is overdue or due soon and how old it is, all as of now.
+/// Completed todos are never overdue or due soon.
+#[query(name = "get_todos_annotated")]
+fn get_todos_annotated() -> Vec<AnnotatedTodo> {
+    let now = now();
+    let soon = now.saturating_add(DUE_SOON_SECS.with(|dss| *dss).saturating_mul(1_000_000_000));
+    get_todos()
+        .into_iter()
+        .map(|todo| {
+            let is_overdue = !todo.completed && todo.is_overdue(now);
+            let is_due_soon =
+                !todo.completed && !is_overdue && todo.due_at.is_some_and(|due_at| due_at <= soon);
+            AnnotatedTodo {
+                is_overdue,
+                is_due_soon,
+                age_secs: now.saturating_sub(todo.created_at) / 1_000_000_000,
+                todo,
+            }
+        })
+        .collect()
+}
+
+/// Orders [a] and [b] by [spec], breaking ties by ascending id whichever
+/// the direction, so equal keys never come out in list order.
+fn compare_todos(a: &Todo, b: &Todo, spec: SortSpec) -> std::cmp::Ordering {
+    let ordering = match spec.key {
+        SortKey::Id => a.id.cmp(&b.id),
+        SortKey::Priority => a.priority.cmp(&b.priority),
+        SortKey::DueAt => (a.due_at.is_none(), a.due_at).cmp(&(b.due_at.is_none(), b.due_at)),
+        SortKey::CreatedAt => a.created_at.cmp(&b.created_at),
+    };
+    let ordering = if spec.descending { ordering.reverse() } else { ordering };
+    ordering.then_with(|| a.id.cmp(&b.id))
+}
+
+/// Sorts [todos] by [spec]; see [compare_todos].
+fn sort_todos(todos: &mut [Todo], spec: SortSpec) {
+    todos.sort_by(|a, b| compare_todos(a, b, spec));
+}
+
+/// Change the id of one of [owner]'s todos, for repairing corrupted ids.
+/// [NEXT_TODO] is raised past [new_id] so the counter never hands it out.
+///      [owner]: the principal owning the todo
+///      [old_id]: the current id of the todo
+///      [new_id]: the id to give it
+///
+/// Returns:
+///      Future of unit, or an error if [old_id] is unknown or [new_id]
+///      is already used by another of [owner]'s todos
+/// Panics:
+///      [caller] is not the admin
+#[update(name = "admin_reassign_id")]
+fn admin_reassign_id(owner: Principal, old_id: u128, new_id: u128) -> Result<(), String> {
+    assert_admin();
+    log_access("admin_reassign_id", &caller());
+
+    let owner_str = principal_key(&owner);
+    TODO_BY_USER.with(|todos_ref| {
+        let mut writer = todos_ref.borrow_mut();
+        let todos = writer
+            .get_mut(&owner_str)
+            .ok_or_else(|| "todo not found".to_string())?;
+        if old_id != new_id && todos.iter().any(|t| t.id == new_id) {
+            return Err("id already in use".to_string());
+        }
+        let todo = todos
+            .iter_mut()
+            .find(|t| t.id == old_id)
+            .ok_or_else(|| "todo not found".to_string())?;
+        todo.id = new_id;
+        Ok(())
+    })?;
+    // Recording the old id as deleted drops its history, so take it first.
+    let history = TASK_HISTORY.with(|history_ref| {
+        history_ref
+            .borrow_mut()
+            .get_mut(&owner_str)
+            .and_then(|histories| histories.remove(&old_id))
+    });
+    record_change(&owner_str, ChangeKind::Deleted, old_id);
+    record_change(&owner_str, ChangeKind::Added, new_id);
+    if let Some(history) = history {
+        TASK_HISTORY.with(|history_ref| {
+            history_ref.borrow_mut().entry(owner_str.clone()).or_default().insert(new_id, history)
+        });
+    }
+    PINS.with(|pins_ref| {
+        for id in pins_ref.borrow_mut().get_mut(&owner_str).into_iter().flatten() {
+            if *id == old_id {
+                *id = new_id;
+            }
+        }
+    });
+    // The external-key index maps keys to ids.
+    reindex_search(&owner_str);
+
+    NEXT_TODO.with(|counter_ref| {
+        let mut writer = counter_ref.borrow_mut();
+        *writer = (*writer).max(new_id);
+    });
+    Ok(())
+}
+
+/// Fix ids left inconsistent by older releases: with counter ids,
+/// [NEXT_TODO] is raised to the highest id in use; then within each user,
+/// every todo repeating an earlier todo's id gets a fresh one. Running it
+/// again changes nothing.
+///
+/// Returns:
+///      Future of what was fixed
+/// Panics:
+///      [caller] is not the admin
+#[update(name = "repair_state")]
+fn repair_state() -> RepairReport {
+    assert_admin();
+    log_access("repair_state", &caller());
+
+    let counter_before = NEXT_TODO.with(|counter_ref| *counter_ref.borrow());
+    if CONFIG.with(|config_ref| config_ref.borrow().id_strategy) == IdStrategy::Counter {
+        let max_id = TODO_BY_USER.with(|todos_ref| {
+            todos_ref.borrow().values().flatten().map(|t| t.id).max().unwrap_or_default()
+        });
+        NEXT_TODO.with(|counter_ref| {
+            let mut writer = counter_ref.borrow_mut();
+            *writer = (*writer).max(max_id);
+        });
+    }
+
+    let mut ids_reassigned = 0;
+    let users: Vec<PrincipalName> =
+        TODO_BY_USER.with(|todos_ref| todos_ref.borrow().keys().cloned().collect());
+    for user_str in users {
+        let reassigned: Vec<u128> = TODO_BY_USER.with(|todos_ref| {
+            let mut writer = todos_ref.borrow_mut();
+            let todos = writer.get_mut(&user_str).expect("user listed above");
+            let mut reassigned = vec![];
+            for pos in 1..todos.len() {
+                if todos[..pos].iter().any(|t| t.id == todos[pos].id) {
+                    let id = assign_todo_id(&user_str, todos, &todos[pos].task.text());
+                    todos[pos].id = id;
+                    reassigned.push(id);
+                }
+            }
+            reassigned
+        });
+        for todo_id in &reassigned {
+            record_change(&user_str, ChangeKind::Added, *todo_id);
+        }
+        if !reassigned.is_empty() {
+            reindex_search(&user_str);
+        }
+        ids_reassigned += reassigned.len() as u64;
+    }
+
+    RepairReport {
+        ids_reassigned,
+        counter_before,
+        counter_after: NEXT_TODO.with(|counter_ref| *counter_ref.borrow()),
+    }
+}
+
+/// Remove everything stored for [user]: its todos, prefs, change feed,
+/// tombstones, task history, pins, limit override, and any links, transfers or shares it takes part in. This frees a [MAX_USERS] slot.
+///      [user]: the principal whose data is deleted
+///
+/// Returns:
+///      Future of the number of todos removed
+/// Panics:
+///      [caller] is not the admin
+#[update(name = "admin_delete_user")]
+fn admin_delete_user(user: Principal) -> u64 {
+    assert_admin();
+    log_access("admin_delete_user", &caller());
+
+    let user_str = principal_key(&user);
+    let removed = TODO_BY_USER
+        .with(|todos_ref| todos_ref.borrow_mut().remove(&user_str))
+        .map(|todos| todos.len())
+        .unwrap_or_default();
+    record_deleted(removed);
+    reindex_search(&user_str);
+    USER_LIMITS.with(|limits_ref| limits_ref.borrow_mut().remove(&user_str));
+    PREFS.with(|prefs_ref| prefs_ref.borrow_mut().remove(&user_str));
+    CHANGES.with(|changes_ref| changes_ref.borrow_mut().remove(&user_str));
+    TOMBSTONES.with(|tombstones_ref| tombstones_ref.borrow_mut().remove(&user_str));
+    TASK_HISTORY.with(|history_ref| history_ref.borrow_mut().remove(&user_str));
+    PINS.with(|pins_ref| pins_ref.borrow_mut().remove(&user_str));
+    COMPLETIONS.with(|completions_ref| completions_ref.borrow_mut().remove(&user_str));
+    IMPORT_SESSIONS.with(|sessions_ref| sessions_ref.borrow_mut().remove(&user_str));
+    ADD_BUCKETS.with(|buckets_ref| buckets_ref.borrow_mut().remove(&user_str));
+    // Kept rather than cleared, so a new list never reuses an old version.
+    bump_version(&user_str);
+    ALIASES.with(|aliases_ref| {
+        aliases_ref
+            .borrow_mut()
+            .retain(|alias, primary| *alias != user_str && *primary != user_str)
+    });
+    PENDING_LINKS.with(|pending_ref| {
+        pending_ref
+            .borrow_mut()
+            .retain(|alias, primary| *alias != user_str && *primary != user_str)
+    });
+    ACCEPTED_TRANSFERS.with(|transfers_ref| {
+        transfers_ref
+            .borrow_mut()
+            .retain(|to, from| *to != user_str && *from != user_str)
+    });
+    SHARES.with(|shares_ref| {
+        let mut shares = shares_ref.borrow_mut();
+        shares.remove(&user_str);
+        for viewers in shares.values_mut() {
+            viewers.retain(|viewer| *viewer != user_str);
+        }
+    });
+    reindex_shares();
+    removed as u64
+}
+
+/// Returns how many of this [caller]'s todos are completed out of the
+/// total, and how many todos it completed over the last seven days. A
+/// caller without todos gets zeros.
+#[query(name = "completion_rate")]
+fn completion_rate() -> CompletionRate {
+    let user_str = owner_key(&caller());
+    let week_start = day_of(now()).saturating_sub(6);
+    let completed_last_7_days = COMPLETIONS.with(|completions_ref| {
+        completions_ref
+            .borrow()
+            .get(&user_str)
+            .map_or(0, |days| days.range(week_start..).map(|(_, count)| count).sum())
+    });
+    TODO_BY_USER.with(|todo_ref| {
+        let reader = todo_ref.borrow();
+        let todos = reader.get(&user_str).map(Vec::as_slice).unwrap_or_default();
+        CompletionRate {
+            completed: todos.iter().filter(|t| t.completed).count() as u64,
+            total: todos.len() as u64,
+            completed_last_7_days,
+        }
+    })
+}
+
+/// Returns (a future of) this [caller]'s todos that aren't archived,
+/// leaving out completed ones unless [include_completed] is set.
+///      [include_completed]: whether completed todos are returned too
+#[query(name = "get_todos_v2")]
+fn get_todos_v2(include_completed: bool) -> Vec<Todo> {
+    let user_str = owner_key(&caller());
+    TODO_BY_USER.with(|todo_ref| {
+        todo_ref
+            .borrow()
+            .get(&user_str)
+            .map(|todos| {
+                todos
+                    .iter()
+                    .filter(|t| !t.archived && (include_completed || !t.completed))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    })
+}
+
+/// Returns (a future of) just the ids of the todos [get_all_todos]
+/// returns, in the same order, for clients that fetch details lazily.
+#[query(name = "get_todo_ids")]
+fn get_todo_ids() -> Vec<u128> {
+    get_all_todos().iter().map(|t| t.id).collect()
+}
+
+/// Returns the principal (as text) owning the todo with given id, if any.
+/// This scans every user's todos, so it costs O(total todos) and is
+/// meant for support/debugging only.
+///      [todo_id]: the id of the todo to trace
+///
+/// Panics:
+///      [caller] is not the admin
+#[query(name = "admin_find_owner")]
+fn admin_find_owner(todo_id: u128) -> Option<String> {
+    assert_admin();
+    TODO_BY_USER.with(|todos_ref| {
+        todos_ref
+            .borrow()
+            .iter()
+            .find(|(_, todos)| todos.iter().any(|t| t.id == todo_id))
+            .map(|(owner, _)| owner.clone())
+    })
+}
+
+/// Returns [owner]'s todo with given id, if any, without dumping the
+/// rest of [owner]'s list.
+///      [owner]: the principal owning the todo
+///      [todo_id]: the id of the todo to inspect
+///
+/// Panics:
+///      [caller] is not the admin
+#[query(name = "admin_get_todo")]
+fn admin_get_todo(owner: Principal, todo_id: u128) -> Option<Todo> {
+    assert_admin();
+    let owner_str = principal_key(&owner);
+    TODO_BY_USER.with(|todos_ref| {
+        todos_ref
+            .borrow()
+            .get(&owner_str)
+            .and_then(|todos| todos.iter().find(|t| t.id == todo_id).cloned())
+    })
+}
+
+/// Returns the todos of any user whose task contains [needle], ignoring
+/// case, each paired with its owner's principal (as text). At most
+/// [MAX_ADMIN_SEARCH_RESULTS] pairs are returned. This scans every user's
+/// todos, so it costs O(total todos) and is meant for abuse
+/// investigation only.
+///      [needle]: the text to look for
+///
+/// Panics:
+///      [caller] is not the admin
+#[query(name = "admin_search", guard = "check_arg_size")]
+fn admin_search(needle: String) -> Vec<(String, Todo)> {
+    assert_admin();
+    let needle = needle.to_lowercase();
+    let max_results = MAX_ADMIN_SEARCH_RESULTS.with(|masr| *masr);
+    TODO_BY_USER.with(|todo_ref| {
+        SEARCH_CACHE.with(|cache_ref| {
+            let cache = cache_ref.borrow();
+            todo_ref
+                .borrow()
+                .iter()
+                .filter_map(|(owner, todos)| {
+                    cache.get(owner).map(|entries| (owner, todos, entries))
+                })
+                .flat_map(|(owner, todos, entries)| {
+                    let needle = &needle;
+                    todos
+                        .iter()
+                        .filter(move |t| entries.get(&t.id).is_some_and(|e| e.lowered.contains(needle)))
+                        .map(move |todo| (owner.clone(), todo.clone()))
+                })
+                .take(max_results)
+                .collect()
+        })
+    })
+}
+
+/// Returns the todos of any user that last changed between [from] and
+/// [to] (nanoseconds since the epoch, both inclusive), each paired with
+/// its owner's principal (as text). At most [MAX_ADMIN_SEARCH_RESULTS]
+/// pairs are returned. This scans every user's todos, so it costs
+/// O(total todos) and is meant for audits only.
+///      [from]: the start of the range
+///      [to]: the end of the range
+///
+/// Panics:
+///      [caller] is not the admin
+#[query(name = "admin_modified_between")]
+fn admin_modified_between(from: u64, to: u64) -> Vec<(String, Todo)> {
+    assert_admin();
+    let max_results = MAX_ADMIN_SEARCH_RESULTS.with(|masr| *masr);
+    TODO_BY_USER.with(|todo_ref| {
+        todo_ref
+            .borrow()
+            .iter()
+            .flat_map(|(owner, todos)| {
+                todos
+                    .iter()
+                    .filter(|t| (from..=to).contains(&t.updated_at))
+                    .map(move |todo| (owner.clone(), todo.clone()))
+            })
+            .take(max_results)
+            .collect()
+    })
+}
+
+/// Delete this [caller]'s todo with given id. If none of the 
+/// existing todos have this id, do nothing. 
+/// [id]: the id of the todo to be deleted
+///
+/// Returns: 
+///      Future of unit, or an error in maintenance mode or if the todo is
+///      locked
+/// Panics: 
+///      [caller] is the anonymous identity
+///      [caller] is not a registered user
+///      [id] is unreasonable; see [is_id_sane]
+#[update(name = "delete_todo")]
+fn delete_todo(todo_id: u128) -> Result<(), String> {
+    logged("delete_todo", || {
+        let user = caller();
+        check_writable()?;
+        log_access("delete_todo", &user);
+        assert!(is_id_sane(todo_id));
+
+        let user_str = owner_key(&user);
+        check_unlocked(&user_str, todo_id)?;
+        // shared ownership borrowing
+        TODO_BY_USER.with(|todo_ref| {
+            let mut writer = todo_ref.borrow_mut();
+            if let Some(v) = writer.get_mut(&user_str) {
+                let before = v.len();
+                v.retain(|item| item.id != todo_id);
+                if v.len() < before {
+                    record_deleted(before - v.len());
+                    record_change(&user_str, ChangeKind::Deleted, todo_id);
+                }
+            }
+        });
+        reindex_changed(&user_str);
+        Ok(())
+    })
+}
+
+/// Returns (a future of) this [caller]'s todos.
+/// Returns:
+///     Future of unit, or an error in maintenance mode, if [todo.task]
+///     holds a [Config::blocklist] term, or if the todo is locked or
+///     doesn't exist
+/// Panics: 
+///     [caller] is the anonymous identity
+///     [caller] is not a registered user
+///     [todo.task] exceeds [MAX_TODO_CHARS]
+///     [todo.id] is unreasonable; see [is_id_sane]
+#[update(name = "update_todo", guard = "check_arg_size")]
+fn update_todo(todos: TodoUpdate) -> Result<(), String> {
+    logged("update_todo", || {
+        let user = caller();
+        check_writable()?;
+        log_access("update_todo", &user);
+        assert!(todos.task.chars().count() <= MAX_TODO_CHARS.with(|mnc| *mnc));
+        assert!(is_id_sane(todos.id));
+        check_min_length(&todos.task)?;
+        check_blocklist(&todos.task)?;
+
+        let user_str = owner_key(&user);
+        check_unlocked(&user_str, todos.id)?;
+        TODO_BY_USER.with(|todos_ref| {
+            let mut writer = todos_ref.borrow_mut();
+            let old_todo = writer
+                .get_mut(&user_str)
+                .and_then(|td| td.iter_mut().find(|t| t.id == todos.id))
+                .ok_or_else(|| "todo not found".to_string())?;
+            record_task_history(&user_str, todos.id, &old_todo.task);
+            old_todo.task = todos.task.into();
+            old_todo.touch(TodoOp::Updated);
+            record_change(&user_str, ChangeKind::Updated, todos.id);
+            Ok::<(), String>(())
+        })?;
+        reindex_changed(&user_str);
+        Ok(())
+    })
+}
+
+/// Apply several field changes to this [caller]'s todo at once. Every
+/// present field is validated before any of them is applied, so a patch
+/// either lands entirely or not at all. Completing a recurring todo
+/// spawns its next occurrence.
+///      [patch]: the id of the todo and the fields to change
+///
+/// Returns:
+///      Future of unit, or an error in maintenance mode, if [patch.task]
+///      is shorter than [Config::min_todo_chars] or holds a
+///      [Config::blocklist] term, if the todo is locked or doesn't exist,
+///      or if completing it would spawn an occurrence past
+///      [Config::max_total_todos]
+/// Panics:
+///      [caller] is the anonymous identity
+///      [caller] is not a registered user
+///      [patch.task] exceeds [MAX_TODO_CHARS]
+///      [patch.id] is unreasonable; see [is_id_sane]
+///      The next occurrence would exceed the user's todo limit
+#[update(name = "patch_todo", guard = "check_arg_size")]
+fn patch_todo(patch: TodoPatch) -> Result<(), String> {
+    logged("patch_todo", || {
+        let user = caller();
+        check_writable()?;
+        log_access("patch_todo", &user);
+        if let Some(task) = &patch.task {
+            assert!(task.chars().count() <= MAX_TODO_CHARS.with(|mtc| *mtc));
+            check_min_length(task)?;
+            check_blocklist(task)?;
+        }
+        assert!(is_id_sane(patch.id));
+
+        let user_str = owner_key(&user);
+        check_unlocked(&user_str, patch.id)?;
+        if patch.completed == Some(true) {
+            check_spawn_room(&user_str, patch.id)?;
+        }
+        let limit = todo_limit(&user_str);
+        TODO_BY_USER.with(|todos_ref| {
+            let mut writer = todos_ref.borrow_mut();
+            let todos = writer
+                .get_mut(&user_str)
+                .ok_or_else(|| "todo not found".to_string())?;
+            let todo_count = todos.len();
+            let todo = todos
+                .iter_mut()
+                .find(|t| t.id == patch.id)
+                .ok_or_else(|| "todo not found".to_string())?;
+            let spawns = !todo.completed && patch.completed == Some(true);
+            let spawns = spawns && todo.recurrence.is_some();
+            if spawns {
+                assert!(todo_count < limit);
+            }
+
+            if let Some(task) = patch.task {
+                record_task_history(&user_str, patch.id, &todo.task);
+                todo.task = task.into();
+            }
+            if let Some(priority) = patch.priority {
+                todo.priority = priority;
+            }
+            if let Some(due_at) = patch.due_at {
+                todo.due_at = due_at;
+            }
+            let completes = !todo.completed && patch.completed == Some(true);
+            if let Some(completed) = patch.completed {
+                todo.completed = completed;
+            }
+            todo.touch(if completes { TodoOp::Completed } else { TodoOp::Updated });
+            record_change(&user_str, ChangeKind::Updated, patch.id);
+            if completes {
+                record_completion(&user_str);
+            }
+
+            if spawns {
+                let template = todo.clone();
+                spawn_next_occurrence(&user_str, todos, &template);
+            }
+            Ok::<(), String>(())
+        })?;
+        reindex_changed(&user_str);
+        Ok(())
+    })
+}
+
+/// Add new todo for this [caller]. If [Config::seed_first_todo] is set,
+/// a user's very first add is preceded by that welcome todo. All checks
+/// run before any id is assigned, so a rejected add leaves [NEXT_TODO]
+/// untouched.
+///      [todo]: (encrypted) content of this todo
+///
+/// Returns: 
+///      Future of unit, or an error in maintenance mode, if [todo] is
+///      shorter than [Config::min_todo_chars] or holds a
+///      [Config::blocklist] term, if the canister is at
+///      [Config::max_total_todos], or if the caller is adding faster than
+///      its leaky bucket allows; see [take_add_token]
+/// Panics: 
+///      [caller] is the anonymous identity
+///      [caller] is not a registered user
+///      [todo] exceeds [MAX_TODO_CHARS]
+///      User already has [MAX_TODOS_PER_USER] todos (or its override) and
+///      [UserPrefs::on_full] frees no room
+///      [todo] would be for a new user and [MAX_USERS] is exceeded
+#[update(name = "add_todo", guard = "check_arg_size")]
+fn add_todo(task: String) -> Result<(), String> {
+    logged("add_todo", || {
+        let user = caller();
+        check_writable()?;
+        log_access("add_todo", &user);
+        assert!(task.chars().count() <= MAX_TODO_CHARS.with(|mtc| *mtc));
+        check_min_length(&task)?;
+        check_blocklist(&task)?;
+
+        let user_str = owner_key(&user);
+        check_canister_room(&user_str, 1)?;
+        take_add_token(&user_str)?;
+        insert_todo(&user_str, task);
+        reindex_changed(&user_str);
+        Ok(())
+    })
+}
+
+/// Draws one add from [user_str]'s leaky bucket, after refilling it for
+/// the time passed since the last draw. Bursts up to
+/// [Config::add_bucket_capacity] go through; sustained adds are held to
+/// [Config::add_refill_per_min].
+fn take_add_token(user_str: &str) -> Result<(), String> {
+    const SCALE: u64 = 1_000;
+    let (capacity, refill_per_min) = CONFIG.with(|config_ref| {
+        let config = config_ref.borrow();
+        (config.add_bucket_capacity, config.add_refill_per_min)
+    });
+    if capacity == 0 {
+        return Ok(());
+    }
+    let now = now();
+    let full = capacity.saturating_mul(SCALE);
+    ADD_BUCKETS.with(|buckets_ref| {
+        let mut buckets = buckets_ref.borrow_mut();
+        let bucket = buckets.entry(user_str.to_string()).or_insert(Bucket {
+            milli_tokens: full,
+            last_refill: now,
+        });
+        let elapsed = now.saturating_sub(bucket.last_refill) as u128;
+        let per_min = refill_per_min as u128 * SCALE as u128;
+        let refill = elapsed * per_min / 60_000_000_000;
+        let refilled = bucket.milli_tokens as u128 + refill;
+        if refilled >= full as u128 {
+            bucket.milli_tokens = full;
+            bucket.last_refill = now;
+        } else if refill > 0 {
+            // Only the time turned into milli-tokens is used up; the rest
+            // carries over, so frequent calls still refill.
+            bucket.milli_tokens = refilled as u64;
+            bucket.last_refill += (refill * 60_000_000_000).div_ceil(per_min) as u64;
+        }
+        if bucket.milli_tokens < SCALE {
+            return Err("rate limited".to_string());
+        }
+        bucket.milli_tokens -= SCALE;
+        Ok(())
+    })
+}
+
+/// Appends a new todo with [task] to [user_str]'s list, registering the
+/// user (and seeding its welcome todo) if needed, and returns its id. A
+/// full list makes room first if the user's [UserPrefs::on_full] says so. All
+/// checks run before any id is assigned. The caller reindexes search.
+fn insert_todo(user_str: &str, task: String) -> u128 {
+    let user_count = user_count();
+    let limit = todo_limit(user_str);
+    let on_full = PREFS.with(|prefs_ref| prefs_ref.borrow().get(user_str).map(|p| p.on_full).unwrap_or_default());
+    assert!(todo_room(user_str) > 0);
+    TODO_BY_USER.with(|todos_ref| {
+        let mut writer = todos_ref.borrow_mut();
+        // Validate everything before an id is handed out, so a rejected
+        // add never advances [NEXT_TODO].
+        let seed = match writer.get_mut(user_str) {
+            Some(user_todos) => {
+                if user_todos.len() >= limit && on_full == FullPolicy::DropOldestCompleted {
+                    let oldest = user_todos
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, t)| t.completed && !t.locked)
+                        .min_by_key(|(_, t)| (t.created_at, t.id))
+                        .map(|(pos, _)| pos);
+                    if let Some(pos) = oldest {
+                        let dropped = user_todos.remove(pos);
+                        record_deleted(1);
+                        record_change(user_str, ChangeKind::Deleted, dropped.id);
+                    }
+                }
+                None
+            }
+            None => {
+                // caller unknown ==> check invariants
+                // A. can we add a new user?
+                assert!(MAX_USERS.with(|mu| user_count < *mu));
+                // B. should the new user be greeted with a sample todo?
+                CONFIG.with(|config_ref| config_ref.borrow().seed_first_todo.clone())
+            }
+        };
+
+        let user_todos = writer.entry(user_str.to_string()).or_default();
+        if let Some(seed) = seed {
+            let seed_id = assign_todo_id(user_str, user_todos, &seed);
+            user_todos.push(Todo::new(seed_id, seed));
+            record_created(1);
+            record_change(user_str, ChangeKind::Added, seed_id);
+        }
+
+        let todo_id = assign_todo_id(user_str, user_todos, &task);
+        user_todos.push(Todo::new(todo_id, task));
+        record_created(1);
+        record_change(user_str, ChangeKind::Added, todo_id);
+        todo_id
+    })
+}
+
+/// Add a single tag to this [caller]'s todo with given id. Adding a
+/// tag the todo already carries does nothing.
+///      [todo_id]: the id of the todo to be tagged
+///      [tag]: the tag to add
+///
+/// Returns:
+///      Future of unit, or an error in maintenance mode, if the todo
+///      doesn't exist, or if it's locked
+/// Panics:
+///      [caller] is the anonymous identity
+///      [caller] is not a registered user
+///      [tag] exceeds [Config::max_tag_chars]
+///      The todo already has [Config::max_tags_per_todo] tags
+///      [todo_id] is unreasonable; see [is_id_sane]
+#[update(name = "add_tag", guard = "check_arg_size")]
+fn add_tag(todo_id: u128, tag: String) -> Result<(), String> {
+    logged("add_tag", || {
+        let user = caller();
+        check_writable()?;
+        log_access("add_tag", &user);
+        assert!(tag.chars().count() <= CONFIG.with(|config_ref| config_ref.borrow().max_tag_chars));
+        assert!(is_id_sane(todo_id));
+
+        let user_str = owner_key(&user);
+        check_unlocked(&user_str, todo_id)?;
+        TODO_BY_USER.with(|todos_ref| {
+            let mut writer = todos_ref.borrow_mut();
+            let todo = writer
+                .get_mut(&user_str)
+                .and_then(|td| td.iter_mut().find(|t| t.id == todo_id))
+                .ok_or_else(|| "todo not found".to_string())?;
+            if todo.tags.contains(&tag) {
+                return Ok(());
+            }
+            assert!(todo.tags.len() < CONFIG.with(|config_ref| config_ref.borrow().max_tags_per_todo));
+            todo.tags.push(tag);
+            todo.touch(TodoOp::Updated);
+            record_change(&user_str, ChangeKind::Updated, todo_id);
+            Ok(())
+        })
+    })
+}
+
+/// Remove a single tag from this [caller]'s todo with given id. Removing
+/// a tag the todo does not carry does nothing.
+///      [todo_id]: the id of the todo to be untagged
+///      [tag]: the tag to remove
+///
+/// Returns:
+///      Future of unit, or an error in maintenance mode, if the todo
+///      doesn't exist, or if it's locked
+/// Panics:
+///      [caller] is the anonymous identity
+///      [caller] is not a registered user
+///      [todo_id] is unreasonable; see [is_id_sane]
+#[update(name = "remove_tag", guard = "check_arg_size")]
+fn remove_tag(todo_id: u128, tag: String) -> Result<(), String> {
+    logged("remove_tag", || {
+        let user = caller();
+        check_writable()?;
+        log_access("remove_tag", &user);
+        assert!(is_id_sane(todo_id));
+
+        let user_str = owner_key(&user);
+        check_unlocked(&user_str, todo_id)?;
+        TODO_BY_USER.with(|todos_ref| {
+            let mut writer = todos_ref.borrow_mut();
+            let todo = writer
+                .get_mut(&user_str)
+                .and_then(|td| td.iter_mut().find(|t| t.id == todo_id))
+                .ok_or_else(|| "todo not found".to_string())?;
+            let before = todo.tags.len();
+            todo.tags.retain(|t| *t != tag);
+            if todo.tags.len() < before {
+                todo.touch(TodoOp::Updated);
+                record_change(&user_str, ChangeKind::Updated, todo_id);
+            }
+            Ok(())
+        })
+    })
+}
+
+/// Attach an external asset reference to this [caller]'s todo with given
+/// id. Only the reference is stored, never the asset itself. Attaching a
+/// reference the todo already carries does nothing.
+///      [todo_id]: the id of the todo to attach to
+///      [reference]: an asset-canister URL or content hash
+///
+/// Returns:
+///      Future of unit, or an error in maintenance mode, if [reference]
+///      is empty, or if the todo doesn't exist or is locked
+/// Panics:
+///      [caller] is the anonymous identity
+///      [caller] is not a registered user
+///      [reference] exceeds [MAX_ATTACHMENT_CHARS]
+///      The todo already has [MAX_ATTACHMENTS_PER_TODO] attachments
+///      [todo_id] is unreasonable; see [is_id_sane]
+#[update(name = "add_attachment", guard = "check_arg_size")]
+fn add_attachment(todo_id: u128, reference: String) -> Result<(), String> {
+    logged("add_attachment", || {
+        let user = caller();
+        check_writable()?;
+        log_access("add_attachment", &user);
+        assert!(reference.chars().count() <= MAX_ATTACHMENT_CHARS.with(|mac| *mac));
+        assert!(is_id_sane(todo_id));
+        if reference.trim().is_empty() {
+            return Err("attachment reference is empty".to_string());
+        }
+
+        let user_str = owner_key(&user);
+        check_unlocked(&user_str, todo_id)?;
+        TODO_BY_USER.with(|todos_ref| {
+            let mut writer = todos_ref.borrow_mut();
+            let todo = writer
+                .get_mut(&user_str)
+                .and_then(|td| td.iter_mut().find(|t| t.id == todo_id))
+                .ok_or_else(|| "todo not found".to_string())?;
+            if todo.attachments.contains(&reference) {
+                return Ok(());
+            }
+            assert!(todo.attachments.len() < MAX_ATTACHMENTS_PER_TODO.with(|mapt| *mapt));
+            todo.attachments.push(reference);
+            todo.touch(TodoOp::Updated);
+            record_change(&user_str, ChangeKind::Updated, todo_id);
+            Ok(())
+        })
+    })
+}
+
+/// Remove an attachment reference from this [caller]'s todo with given id.
+/// Removing a reference the todo does not carry does nothing.
+///      [todo_id]: the id of the todo to detach from
+///      [reference]: the reference to remove
+///
+/// Returns:
+///      Future of unit, or an error in maintenance mode, if the todo
+///      doesn't exist, or if it's locked
+/// Panics:
+///      [caller] is the anonymous identity
+///      [caller] is not a registered user
+///      [todo_id] is unreasonable; see [is_id_sane]
+#[update(name = "remove_attachment", guard = "check_arg_size")]
+fn remove_attachment(todo_id: u128, reference: String) -> Result<(), String> {
+    logged("remove_attachment", || {
+        let user = caller();
+        check_writable()?;
+        log_access("remove_attachment", &user);
+        assert!(is_id_sane(todo_id));
+
+        let user_str = owner_key(&user);
+        check_unlocked(&user_str, todo_id)?;
+        TODO_BY_USER.with(|todos_ref| {
+            let mut writer = todos_ref.borrow_mut();
+            let todo = writer
+                .get_mut(&user_str)
+                .and_then(|td| td.iter_mut().find(|t| t.id == todo_id))
+                .ok_or_else(|| "todo not found".to_string())?;
+            let before = todo.attachments.len();
+            todo.attachments.retain(|a| *a != reference);
+            if todo.attachments.len() < before {
+                todo.touch(TodoOp::Updated);
+                record_change(&user_str, ChangeKind::Updated, todo_id);
+            }
+            Ok(())
+        })
+    })
+}
+
+/// Offer to treat [alias] as another sign-in for this [caller]'s todo list.
+/// The link only takes effect once [alias] calls `accept_link`.
+///      [alias]: the principal that should share this [caller]'s todos
+///
+/// Returns:
+///      Future of unit, or an error in maintenance mode or if the link
+///      would create a chain or cycle
+/// Panics:
+///      [caller] is the anonymous identity
+///      [caller] is not a registered user
+#[update(name = "link_principal")]
+fn link_principal(alias: Principal) -> Result<(), String> {
+    logged("link_principal", || {
+        let user = caller();
+        check_writable()?;
+        log_access("link_principal", &user);
+
+        let primary_str = principal_key(&user);
+        let alias_str = principal_key(&alias);
+        assert!(TODO_BY_USER.with(|todos_ref| todos_ref.borrow().contains_key(&primary_str)));
+        check_linkable(&alias_str, &primary_str)?;
+
+        PENDING_LINKS.with(|pending_ref| pending_ref.borrow_mut().insert(alias_str, primary_str));
+        Ok(())
+    })
+}
+
+/// Accept a link offered by [primary], after which this [caller] reads
+/// and writes [primary]'s todo list.
+///      [primary]: the principal that offered the link
+///
+/// Returns:
+///      Future of unit, or an error in maintenance mode, if [primary]
+///      made no such offer, or if the link would create a chain or cycle
+/// Panics:
+///      [caller] is the anonymous identity
+#[update(name = "accept_link")]
+fn accept_link(primary: Principal) -> Result<(), String> {
+    logged("accept_link", || {
+        let user = caller();
+        check_writable()?;
+        log_access("accept_link", &user);
+
+        let alias_str = principal_key(&user);
+        let primary_str = principal_key(&primary);
+        let offered = PENDING_LINKS.with(|pending_ref| pending_ref.borrow().get(&alias_str).cloned());
+        if offered.as_ref() != Some(&primary_str) {
+            return Err("no pending link".to_string());
+        }
+        check_linkable(&alias_str, &primary_str)?;
+
+        PENDING_LINKS.with(|pending_ref| pending_ref.borrow_mut().remove(&alias_str));
+        ALIASES.with(|aliases_ref| aliases_ref.borrow_mut().insert(alias_str, primary_str));
+        Ok(())
+    })
+}
+
+/// Links are kept one level deep: a primary is never an alias and an
+/// alias is never a primary, which rules out chains and cycles. An alias
+/// must also not own todos of its own, since they would become unreachable.
+fn check_linkable(alias_str: &str, primary_str: &str) -> Result<(), String> {
+    if alias_str == primary_str {
+        return Err("cannot link a principal to itself".to_string());
+    }
+    ALIASES.with(|aliases_ref| {
+        let aliases = aliases_ref.borrow();
+        if aliases.contains_key(alias_str) || aliases.contains_key(primary_str) {
+            return Err("principal is already linked".to_string());
+        }
+        if aliases.values().any(|p| p == alias_str) {
+            return Err("principal is already linked".to_string());
+        }
+        Ok(())
+    })?;
+    if TODO_BY_USER.with(|todos_ref| todos_ref.borrow().contains_key(alias_str)) {
+        return Err("alias already has todos".to_string());
+    }
+    Ok(())
+}
+
+/// Make this [caller]'s todo with given id recur. Each time it is
+/// completed, a new occurrence due one interval later is added.
+///      [todo_id]: the id of the todo
+///      [interval]: the repeat interval in nanoseconds
+///
+/// Returns:
+///      Future of unit, or an error in maintenance mode, if the todo
+///      doesn't exist, or if it's locked
+/// Panics:
+///      [caller] is the anonymous identity
+///      [caller] is not a registered user
+///      [interval] is zero
+///      [todo_id] is unreasonable; see [is_id_sane]
+#[update(name = "set_recurrence")]
+fn set_recurrence(todo_id: u128, interval: u64) -> Result<(), String> {
+    logged("set_recurrence", || {
+        let user = caller();
+        check_writable()?;
+        log_access("set_recurrence", &user);
+        assert!(interval > 0);
+        assert!(is_id_sane(todo_id));
+
+        let user_str = owner_key(&user);
+        check_unlocked(&user_str, todo_id)?;
+        TODO_BY_USER.with(|todos_ref| {
+            let mut writer = todos_ref.borrow_mut();
+            let todo = writer
+                .get_mut(&user_str)
+                .and_then(|td| td.iter_mut().find(|t| t.id == todo_id))
+                .ok_or_else(|| "todo not found".to_string())?;
+            todo.recurrence = Some(interval);
+            todo.touch(TodoOp::Updated);
+            record_change(&user_str, ChangeKind::Updated, todo_id);
+            Ok(())
+        })
+    })
+}
+
+/// Stop this [caller]'s todo with given id from recurring. The todo itself
+/// is kept, and occurrences spawned earlier are separate todos that are
+/// left untouched.
+///      [todo_id]: the id of the todo
+///
+/// Returns:
+///      Future of unit, or an error in maintenance mode, if the todo
+///      doesn't exist, or if it's locked
+/// Panics:
+///      [caller] is the anonymous identity
+///      [caller] is not a registered user
+///      [todo_id] is unreasonable; see [is_id_sane]
+#[update(name = "cancel_recurrence")]
+fn cancel_recurrence(todo_id: u128) -> Result<(), String> {
+    logged("cancel_recurrence", || {
+        let user = caller();
+        check_writable()?;
+        log_access("cancel_recurrence", &user);
+        assert!(is_id_sane(todo_id));
+
+        let user_str = owner_key(&user);
+        check_unlocked(&user_str, todo_id)?;
+        TODO_BY_USER.with(|todos_ref| {
+            let mut writer = todos_ref.borrow_mut();
+            let todo = writer
+                .get_mut(&user_str)
+                .and_then(|td| td.iter_mut().find(|t| t.id == todo_id))
+                .ok_or_else(|| "todo not found".to_string())?;
+            todo.recurrence = None;
+            todo.touch(TodoOp::Updated);
+            record_change(&user_str, ChangeKind::Updated, todo_id);
+            Ok(())
+        })
+    })
+}
+
+/// Returns (a future of) this [caller]'s open todos that have no due
+/// date, i.e. the unscheduled "someday" list.
+#[query(name = "get_someday")]
+fn get_someday() -> Vec<Todo> {
+    let user_str = owner_key(&caller());
+    TODO_BY_USER.with(|todo_ref| {
+        todo_ref
+            .borrow()
+            .get(&user_str)
+            .map(|todos| {
+                todos
+                    .iter()
+                    .filter(|t| !t.completed && t.due_at.is_none())
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    })
+}
+
+/// Returns (a future of) this [caller]'s todos that have a recurrence set.
+#[query(name = "get_recurring_todos")]
+fn get_recurring_todos() -> Vec<Todo> {
+    let user_str = owner_key(&caller());
+    TODO_BY_USER.with(|todo_ref| {
+        todo_ref
+            .borrow()
+            .get(&user_str)
+            .map(|todos| {
+                todos
+                    .iter()
+                    .filter(|t| t.recurrence.is_some())
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    })
+}
+
+/// Fold this [caller]'s todo [merge_id] into [keep_id]: the texts are
+/// joined on a new line, the tags are unioned, and [merge_id] is deleted.
+///      [keep_id]: the id of the todo that survives
+///      [merge_id]: the id of the todo merged into it
+///
+/// Returns:
+///      Future of the surviving todo, or an error in maintenance mode, if
+///      either id isn't one of this [caller]'s todos, or if either is locked
+/// Panics:
+///      [caller] is the anonymous identity
+///      [caller] is not a registered user
+///      The joined text exceeds [MAX_TODO_CHARS]
+///      The unioned tags exceed [Config::max_tags_per_todo]
+///      The unioned attachments exceed [MAX_ATTACHMENTS_PER_TODO]
+#[update(name = "merge_todos")]
+fn merge_todos(keep_id: u128, merge_id: u128) -> Result<Todo, String> {
+    logged("merge_todos", || {
+        let user = caller();
+        check_writable()?;
+        log_access("merge_todos", &user);
+        if keep_id == merge_id {
+            return Err("cannot merge a todo into itself".to_string());
+        }
+
+        let user_str = owner_key(&user);
+        check_unlocked(&user_str, keep_id)?;
+        check_unlocked(&user_str, merge_id)?;
+        let survivor = TODO_BY_USER.with(|todos_ref| -> Result<Todo, String> {
+            let mut writer = todos_ref.borrow_mut();
+            let todos = writer
+                .get_mut(&user_str)
+                .ok_or_else(|| "todo not found".to_string())?;
+            let merged = todos
+                .iter()
+                .find(|t| t.id == merge_id)
+                .cloned()
+                .ok_or_else(|| "todo not found".to_string())?;
+            let kept = todos
+                .iter_mut()
+                .find(|t| t.id == keep_id)
+                .ok_or_else(|| "todo not found".to_string())?;
+
+            let task = format!("{}\n{}", kept.task.text(), merged.task.text());
+            assert!(task.chars().count() <= MAX_TODO_CHARS.with(|mtc| *mtc));
+            let mut tags = kept.tags.clone();
+            for tag in merged.tags {
+                if !tags.contains(&tag) {
+                    tags.push(tag);
+                }
+            }
+            assert!(tags.len() <= CONFIG.with(|config_ref| config_ref.borrow().max_tags_per_todo));
+            let mut attachments = kept.attachments.clone();
+            for attachment in merged.attachments {
+                if !attachments.contains(&attachment) {
+                    attachments.push(attachment);
+                }
+            }
+            assert!(attachments.len() <= MAX_ATTACHMENTS_PER_TODO.with(|mapt| *mapt));
+
+            record_task_history(&user_str, keep_id, &kept.task);
+            kept.task = task.into();
+            kept.tags = tags;
+            kept.attachments = attachments;
+            kept.touch(TodoOp::Updated);
+            let survivor = kept.clone();
+            todos.retain(|t| t.id != merge_id);
+            record_deleted(1);
+            record_change(&user_str, ChangeKind::Updated, keep_id);
+            record_change(&user_str, ChangeKind::Deleted, merge_id);
+            Ok(survivor)
+        })?;
+        reindex_changed(&user_str);
+        Ok(survivor)
+    })
+}
+
+/// Picks the todo to work on next among [todos]: incomplete todos only,
+/// preferring ones that aren't overdue at [now], then the highest
+/// priority, then the oldest.
+fn pick_next_task(todos: &[Todo], now: u64) -> Option<&Todo> {
+    todos
+        .iter()
+        .filter(|t| !t.completed)
+        .min_by_key(|t| (t.is_overdue(now), std::cmp::Reverse(t.priority), t.created_at, t.id))
+}
+
+/// Returns (a future of) the todo this [caller] should work on next, or
+/// nothing if none of its todos are still open; see [pick_next_task].
+#[query(name = "next_task")]
+fn next_task() -> Option<Todo> {
+    let user_str = owner_key(&caller());
+    let now = now();
+    TODO_BY_USER.with(|todo_ref| {
+        todo_ref
+            .borrow()
+            .get(&user_str)
+            .and_then(|todos| pick_next_task(todos, now).cloned())
+    })
+}
+
+/// Push this [caller]'s todo with given id back by [by_secs] seconds. A
+/// todo without a due date becomes due [by_secs] seconds from now.
+///      [todo_id]: the id of the todo to snooze
+///      [by_secs]: how far to push the due date, in seconds
+///
+/// Returns:
+///      Future of the new due date in nanoseconds, or an error in
+///      maintenance mode, if the todo doesn't exist, or if it's locked
+/// Panics:
+///      [caller] is the anonymous identity
+///      [caller] is not a registered user
+///      [todo_id] is unreasonable; see [is_id_sane]
+#[update(name = "snooze_todo")]
+fn snooze_todo(todo_id: u128, by_secs: u64) -> Result<u64, String> {
+    logged("snooze_todo", || {
+        let user = caller();
+        check_writable()?;
+        log_access("snooze_todo", &user);
+        assert!(is_id_sane(todo_id));
+
+        let user_str = owner_key(&user);
+        check_unlocked(&user_str, todo_id)?;
+        let by_nanos = by_secs.saturating_mul(1_000_000_000);
+        TODO_BY_USER.with(|todos_ref| {
+            let mut writer = todos_ref.borrow_mut();
+            let todo = writer
+                .get_mut(&user_str)
+                .and_then(|td| td.iter_mut().find(|t| t.id == todo_id))
+                .ok_or_else(|| "todo not found".to_string())?;
+            let due_at = todo
+                .due_at
+                .unwrap_or_else(now)
+                .saturating_add(by_nanos);
+            todo.due_at = Some(due_at);
+            todo.touch(TodoOp::Updated);
+            record_change(&user_str, ChangeKind::Updated, todo_id);
+            Ok(due_at)
+        })
+    })
+}
+
+/// Returns (a future of) this [caller]'s todos split into completed ones,
+/// open ones past their due date, and the remaining open ones.
+#[query(name = "get_grouped")]
+fn get_grouped() -> GroupedTodos {
+    let user_str = owner_key(&caller());
+    let now = now();
+    TODO_BY_USER.with(|todo_ref| {
+        let mut grouped = GroupedTodos::default();
+        for todo in todo_ref.borrow().get(&user_str).into_iter().flatten() {
+            if todo.completed {
+                grouped.completed.push(todo.clone());
+            } else if todo.is_overdue(now) {
+                grouped.overdue.push(todo.clone());
+            } else {
+                grouped.active.push(todo.clone());
+            }
+        }
+        grouped
+    })
+}
+
+/// Returns this [caller]'s prefs, or the defaults if none were set.
+#[query(name = "get_prefs")]
+fn get_prefs() -> UserPrefs {
+    let user_str = owner_key(&caller());
+    PREFS.with(|prefs_ref| prefs_ref.borrow().get(&user_str).cloned().unwrap_or_default())
+}
+
+/// Replace this [caller]'s prefs.
+///      [prefs]: the new prefs
+///
+/// Returns:
+///      Future of unit, or an error in maintenance mode
+/// Panics:
+///      [caller] is the anonymous identity
+///      [caller] is not a registered user
+///      [prefs] holds more than [MAX_TEMPLATES_PER_USER] templates, or one
+///      exceeding [MAX_TODO_CHARS]
+#[update(name = "set_prefs", guard = "check_arg_size")]
+fn set_prefs(prefs: UserPrefs) -> Result<(), String> {
+    logged("set_prefs", || {
+        let user = caller();
+        check_writable()?;
+        log_access("set_prefs", &user);
+        assert!(prefs.templates.len() <= MAX_TEMPLATES_PER_USER.with(|mtpu| *mtpu));
+        assert!(prefs
+            .templates
+            .values()
+            .all(|body| body.chars().count() <= MAX_TODO_CHARS.with(|mtc| *mtc)));
+
+        let user_str = owner_key(&user);
+        assert!(TODO_BY_USER.with(|todos_ref| todos_ref.borrow().contains_key(&user_str)));
+        PREFS.with(|prefs_ref| prefs_ref.borrow_mut().insert(user_str, prefs));
+        Ok(())
+    })
+}
+
+/// Returns [user_str]'s todos that aren't archived, in list order.
+fn unarchived_todos(user_str: &str) -> Vec<Todo> {
+    TODO_BY_USER.with(|todo_ref| {
+        todo_ref
+            .borrow()
+            .get(user_str)
+            .map(|todos| todos.iter().filter(|t| !t.archived).cloned().collect())
+            .unwrap_or_default()
+    })
+}
+
+/// Returns (a future of) [owner]'s todos that aren't archived if [owner]
+/// made them public in its prefs, and nothing otherwise. Any caller may
+/// ask.
+///      [owner]: the principal whose todos are read
+#[query(name = "get_public_todos")]
+fn get_public_todos(owner: Principal) -> Vec<Todo> {
+    let owner_str = owner_key(&owner);
+    let public = PREFS.with(|prefs_ref| prefs_ref.borrow().get(&owner_str).is_some_and(|p| p.public));
+    if !public {
+        return vec![];
+    }
+    unarchived_todos(&owner_str)
+}
+
+/// Rebuilds [SHARED_WITH] from [SHARES].
+fn reindex_shares() {
+    let mut shared_with: BTreeMap<PrincipalName, Vec<PrincipalName>> = BTreeMap::new();
+    SHARES.with(|shares_ref| {
+        for (owner, viewers) in shares_ref.borrow().iter() {
+            for viewer in viewers {
+                shared_with.entry(viewer.clone()).or_default().push(owner.clone());
+            }
+        }
+    });
+    SHARED_WITH.with(|shared_ref| *shared_ref.borrow_mut() = shared_with);
+}
+
+/// Let [viewer] read this [caller]'s todos through `get_shared_todos`.
+/// Sharing with a principal that already has access does nothing.
+///      [viewer]: the principal granted read access
+///
+/// Returns:
+///      Future of unit, or an error in maintenance mode or if [viewer] is
+///      this [caller]
+/// Panics:
+///      [caller] is the anonymous identity
+///      [caller] is not a registered user
+#[update(name = "share_todos")]
+fn share_todos(viewer: Principal) -> Result<(), String> {
+    logged("share_todos", || {
+        let user = caller();
+        check_writable()?;
+        log_access("share_todos", &user);
+
+        let owner_str = owner_key(&user);
+        let viewer_str = owner_key(&viewer);
+        assert!(TODO_BY_USER.with(|todos_ref| todos_ref.borrow().contains_key(&owner_str)));
+        if owner_str == viewer_str {
+            return Err("cannot share with self".to_string());
+        }
+        let added = SHARES.with(|shares_ref| {
+            let mut shares = shares_ref.borrow_mut();
+            let viewers = shares.entry(owner_str.clone()).or_default();
+            if viewers.contains(&viewer_str) {
+                return false;
+            }
+            viewers.push(viewer_str.clone());
+            true
+        });
+        if added {
+            SHARED_WITH.with(|shared_ref| shared_ref.borrow_mut().entry(viewer_str).or_default().push(owner_str));
+        }
+        Ok(())
+    })
+}
+
+/// Revoke [viewer]'s read access to this [caller]'s todos. Revoking
+/// access that was never granted does nothing.
+///      [viewer]: the principal losing read access
+///
+/// Returns:
+///      Future of unit, or an error in maintenance mode
+/// Panics:
+///      [caller] is the anonymous identity
+#[update(name = "unshare_todos")]
+fn unshare_todos(viewer: Principal) -> Result<(), String> {
+    logged("unshare_todos", || {
+        let user = caller();
+        check_writable()?;
+        log_access("unshare_todos", &user);
+
+        let owner_str = owner_key(&user);
+        let viewer_str = owner_key(&viewer);
+        SHARES.with(|shares_ref| {
+            let mut shares = shares_ref.borrow_mut();
+            if let Some(viewers) = shares.get_mut(&owner_str) {
+                viewers.retain(|v| *v != viewer_str);
+                if viewers.is_empty() {
+                    shares.remove(&owner_str);
+                }
+            }
+        });
+        SHARED_WITH.with(|shared_ref| {
+            let mut shared_with = shared_ref.borrow_mut();
+            if let Some(owners) = shared_with.get_mut(&viewer_str) {
+                owners.retain(|o| *o != owner_str);
+                if owners.is_empty() {
+                    shared_with.remove(&viewer_str);
+                }
+            }
+        });
+        Ok(())
+    })
+}
+
+/// Returns (a future of) the principals (as text) who shared their todos
+/// with this [caller], in the order they did so.
+#[query(name = "shared_with_me")]
+fn shared_with_me() -> Vec<String> {
+    let viewer_str = owner_key(&caller());
+    SHARED_WITH.with(|shared_ref| shared_ref.borrow().get(&viewer_str).cloned().unwrap_or_default())
+}
+
+/// Returns (a future of) [owner]'s todos that aren't archived if [owner]
+/// shared them with this [caller], and nothing otherwise.
+///      [owner]: the principal whose todos are read
+#[query(name = "get_shared_todos")]
+fn get_shared_todos(owner: Principal) -> Vec<Todo> {
+    let owner_str = owner_key(&owner);
+    let viewer_str = owner_key(&caller());
+    let shared = SHARES.with(|shares_ref| {
+        shares_ref
+            .borrow()
+            .get(&owner_str)
+            .is_some_and(|viewers| viewers.contains(&viewer_str))
+    });
+    if !shared {
+        return vec![];
+    }
+    unarchived_todos(&owner_str)
+}
+
+/// Rebuilds [SEARCH_CACHE], [EXTERNAL_KEYS] and [USER_STATS] for
+/// [user_str] from its current todos. Writes that change a whole list
+/// (restores, transfers, bulk imports, id changes) call this; writes that
+/// touch single todos call [reindex_changed].
+fn reindex_search(user_str: &str) {
+    UNINDEXED.with(|unindexed_ref| unindexed_ref.borrow_mut().remove(user_str));
+    let indexed = TODO_BY_USER.with(|todos_ref| {
+        todos_ref.borrow().get(user_str).map(|todos| {
+            let entries: BTreeMap<u128, IndexEntry> =
+                todos.iter().map(|t| (t.id, IndexEntry::of(t))).collect();
+            let stats = UserStats {
+                todos: todos.len() as u64,
+                completed: todos.iter().filter(|t| t.completed).count() as u64,
+                chars_used: entries.values().map(|e| e.chars).sum(),
+            };
+            let mut keys = BTreeMap::new();
+            for todo in todos {
+                if let Some(key) = &todo.external_key {
+                    keys.entry(key.clone()).or_insert(todo.id);
+                }
+            }
+            (entries, (keys, stats))
+        })
+    });
+    let (entries, rest) = indexed.unzip();
+    let (keys, stats) = rest.unzip();
+    let previous = USER_STATS.with(|stats_ref| {
+        let mut cache = stats_ref.borrow_mut();
+        match stats {
+            Some(stats) => cache.insert(user_str.to_string(), stats),
+            None => cache.remove(user_str),
+        }
+    });
+    TOTAL_TODOS.with(|total_ref| {
+        let mut total = total_ref.borrow_mut();
+        *total = *total - previous.map_or(0, |s| s.todos) + stats.map_or(0, |s| s.todos);
+    });
+    SEARCH_CACHE.with(|cache_ref| {
+        let mut cache = cache_ref.borrow_mut();
+        match entries {
+            Some(entries) => cache.insert(user_str.to_string(), entries),
+            None => cache.remove(user_str),
+        }
+    });
+    EXTERNAL_KEYS.with(|keys_ref| {
+        let mut index = keys_ref.borrow_mut();
+        match keys {
+            Some(keys) => index.insert(user_str.to_string(), keys),
+            None => index.remove(user_str),
+        }
+    });
+}
+
+/// Brings [SEARCH_CACHE], [EXTERNAL_KEYS] and [USER_STATS] up to date with
+/// the todos [record_change] saw since [user_str] was last reindexed,
+/// leaving the rest of the list alone. Every write that changes a task
+/// text, an external key or a completion must call this (or
+/// [reindex_search]) before returning.
+fn reindex_changed(user_str: &str) {
+    let changed = UNINDEXED.with(|unindexed_ref| unindexed_ref.borrow_mut().remove(user_str));
+    let Some(changed) = changed else {
+        return;
+    };
+    let current: Option<Vec<(u128, Option<IndexEntry>)>> = TODO_BY_USER.with(|todos_ref| {
+        todos_ref.borrow().get(user_str).map(|todos| {
+            changed
+                .iter()
+                .map(|&id| (id, todos.iter().find(|t| t.id == id).map(IndexEntry::of)))
+                .collect()
+        })
+    });
+    let Some(current) = current else {
+        // The list itself is gone
+        return reindex_search(user_str);
+    };
+    for (todo_id, entry) in current {
+        let previous = SEARCH_CACHE.with(|cache_ref| {
+            let mut cache = cache_ref.borrow_mut();
+            let entries = cache.entry(user_str.to_string()).or_default();
+            match &entry {
+                Some(entry) => entries.insert(todo_id, entry.clone()),
+                None => entries.remove(&todo_id),
+            }
+        });
+        USER_STATS.with(|stats_ref| {
+            let mut cache = stats_ref.borrow_mut();
+            let stats = cache.entry(user_str.to_string()).or_default();
+            if let Some(old) = &previous {
+                stats.todos -= 1;
+                stats.completed -= old.completed as u64;
+                stats.chars_used -= old.chars;
+            }
+            if let Some(new) = &entry {
+                stats.todos += 1;
+                stats.completed += new.completed as u64;
+                stats.chars_used += new.chars;
+            }
+        });
+        TOTAL_TODOS.with(|total_ref| {
+            let mut total = total_ref.borrow_mut();
+            *total = *total + entry.is_some() as u64 - previous.is_some() as u64;
+        });
+        let old_key = previous.and_then(|e| e.external_key);
+        let new_key = entry.and_then(|e| e.external_key);
+        if old_key != new_key {
+            EXTERNAL_KEYS.with(|keys_ref| {
+                let mut index = keys_ref.borrow_mut();
+                let keys = index.entry(user_str.to_string()).or_default();
+                if let Some(key) = old_key {
+                    if keys.get(&key) == Some(&todo_id) {
+                        keys.remove(&key);
+                    }
+                }
+                if let Some(key) = new_key {
+                    keys.entry(key).or_insert(todo_id);
+                }
+            });
+        }
+    }
+}
+
+/// Returns the id of [user_str]'s todo carrying [external_key], if any.
+fn todo_by_external_key(user_str: &str, external_key: &str) -> Option<u128> {
+    EXTERNAL_KEYS.with(|keys_ref| {
+        keys_ref
+            .borrow()
+            .get(user_str)
+            .and_then(|keys| keys.get(external_key).copied())
+    })
+}
+
+/// Rebuilds [SEARCH_CACHE], [EXTERNAL_KEYS] and [USER_STATS] for every
+/// user.
+fn reindex_search_all() {
+    let users: Vec<PrincipalName> =
+        TODO_BY_USER.with(|todos_ref| todos_ref.borrow().keys().cloned().collect());
+    SEARCH_CACHE.with(|cache_ref| cache_ref.borrow_mut().clear());
+    EXTERNAL_KEYS.with(|keys_ref| keys_ref.borrow_mut().clear());
+    USER_STATS.with(|stats_ref| stats_ref.borrow_mut().clear());
+    TOTAL_TODOS.with(|total_ref| *total_ref.borrow_mut() = 0);
+    for user_str in users {
+        reindex_search(&user_str);
+    }
+}
+
+/// Returns (a future of) this [caller]'s todos whose task contains
+/// [needle], ignoring case unless [case_sensitive] is set.
+///      [needle]: the text to look for
+///      [case_sensitive]: match [needle]'s exact case
+#[query(name = "search_todos", guard = "check_arg_size")]
+fn search_todos(needle: String, case_sensitive: bool) -> Vec<Todo> {
+    let user_str = owner_key(&caller());
+    if case_sensitive {
+        return TODO_BY_USER.with(|todo_ref| {
+            todo_ref
+                .borrow()
+                .get(&user_str)
+                .map(|todos| {
+                    todos
+                        .iter()
+                        .filter(|t| t.task.text().contains(&needle))
+                        .cloned()
+                        .collect()
+                })
+                .unwrap_or_default()
+        });
+    }
+    let needle = needle.to_lowercase();
+    TODO_BY_USER.with(|todo_ref| {
+        SEARCH_CACHE.with(|cache_ref| {
+            let todos = todo_ref.borrow();
+            let cache = cache_ref.borrow();
+            match (todos.get(&user_str), cache.get(&user_str)) {
+                (Some(todos), Some(entries)) => todos
+                    .iter()
+                    .filter(|t| entries.get(&t.id).is_some_and(|e| e.lowered.contains(&needle)))
+                    .cloned()
+                    .collect(),
+                _ => vec![],
+            }
+        })
+    })
+}
+
+/// Appends an entry to [user_str]'s change feed, dropping the oldest
+/// entries beyond [MAX_CHANGES_PER_USER], bumps [user_str]'s list version
+/// and queues [todo_id] for [reindex_changed]. Deletions also leave a tombstone and drop the todo's task
+/// history.
+fn record_change(user_str: &str, kind: ChangeKind, todo_id: u128) {
+    UNINDEXED.with(|unindexed_ref| {
+        unindexed_ref.borrow_mut().entry(user_str.to_string()).or_default().insert(todo_id);
+    });
+    if kind == ChangeKind::Deleted {
+        record_tombstone(user_str, todo_id);
+        TASK_HISTORY.with(|history_ref| {
+            if let Some(histories) = history_ref.borrow_mut().get_mut(user_str) {
+                histories.remove(&todo_id);
+            }
+        });
+    }
+    bump_version(user_str);
+    CHANGES.with(|changes_ref| {
+        let mut changes = changes_ref.borrow_mut();
+        let log = changes.entry(user_str.to_string()).or_default();
+        log.next_seq += 1;
+        log.entries.push(ChangeEntry {
+            seq: log.next_seq,
+            kind,
+            todo_id,
+            at: now(),
+        });
+        let max_changes = MAX_CHANGES_PER_USER.with(|mcpu| *mcpu);
+        if log.entries.len() > max_changes {
+            let excess = log.entries.len() - max_changes;
+            log.entries.drain(..excess);
+        }
+    })
+}
+
+/// Remembers that [user_str]'s todo [todo_id] was deleted, dropping
+/// tombstones past [TOMBSTONE_RETENTION_SECS] or beyond
+/// [MAX_TOMBSTONES_PER_USER].
+fn record_tombstone(user_str: &str, todo_id: u128) {
+    let now = now();
+    let cutoff = now.saturating_sub(TOMBSTONE_RETENTION_SECS.with(|trs| *trs).saturating_mul(1_000_000_000));
+    TOMBSTONES.with(|tombstones_ref| {
+        let mut tombstones = tombstones_ref.borrow_mut();
+        let kept = tombstones.entry(user_str.to_string()).or_default();
+        kept.retain(|t| t.at >= cutoff);
+        kept.push(DeletedRef { id: todo_id, at: now });
+        let max_tombstones = MAX_TOMBSTONES_PER_USER.with(|mtpu| *mtpu);
+        if kept.len() > max_tombstones {
+            let excess = kept.len() - max_tombstones;
+            kept.drain(..excess);
+        }
+    })
+}
+
+/// Returns the entries of this [caller]'s change feed newer than [seq],
+/// oldest first, and the latest seq to use as the next cursor. Start with
+/// a cursor of 0. Only the last [MAX_CHANGES_PER_USER] entries are kept;
+/// if some entries after [seq] were already dropped, the reply is marked
+/// truncated and the client should fall back to a full `get_todos`.
+/// Every deletion within [TOMBSTONE_RETENTION_SECS] is also listed as a
+/// tombstone, oldest first, however far the feed itself was trimmed.
+///      [seq]: the latest seq the client has seen
+#[query(name = "changes_since")]
+fn changes_since(seq: u64) -> ChangesSince {
+    let user_str = owner_key(&caller());
+    let cutoff = now()
+        .saturating_sub(TOMBSTONE_RETENTION_SECS.with(|trs| *trs).saturating_mul(1_000_000_000));
+    let tombstones = TOMBSTONES.with(|tombstones_ref| {
+        tombstones_ref
+            .borrow()
+            .get(&user_str)
+            .map(|kept| kept.iter().filter(|t| t.at >= cutoff).cloned().collect())
+            .unwrap_or_default()
+    });
+    CHANGES.with(|changes_ref| {
+        let changes = changes_ref.borrow();
+        let log = changes.get(&user_str).cloned().unwrap_or_default();
+        let oldest_kept = log.entries.first().map_or(log.next_seq + 1, |e| e.seq);
+        ChangesSince {
+            truncated: seq.saturating_add(1) < oldest_kept,
+            changes: log.entries.into_iter().filter(|e| e.seq > seq).collect(),
+            latest_seq: log.next_seq,
+            tombstones,
+        }
+    })
+}
+
+/// Returns (a future of) up to [limit] of this [caller]'s todos with an id
+/// greater than [after_id], ordered by id. Pass the last id of one page as
+/// [after_id] of the next; todos added meanwhile never shift a page.
+///      [after_id]: the last id already seen, or none for the first page
+///      [limit]: the maximum number of todos to return
+#[query(name = "get_todos_after")]
+fn get_todos_after(after_id: Option<u128>, limit: usize) -> Vec<Todo> {
+    let user_str = owner_key(&caller());
+    TODO_BY_USER.with(|todo_ref| {
+        let todos = todo_ref.borrow();
+        let mut page: Vec<&Todo> = todos
+            .get(&user_str)
+            .map(|todos| {
+                todos
+                    .iter()
+                    .filter(|t| after_id.is_none_or(|after_id| t.id > after_id))
+                    .collect()
+            })
+            .unwrap_or_default();
+        // Select the page in one linear pass; only the page itself is sorted.
+        if limit == 0 {
+            return vec![];
+        }
+        if page.len() > limit {
+            page.select_nth_unstable_by_key(limit - 1, |t| t.id);
+            page.truncate(limit);
+        }
+        page.sort_unstable_by_key(|t| t.id);
+        page.into_iter().cloned().collect()
+    })
+}
+
+/// Returns (a future of) how many of this [caller]'s todos carry each tag.
+/// Todos without any tag are counted under no tag, which sorts first, so
+/// they never mix with a tag of any name.
+#[query(name = "count_by_tag")]
+fn count_by_tag() -> BTreeMap<Option<String>, u64> {
+    let user_str = owner_key(&caller());
+    TODO_BY_USER.with(|todo_ref| {
+        let mut counts = BTreeMap::new();
+        for todo in todo_ref.borrow().get(&user_str).into_iter().flatten() {
+            if todo.tags.is_empty() {
+                *counts.entry(None).or_insert(0) += 1;
+            }
+            for tag in &todo.tags {
+                *counts.entry(Some(tag.clone())).or_insert(0) += 1;
+            }
+        }
+        counts
+    })
+}
+
+/// Returns (a future of) whether the canister is in maintenance mode,
+/// whether the last upgrade lost the saved state and whether a snapshot
+/// that failed to decode is kept.
+#[query(name = "health")]
+fn health() -> Health {
+    Health {
+        read_only: CONFIG.with(|config_ref| config_ref.borrow().read_only),
+        data_lost: DATA_LOST.with(|lost_ref| *lost_ref.borrow()),
+        lost_snapshot: LOST_SNAPSHOT.with(|lost_ref| !lost_ref.borrow().is_empty()),
+    }
+}
+
+/// Checks [task] against the same rules `add_todo` applies, without
+/// storing anything, so frontends can validate as the user types.
+///      [task]: the candidate task text
+///
+/// Returns:
+///      Future of unit, or an error naming the first rule [task] breaks
+#[query(name = "validate_task", guard = "check_arg_size")]
+fn validate_task(task: String) -> Result<(), String> {
+    if task.chars().count() > MAX_TODO_CHARS.with(|mtc| *mtc) {
+        return Err("task too long".to_string());
+    }
+    check_min_length(&task)?;
+    check_blocklist(&task)
+}
+
+/// Pin this [caller]'s todo with given id to the end of its pinned
+/// section. Pinning an already pinned todo does nothing. Pins of todos
+/// that have since been deleted are dropped first and don't count
+/// towards the cap.
+///      [todo_id]: the id of the todo to pin
+///
+/// Returns:
+///      Future of unit, or an error in maintenance mode or if the todo
+///      does not exist
+/// Panics:
+///      [caller] is the anonymous identity
+///      [caller] is not a registered user
+///      The user already has [MAX_PINS_PER_USER] pins
+#[update(name = "pin_todo")]
+fn pin_todo(todo_id: u128) -> Result<(), String> {
+    logged("pin_todo", || {
+        let user = caller();
+        check_writable()?;
+        log_access("pin_todo", &user);
+
+        let user_str = owner_key(&user);
+        let ids: Vec<u128> = TODO_BY_USER.with(|todos_ref| {
+            todos_ref
+                .borrow()
+                .get(&user_str)
+                .map(|todos| todos.iter().map(|t| t.id).collect())
+                .unwrap_or_default()
+        });
+        if !ids.contains(&todo_id) {
+            return Err("todo not found".to_string());
+        }
+        PINS.with(|pins_ref| {
+            let mut writer = pins_ref.borrow_mut();
+            let pins = writer.entry(user_str).or_default();
+            pins.retain(|id| ids.contains(id));
+            if pins.contains(&todo_id) {
+                return;
+            }
+            assert!(pins.len() < MAX_PINS_PER_USER.with(|mppu| *mppu));
+            pins.push(todo_id);
+        });
+        Ok(())
+    })
+}
+
+/// Unpin this [caller]'s todo with given id. Unpinning a todo that is not
+/// pinned does nothing.
+///      [todo_id]: the id of the todo to unpin
+///
+/// Returns:
+///      Future of unit, or an error in maintenance mode
+/// Panics:
+///      [caller] is the anonymous identity
+///      [caller] is not a registered user
+#[update(name = "unpin_todo")]
+fn unpin_todo(todo_id: u128) -> Result<(), String> {
+    logged("unpin_todo", || {
+        let user = caller();
+        check_writable()?;
+        log_access("unpin_todo", &user);
+
+        let user_str = owner_key(&user);
+        PINS.with(|pins_ref| {
+            if let Some(pins) = pins_ref.borrow_mut().get_mut(&user_str) {
+                pins.retain(|id| *id != todo_id);
+            }
+        });
+        Ok(())
+    })
+}
+
+/// Returns (a future of) this [caller]'s pinned todos, in pin order.
+#[query(name = "get_pinned")]
+fn get_pinned() -> Vec<Todo> {
+    let user_str = owner_key(&caller());
+    let pins = PINS.with(|pins_ref| pins_ref.borrow().get(&user_str).cloned().unwrap_or_default());
+    TODO_BY_USER.with(|todo_ref| {
+        let todos = todo_ref.borrow();
+        let todos = todos.get(&user_str).map(Vec::as_slice).unwrap_or_default();
+        pins.iter()
+            .filter_map(|id| todos.iter().find(|t| t.id == *id).cloned())
+            .collect()
+    })
+}
+
+/// Agree to receive the whole todo list of [from] through `transfer_all_to`.
+/// Nobody can move todos onto this [caller]'s list without this consent,
+/// and this [caller] consents to one sender at a time: accepting another
+/// replaces the earlier consent.
+///      [from]: the principal allowed to transfer its todos here
+///
+/// Returns:
+///      Future of unit, or an error in maintenance mode or if [from] is
+///      this [caller]
+/// Panics:
+///      [caller] is the anonymous identity
+#[update(name = "accept_transfer")]
+fn accept_transfer(from: Principal) -> Result<(), String> {
+    logged("accept_transfer", || {
+        let user = caller();
+        check_writable()?;
+        log_access("accept_transfer", &user);
+
+        let to_str = owner_key(&user);
+        let from_str = owner_key(&from);
+        if from_str == to_str {
+            return Err("cannot transfer to self".to_string());
+        }
+        ACCEPTED_TRANSFERS.with(|transfers_ref| transfers_ref.borrow_mut().insert(to_str, from_str));
+        Ok(())
+    })
+}
+
+/// Move this [caller]'s entire todo list to [to], who must have called
+/// `accept_transfer` for this [caller] first. The todos are appended to
+/// [to]'s list under fresh ids; [to] keeps its own prefs if it has any and
+/// takes over this [caller]'s otherwise. Afterwards this [caller] holds
+/// no todos, prefs or pins.
+///      [to]: the recipient of the todos
+///
+/// Returns:
+///      Future of the number of todos moved, or an error in maintenance
+///      mode or if [to] has not accepted the transfer
+/// Panics:
+///      [caller] is the anonymous identity
+///      [caller] is not a registered user
+///      The moved todos would exceed [to]'s todo limit
+#[update(name = "transfer_all_to")]
+fn transfer_all_to(to: Principal) -> Result<u64, String> {
+    logged("transfer_all_to", || {
+        let user = caller();
+        check_writable()?;
+        log_access("transfer_all_to", &user);
+
+        let from_str = owner_key(&user);
+        let to_str = owner_key(&to);
+        assert!(TODO_BY_USER.with(|todos_ref| todos_ref.borrow().contains_key(&from_str)));
+        let accepted = ACCEPTED_TRANSFERS.with(|transfers_ref| transfers_ref.borrow().get(&to_str).cloned());
+        if accepted.as_ref() != Some(&from_str) {
+            return Err("transfer not accepted".to_string());
+        }
+
+        let limit = todo_limit(&to_str);
+        let moved = TODO_BY_USER.with(|todos_ref| {
+            let mut writer = todos_ref.borrow_mut();
+            let incoming_len = writer.get(&from_str).map_or(0, Vec::len);
+            let existing_len = writer.get(&to_str).map_or(0, Vec::len);
+            assert!(existing_len + incoming_len <= limit);
+
+            let incoming = writer.remove(&from_str).unwrap_or_default();
+            let target = writer.entry(to_str.clone()).or_default();
+            for mut todo in incoming {
+                record_change(&from_str, ChangeKind::Deleted, todo.id);
+                // External keys stay unique per user; a clashing key is dropped.
+                if todo.external_key.as_ref().is_some_and(|key| todo_by_external_key(&to_str, key).is_some()) {
+                    todo.external_key = None;
+                }
+                todo.id = assign_todo_id(&to_str, target, &todo.task.text());
+                record_change(&to_str, ChangeKind::Added, todo.id);
+                target.push(todo);
+            }
+            incoming_len
+        });
+
+        PREFS.with(|prefs_ref| {
+            let mut writer = prefs_ref.borrow_mut();
+            if let Some(prefs) = writer.remove(&from_str) {
+                writer.entry(to_str.clone()).or_insert(prefs);
+            }
+        });
+        PINS.with(|pins_ref| pins_ref.borrow_mut().remove(&from_str));
+        ACCEPTED_TRANSFERS.with(|transfers_ref| transfers_ref.borrow_mut().remove(&to_str));
+        reindex_search(&from_str);
+        reindex_search(&to_str);
+        Ok(moved as u64)
+    })
+}
+
+/// Returns (a future of) this [caller]'s todos whose most recent change
+/// was of kind [op], e.g. to highlight what was just completed.
+///      [op]: the kind of change to look for
+#[query(name = "get_todos_by_last_op")]
+fn get_todos_by_last_op(op: TodoOp) -> Vec<Todo> {
+    let user_str = owner_key(&caller());
+    TODO_BY_USER.with(|todo_ref| {
+        todo_ref
+            .borrow()
+            .get(&user_str)
+            .map(|todos| todos.iter().filter(|t| t.last_op == op).cloned().collect())
+            .unwrap_or_default()
+    })
+}
+
+/// Rearrange this [caller]'s todos into the order of [ids]. Todos left
+/// out of [ids] follow at the end, keeping their previous relative order.
+///      [ids]: todo ids in the desired order
+///
+/// Returns:
+///      Future of unit, or an error in maintenance mode or if [ids] holds
+///      an id this [caller] does not own
+/// Panics:
+///      [caller] is the anonymous identity
+///      [caller] is not a registered user
+#[update(name = "reorder_todos", guard = "check_batch_arg_size")]
+fn reorder_todos(ids: Vec<u128>) -> Result<(), String> {
+    logged("reorder_todos", || {
+        let user = caller();
+        check_writable()?;
+        log_access("reorder_todos", &user);
+
+        let user_str = owner_key(&user);
+        assert!(TODO_BY_USER.with(|todos_ref| todos_ref.borrow().contains_key(&user_str)));
+        TODO_BY_USER.with(|todos_ref| {
+            let mut writer = todos_ref.borrow_mut();
+            let todos = writer.entry(user_str.clone()).or_default();
+            if ids.iter().any(|id| !todos.iter().any(|t| t.id == *id)) {
+                return Err("todo not found".to_string());
+            }
+            let mut rest = std::mem::take(todos);
+            for id in &ids {
+                if let Some(pos) = rest.iter().position(|t| t.id == *id) {
+                    todos.push(rest.remove(pos));
+                }
+            }
+            todos.append(&mut rest);
+            Ok(())
+        })?;
+        bump_version(&user_str);
+        reindex_search(&user_str);
+        Ok(())
+    })
+}
+
+/// Insert or update the todo this [caller] synced under [external_key]:
+/// if one of its todos carries that key its task is replaced, otherwise a
+/// new todo recording the key is added as by `add_todo`.
+///      [external_key]: the todo's id in the external source
+///      [task]: (encrypted) content of this todo
+///
+/// Returns:
+///      Future of the todo's id, or an error in maintenance mode, if
+///      [task] is shorter than [Config::min_todo_chars] or holds a
+///      [Config::blocklist] term, if the keyed todo is locked, or if a new
+///      todo would exceed [Config::max_total_todos]
+/// Panics:
+///      [caller] is the anonymous identity
+///      [task] exceeds [MAX_TODO_CHARS]
+///      [external_key] exceeds [MAX_EXTERNAL_KEY_CHARS]
+///      A new todo would exceed the user's todo limit or [MAX_USERS]
+#[update(name = "upsert_todo", guard = "check_arg_size")]
+fn upsert_todo(external_key: String, task: String) -> Result<u128, String> {
+    logged("upsert_todo", || {
+        let user = caller();
+        check_writable()?;
+        log_access("upsert_todo", &user);
+        assert!(task.chars().count() <= MAX_TODO_CHARS.with(|mtc| *mtc));
+        assert!(external_key.chars().count() <= MAX_EXTERNAL_KEY_CHARS.with(|mekc| *mekc));
+        check_min_length(&task)?;
+        check_blocklist(&task)?;
+
+        let user_str = owner_key(&user);
+        let existing_id = todo_by_external_key(&user_str, &external_key);
+        let existing = TODO_BY_USER.with(|todos_ref| -> Result<Option<u128>, String> {
+            let mut writer = todos_ref.borrow_mut();
+            let Some(todo) = writer
+                .get_mut(&user_str)
+                .and_then(|td| td.iter_mut().find(|t| Some(t.id) == existing_id))
+            else {
+                return Ok(None);
+            };
+            if todo.locked {
+                return Err("todo locked".to_string());
+            }
+            record_task_history(&user_str, todo.id, &todo.task);
+            todo.task = task.clone().into();
+            todo.touch(TodoOp::Updated);
+            Ok(Some(todo.id))
+        })?;
+        let todo_id = match existing {
+            Some(todo_id) => {
+                record_change(&user_str, ChangeKind::Updated, todo_id);
+                todo_id
+            }
+            None => {
+                check_canister_room(&user_str, 1)?;
+                let todo_id = insert_todo(&user_str, task);
+                TODO_BY_USER.with(|todos_ref| {
+                    let mut writer = todos_ref.borrow_mut();
+                    if let Some(todo) = writer
+                        .get_mut(&user_str)
+                        .and_then(|td| td.iter_mut().find(|t| t.id == todo_id))
+                    {
+                        todo.external_key = Some(external_key);
+                    }
+                });
+                todo_id
+            }
+        };
+        reindex_changed(&user_str);
+        Ok(todo_id)
+    })
+}
+
+/// Returns (a future of) how many of this [caller]'s todos were created in
+/// each [bucket_secs]-wide window, as (bucket start in nanoseconds, count)
+/// pairs in ascending order. Empty buckets are left out.
+///      [bucket_secs]: the bucket width in seconds
+///
+/// Returns:
+///      Future of the histogram, or an error if [bucket_secs] is 0
+#[query(name = "created_histogram")]
+fn created_histogram(bucket_secs: u64) -> Result<Vec<(u64, u64)>, String> {
+    if bucket_secs == 0 {
+        return Err("bucket_secs must be positive".to_string());
+    }
+    let bucket_nanos = bucket_secs.saturating_mul(1_000_000_000);
+    let user_str = owner_key(&caller());
+    TODO_BY_USER.with(|todo_ref| {
+        let mut buckets: BTreeMap<u64, u64> = BTreeMap::new();
+        for todo in todo_ref.borrow().get(&user_str).into_iter().flatten() {
+            let start = todo.created_at - todo.created_at % bucket_nanos;
+            *buckets.entry(start).or_insert(0) += 1;
+        }
+        Ok(buckets.into_iter().collect())
+    })
+}
+
+/// Fails with "todo locked" if [user_str]'s todo [todo_id] is locked.
+fn check_unlocked(user_str: &str, todo_id: u128) -> Result<(), String> {
+    let locked = TODO_BY_USER.with(|todos_ref| {
+        todos_ref
+            .borrow()
+            .get(user_str)
+            .and_then(|td| td.iter().find(|t| t.id == todo_id))
+            .is_some_and(|t| t.locked)
+    });
+    if locked {
+        return Err("todo locked".to_string());
+    }
+    Ok(())
+}
+
+/// Lock or unlock this [caller]'s todo with given id. While locked, the
+/// todo can't be updated, patched (including completing it), merged or
+/// deleted.
+///      [todo_id]: the id of the todo
+///      [locked]: whether the todo should be locked
+///
+/// Returns:
+///      Future of unit, or an error in maintenance mode or if the todo
+///      doesn't exist
+/// Panics:
+///      [caller] is the anonymous identity
+///      [caller] is not a registered user
+///      [todo_id] is unreasonable; see [is_id_sane]
+#[update(name = "set_todo_locked")]
+fn set_todo_locked(todo_id: u128, locked: bool) -> Result<(), String> {
+    logged("set_todo_locked", || {
+        let user = caller();
+        check_writable()?;
+        log_access("set_todo_locked", &user);
+        assert!(is_id_sane(todo_id));
+
+        let user_str = owner_key(&user);
+        TODO_BY_USER.with(|todos_ref| {
+            let mut writer = todos_ref.borrow_mut();
+            let todo = writer
+                .get_mut(&user_str)
+                .and_then(|td| td.iter_mut().find(|t| t.id == todo_id))
+                .ok_or_else(|| "todo not found".to_string())?;
+            if todo.locked != locked {
+                todo.locked = locked;
+                todo.touch(TodoOp::Updated);
+                record_change(&user_str, ChangeKind::Updated, todo_id);
+            }
+            Ok(())
+        })
+    })
+}
+
+/// Remove this [caller]'s open todos whose task, trimmed and lowercased,
+/// repeats that of an older open todo; the oldest copy is kept. Completed,
+/// archived and locked todos are never removed.
+///
+/// Returns:
+///      Future of the number of todos removed, or an error in maintenance
+///      mode
+/// Panics:
+///      [caller] is the anonymous identity
+///      [caller] is not a registered user
+#[update(name = "deduplicate_my_todos")]
+fn deduplicate_my_todos() -> Result<u64, String> {
+    logged("deduplicate_my_todos", || {
+        let user = caller();
+        check_writable()?;
+        log_access("deduplicate_my_todos", &user);
+
+        let user_str = owner_key(&user);
+        let removed = TODO_BY_USER.with(|todos_ref| {
+            let mut writer = todos_ref.borrow_mut();
+            let Some(todos) = writer.get_mut(&user_str) else {
+                return vec![];
+            };
+            let mut keepers: BTreeMap<String, (u64, u128)> = BTreeMap::new();
+            for todo in todos.iter().filter(|t| !t.completed && !t.archived) {
+                let key = todo.task.text().trim().to_lowercase();
+                let keeper = keepers.entry(key).or_insert((todo.created_at, todo.id));
+                if todo.created_at < keeper.0 {
+                    *keeper = (todo.created_at, todo.id);
+                }
+            }
+            let mut removed = vec![];
+            todos.retain(|t| {
+                let keep = t.completed
+                    || t.archived
+                    || t.locked
+                    || keepers.get(t.task.text().trim().to_lowercase().as_str()).map(|k| k.1) == Some(t.id);
+                if !keep {
+                    removed.push(t.id);
+                }
+                keep
+            });
+            removed
+        });
+        record_deleted(removed.len());
+        for todo_id in &removed {
+            record_change(&user_str, ChangeKind::Deleted, *todo_id);
+        }
+        reindex_search(&user_str);
+        Ok(removed.len() as u64)
+    })
+}
+
+/// Returns (a future of) this [caller]'s todos grouped by tag, in tag
+/// order. A todo appears under each of its tags; todos without any tag
+/// form a first group with no tag.
+#[query(name = "get_todos_grouped_by_tag")]
+fn get_todos_grouped_by_tag() -> Vec<(Option<String>, Vec<Todo>)> {
+    let user_str = owner_key(&caller());
+    TODO_BY_USER.with(|todo_ref| {
+        let mut groups: BTreeMap<Option<String>, Vec<Todo>> = BTreeMap::new();
+        for todo in todo_ref.borrow().get(&user_str).into_iter().flatten() {
+            if todo.tags.is_empty() {
+                groups.entry(None).or_default().push(todo.clone());
+            }
+            for tag in &todo.tags {
+                groups.entry(Some(tag.clone())).or_default().push(todo.clone());
+            }
+        }
+        groups.into_iter().collect()
+    })
+}
+
+/// Archive all of this [caller]'s completed todos at once, hiding them
+/// from `get_todos` while keeping them retrievable through
+/// `get_archived_todos`. Locked todos are left as they are.
+///
+/// Returns:
+///      Future of the number of todos archived, or an error in
+///      maintenance mode
+/// Panics:
+///      [caller] is the anonymous identity
+///      [caller] is not a registered user
+#[update(name = "archive_completed")]
+fn archive_completed() -> Result<u64, String> {
+    archive_completed_as("archive_completed")
+}
+
+/// Does the work of [archive_completed], logged as a call to [method].
+fn archive_completed_as(method: &str) -> Result<u64, String> {
+    logged(method, || {
+        let user = caller();
+        check_writable()?;
+        log_access(method, &user);
+
+        let user_str = owner_key(&user);
+        let archived: Vec<u128> = TODO_BY_USER.with(|todos_ref| {
+            let mut writer = todos_ref.borrow_mut();
+            writer
+                .get_mut(&user_str)
+                .into_iter()
+                .flatten()
+                .filter(|t| t.completed && !t.archived && !t.locked)
+                .map(|todo| {
+                    todo.archived = true;
+                    todo.touch(TodoOp::Updated);
+                    todo.id
+                })
+                .collect()
+        });
+        for todo_id in &archived {
+            record_change(&user_str, ChangeKind::Updated, *todo_id);
+        }
+        Ok(archived.len() as u64)
+    })
+}
+
+/// Returns (a future of) this [caller]'s archived todos.
+#[query(name = "get_archived_todos")]
+fn get_archived_todos() -> Vec<Todo> {
+    let user_str = owner_key(&caller());
+    TODO_BY_USER.with(|todo_ref| {
+        todo_ref
+            .borrow()
+            .get(&user_str)
+            .map(|todos| todos.iter().filter(|t| t.archived).cloned().collect())
+            .unwrap_or_default()
+    })
+}
+
+/// Returns (a future of) this [caller]'s todos as `get_todos` does, but
+/// trimmed to the id, task, completion and the parts [projection] asks for.
+///      [projection]: the optional parts to include
+#[query(name = "get_todos_projected")]
+fn get_todos_projected(projection: Projection) -> Vec<TodoView> {
+    get_todos()
+        .iter()
+        .map(|todo| TodoView::project(todo, projection))
+        .collect()
+}
+
+/// Remembers [prior] as an earlier task of [user_str]'s todo [todo_id],
+/// dropping the oldest versions beyond [MAX_HISTORY_PER_TODO].
+fn record_task_history(user_str: &str, todo_id: u128, prior: &TaskText) {
+    TASK_HISTORY.with(|history_ref| {
+        let mut history = history_ref.borrow_mut();
+        let versions = history
+            .entry(user_str.to_string())
+            .or_default()
+            .entry(todo_id)
+            .or_default();
+        versions.push(prior.clone());
+        let max_history = MAX_HISTORY_PER_TODO.with(|mhpt| *mhpt);
+        if versions.len() > max_history {
+            let excess = versions.len() - max_history;
+            versions.drain(..excess);
+        }
+    })
+}
+
+/// Returns (a future of) the earlier tasks of this [caller]'s todo with
+/// given id, oldest first, as indexed by `revert_todo`.
+///      [todo_id]: the id of the todo
+#[query(name = "get_task_history")]
+fn get_task_history(todo_id: u128) -> Vec<String> {
+    let user_str = owner_key(&caller());
+    TASK_HISTORY.with(|history_ref| {
+        history_ref
+            .borrow()
+            .get(&user_str)
+            .and_then(|histories| histories.get(&todo_id))
+            .map(|versions| versions.iter().map(|v| v.text().into_owned()).collect())
+            .unwrap_or_default()
+    })
+}
+
+/// Restore the task of this [caller]'s todo with given id to an earlier
+/// version from `get_task_history`. The replaced task is itself added to
+/// the history, so a revert can be reverted.
+///      [todo_id]: the id of the todo
+///      [index]: the position of the version in `get_task_history`
+///
+/// Returns:
+///      Future of unit, or an error in maintenance mode, if the todo is
+///      locked or doesn't exist, or if [index] is out of range
+/// Panics:
+///      [caller] is the anonymous identity
+///      [caller] is not a registered user
+///      The restored task exceeds [MAX_TODO_CHARS]
+#[update(name = "revert_todo")]
+fn revert_todo(todo_id: u128, index: u64) -> Result<(), String> {
+    logged("revert_todo", || {
+        let user = caller();
+        check_writable()?;
+        log_access("revert_todo", &user);
+
+        let user_str = owner_key(&user);
+        check_unlocked(&user_str, todo_id)?;
+        let version = TASK_HISTORY.with(|history_ref| {
+            history_ref
+                .borrow()
+                .get(&user_str)
+                .and_then(|histories| histories.get(&todo_id))
+                .and_then(|versions| versions.get(index as usize).cloned())
+        });
+        let version = version.ok_or_else(|| "history index out of range".to_string())?;
+        assert!(version.text().chars().count() <= MAX_TODO_CHARS.with(|mtc| *mtc));
+
+        TODO_BY_USER.with(|todos_ref| {
+            let mut writer = todos_ref.borrow_mut();
+            let todo = writer
+                .get_mut(&user_str)
+                .and_then(|td| td.iter_mut().find(|t| t.id == todo_id))
+                .ok_or_else(|| "todo not found".to_string())?;
+            record_task_history(&user_str, todo_id, &todo.task);
+            todo.task = version;
+            todo.touch(TodoOp::Updated);
+            record_change(&user_str, ChangeKind::Updated, todo_id);
+            Ok::<(), String>(())
+        })?;
+        reindex_changed(&user_str);
+        Ok(())
+    })
+}
+
+/// Returns (a future of) this [caller]'s todos with the given ids, in the
+/// order asked for. Ids that aren't this [caller]'s are skipped.
+///      [ids]: the ids of the todos to fetch
+///
+/// Panics:
+///      [ids] holds more than [MAX_IDS_PER_BATCH] ids
+#[query(name = "get_todos_by_ids", guard = "check_arg_size")]
+fn get_todos_by_ids(ids: Vec<u128>) -> Vec<Todo> {
+    assert!(ids.len() <= MAX_IDS_PER_BATCH.with(|mipb| *mipb));
+    let user_str = owner_key(&caller());
+    TODO_BY_USER.with(|todo_ref| {
+        let todos = todo_ref.borrow();
+        let todos = todos.get(&user_str).map(Vec::as_slice).unwrap_or_default();
+        ids.iter()
+            .filter_map(|id| todos.iter().find(|t| t.id == *id).cloned())
+            .collect()
+    })
+}
+
+/// Returns (a future of) how many of this [caller]'s todos are not yet
+/// completed.
+#[query(name = "count_active")]
+fn count_active() -> u64 {
+    let stats = user_stats(&owner_key(&caller()));
+    stats.todos - stats.completed
+}
+
+/// Returns (a future of) how many of this [caller]'s todos are completed,
+/// archived ones included.
+#[query(name = "count_completed")]
+fn count_completed() -> u64 {
+    user_stats(&owner_key(&caller())).completed
+}
+
+/// Returns (a future of) how many todos this [caller] has, archived ones
+/// included.
+#[query(name = "count_todos")]
+fn count_todos() -> u64 {
+    user_stats(&owner_key(&caller())).todos
+}
+
+/// Returns [user_str]'s cached counts; all zero for an unknown user.
+fn user_stats(user_str: &str) -> UserStats {
+    USER_STATS.with(|stats_ref| stats_ref.borrow().get(user_str).copied().unwrap_or_default())
+}
+
+/// Set or clear the external key of this [caller]'s todo with given id.
+/// External keys are unique per user.
+///      [todo_id]: the id of the todo
+///      [external_key]: the key to record, or none to clear it
+///
+/// Returns:
+///      Future of unit, or an error in maintenance mode, if the todo
+///      doesn't exist or is locked, or if another todo already carries
+///      [external_key]
+/// Panics:
+///      [caller] is the anonymous identity
+///      [caller] is not a registered user
+///      [external_key] exceeds [MAX_EXTERNAL_KEY_CHARS]
+#[update(name = "set_external_key", guard = "check_arg_size")]
+fn set_external_key(todo_id: u128, external_key: Option<String>) -> Result<(), String> {
+    logged("set_external_key", || {
+        let user = caller();
+        check_writable()?;
+        log_access("set_external_key", &user);
+        if let Some(key) = &external_key {
+            assert!(key.chars().count() <= MAX_EXTERNAL_KEY_CHARS.with(|mekc| *mekc));
+        }
+
+        let user_str = owner_key(&user);
+        check_unlocked(&user_str, todo_id)?;
+        if let Some(key) = &external_key {
+            if todo_by_external_key(&user_str, key).is_some_and(|id| id != todo_id) {
+                return Err("duplicate external key".to_string());
+            }
+        }
+        TODO_BY_USER.with(|todos_ref| {
+            let mut writer = todos_ref.borrow_mut();
+            let todo = writer
+                .get_mut(&user_str)
+                .and_then(|td| td.iter_mut().find(|t| t.id == todo_id))
+                .ok_or_else(|| "todo not found".to_string())?;
+            todo.external_key = external_key;
+            todo.touch(TodoOp::Updated);
+            record_change(&user_str, ChangeKind::Updated, todo_id);
+            Ok::<(), String>(())
+        })?;
+        reindex_changed(&user_str);
+        Ok(())
+    })
+}
+
+/// Remove all of this [caller]'s completed todos in one pass, freeing
+/// their quota, or archive them as `archive_completed` does if
+/// [Config::clear_archives] is set. Locked todos are left as they are.
+///
+/// Returns:
+///      Future of the number of todos removed or archived, or an error in
+///      maintenance mode
+/// Panics:
+///      [caller] is the anonymous identity
+///      [caller] is not a registered user
+#[update(name = "clear_completed")]
+fn clear_completed() -> Result<u64, String> {
+    if CONFIG.with(|config_ref| config_ref.borrow().clear_archives) {
+        return archive_completed_as("clear_completed");
+    }
+    logged("clear_completed", || {
+        let user = caller();
+        check_writable()?;
+        log_access("clear_completed", &user);
+
+        let user_str = owner_key(&user);
+        let mut removed = vec![];
+        TODO_BY_USER.with(|todos_ref| {
+            if let Some(todos) = todos_ref.borrow_mut().get_mut(&user_str) {
+                todos.retain(|t| {
+                    let keep = !t.completed || t.locked;
+                    if !keep {
+                        removed.push(t.id);
+                    }
+                    keep
+                });
+            }
+        });
+        record_deleted(removed.len());
+        for todo_id in &removed {
+            record_change(&user_str, ChangeKind::Deleted, *todo_id);
+        }
+        reindex_search(&user_str);
+        Ok(removed.len() as u64)
+    })
+}
+
+/// Set or clear the display color of this [caller]'s todo with given id.
+///      [todo_id]: the id of the todo
+///      [color]: a hex color like `#1E90FF`, or none to clear it
+///
+/// Returns:
+///      Future of unit, or an error in maintenance mode, if [color] is not
+///      a `#RRGGBB` hex color, if the todo doesn't exist, or if it's locked
+/// Panics:
+///      [caller] is the anonymous identity
+///      [caller] is not a registered user
+#[update(name = "set_todo_color", guard = "check_arg_size")]
+fn set_todo_color(todo_id: u128, color: Option<String>) -> Result<(), String> {
+    logged("set_todo_color", || {
+        let user = caller();
+        check_writable()?;
+        log_access("set_todo_color", &user);
+        if color.as_deref().is_some_and(|c| !is_hex_color(c)) {
+            return Err("invalid color".to_string());
+        }
+
+        let user_str = owner_key(&user);
+        check_unlocked(&user_str, todo_id)?;
+        TODO_BY_USER.with(|todos_ref| {
+            let mut writer = todos_ref.borrow_mut();
+            let todo = writer
+                .get_mut(&user_str)
+                .and_then(|td| td.iter_mut().find(|t| t.id == todo_id))
+                .ok_or_else(|| "todo not found".to_string())?;
+            todo.color = color;
+            todo.touch(TodoOp::Updated);
+            record_change(&user_str, ChangeKind::Updated, todo_id);
+            Ok(())
+        })
+    })
+}
+
+/// Marks [user_str]'s list as changed for `todos_version`.
+fn bump_version(user_str: &str) {
+    VERSIONS.with(|versions_ref| {
+        *versions_ref.borrow_mut().entry(user_str.to_string()).or_default() += 1;
+    });
+}
+
+/// Returns (a future of) the version of this [caller]'s list. It changes
+/// whenever the list is written to and stays the same across reads, so a
+/// client can poll it and only refetch `get_todos` when it differs.
+#[query(name = "todos_version")]
+fn todos_version() -> u64 {
+    let user_str = owner_key(&caller());
+    VERSIONS.with(|versions_ref| versions_ref.borrow().get(&user_str).copied().unwrap_or_default())
+}
+
+/// Whether [word] is a todo.txt date, `YYYY-MM-DD`.
+fn is_todotxt_date(word: &str) -> bool {
+    word.len() == 10
+        && word.char_indices().all(|(i, c)| match i {
+            4 | 7 => c == '-',
+            _ => c.is_ascii_digit(),
+        })
+}
+
+/// Whether [word] is a todo.txt priority, `(A)` to `(Z)`.
+fn is_todotxt_priority(word: &str) -> bool {
+    let b = word.as_bytes();
+    b.len() == 3 && b[0] == b'(' && b[1].is_ascii_uppercase() && b[2] == b')'
+}
+
+/// Parses one todo.txt [line]: a leading `x ` marks it completed, a
+/// priority `(A)` to `(Z)` maps to 26 down to 1, completion and creation
+/// dates are dropped, `+project` words become the tag `project` and
+/// `@context` words the tag `@context`. A word escaped with a leading `\`
+/// is task text, minus the `\`. Returns none if no task text is left.
+fn parse_todotxt_line(line: &str) -> Option<TodoTxtLine> {
+    let mut words = line.split_whitespace().peekable();
+    let completed = words.next_if_eq(&"x").is_some();
+    let priority = words
+        .next_if(|w| is_todotxt_priority(w))
+        .map_or(0, |w| b'Z' - w.as_bytes()[1] + 1);
+    while words.next_if(|w| is_todotxt_date(w)).is_some() {}
+
+    let mut task = vec![];
+    let mut tags = vec![];
+    for word in words {
+        let tag = match word.strip_prefix('+') {
+            Some(project) if !project.is_empty() => Some(project),
+            _ if word.len() > 1 && word.starts_with('@') => Some(word),
+            _ => None,
+        };
+        if let Some(tag) = tag {
+            if !tags.iter().any(|t| t == tag) {
+                tags.push(tag.to_string());
+            }
+        } else {
+            task.push(word.strip_prefix('\\').filter(|w| !w.is_empty()).unwrap_or(word));
+        }
+    }
+    if task.is_empty() {
+        return None;
+    }
+    Some(TodoTxtLine {
+        task: task.join(" "),
+        completed,
+        priority,
+        tags,
+    })
+}
+
+/// Formats [todo] as one todo.txt line, the inverse of
+/// [parse_todotxt_line]. Priorities above 26 are written as `(A)`, and
+/// runs of whitespace in the task, line breaks included, become single
+/// spaces. Task words that would read back as something else, such as a
+/// leading `x` or an embedded `+word`, are escaped with a `\`.
+fn format_todotxt_line(todo: &Todo) -> String {
+    let mut words = vec![];
+    if todo.completed {
+        words.push("x".to_string());
+    }
+    if todo.priority > 0 {
+        let letter = b'Z' + 1 - todo.priority.min(26);
+        words.push(format!("({})", letter as char));
+    }
+    for (i, word) in todo.task.text().split_whitespace().enumerate() {
+        let special = word.starts_with('\\')
+            || (word.len() > 1 && (word.starts_with('+') || word.starts_with('@')))
+            || (i == 0 && (word == "x" || is_todotxt_priority(word) || is_todotxt_date(word)));
+        words.push(if special { format!("\\{}", word) } else { word.to_string() });
+    }
+    for tag in &todo.tags {
+        words.push(if tag.starts_with('@') { tag.clone() } else { format!("+{}", tag) });
+    }
+    words.join(" ")
+}
+
+/// Parses each non-blank line of a todo.txt [file], leaving `None` for
+/// lines that don't parse or whose task or tags break the usual limits.
+fn parse_todotxt_file(file: &str) -> Vec<Option<TodoTxtLine>> {
+    let max_todo_chars = MAX_TODO_CHARS.with(|mtc| *mtc);
+    let (max_tags, max_tag_chars) = CONFIG.with(|config_ref| {
+        let config = config_ref.borrow();
+        (config.max_tags_per_todo, config.max_tag_chars)
+    });
+    file.lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|line| {
+            parse_todotxt_line(line).filter(|p| {
+                p.task.chars().count() <= max_todo_chars
+                    && check_min_length(&p.task).is_ok()
+                    && check_blocklist(&p.task).is_ok()
+                    && p.tags.len() <= max_tags
+                    && p.tags.iter().all(|t| t.chars().count() <= max_tag_chars)
+            })
+        })
+        .collect()
+}
+
+/// Add a todo for each line of a todo.txt [file]; see
+/// [parse_todotxt_line] for how lines map to todos. Blank lines are
+/// ignored. Lines that don't parse, or whose task or tags break the usual
+/// limits, are skipped and counted. The file may take up to
+/// [MAX_BATCH_ARG_BYTES] encoded, enough for a full list.
+///      [file]: the raw todo.txt content
+///
+/// Returns:
+///      Future of the imported and skipped line counts, or an error in
+///      maintenance mode or if the valid lines would exceed
+///      [Config::max_total_todos]
+/// Panics:
+///      [caller] is the anonymous identity
+///      [caller] is not a registered user
+///      The imported todos would exceed the user's todo limit
+///      The import would be for a new user and [MAX_USERS] is exceeded
+#[update(name = "import_todotxt", guard = "check_batch_arg_size")]
+fn import_todotxt(file: String) -> Result<ImportCounts, String> {
+    logged("import_todotxt", || {
+        let user = caller();
+        check_writable()?;
+        log_access("import_todotxt", &user);
+
+        let user_str = owner_key(&user);
+        let lines = parse_todotxt_file(&file);
+        check_canister_room(&user_str, lines.iter().flatten().count() as u64)?;
+
+        let mut counts = ImportCounts { imported: 0, skipped: 0 };
+        for parsed in lines {
+            let Some(parsed) = parsed else {
+                counts.skipped += 1;
+                continue;
+            };
+            let todo_id = insert_todo(&user_str, parsed.task);
+            TODO_BY_USER.with(|todos_ref| {
+                if let Some(todo) = todos_ref
+                    .borrow_mut()
+                    .get_mut(&user_str)
+                    .and_then(|td| td.iter_mut().find(|t| t.id == todo_id))
+                {
+                    todo.completed = parsed.completed;
+                    todo.priority = parsed.priority;
+                    todo.tags = parsed.tags;
+                }
+            });
+            counts.imported += 1;
+        }
+        reindex_search(&user_str);
+        Ok(counts)
+    })
+}
+
+/// Returns (a future of) all of this [caller]'s todos as a todo.txt file,
+/// one line per todo by id, like the other exports, so unchanged data
+/// always exports the same; see [format_todotxt_line]. Importing the file
+/// with `import_todotxt` restores the task, completion, priority and tags.
+#[query(name = "export_todotxt")]
+fn export_todotxt() -> String {
+    let user_str = owner_key(&caller());
+    TODO_BY_USER.with(|todo_ref| {
+        todo_ref
+            .borrow()
+            .get(&user_str)
+            .map(|todos| {
+                let mut todos: Vec<&Todo> = todos.iter().collect();
+                todos.sort_by_key(|t| t.id);
+                todos.into_iter().map(|t| format_todotxt_line(t) + "\n").collect()
+            })
+            .unwrap_or_default()
+    })
+}
+
+/// Save, replace or (given no [body]) delete this [caller]'s template
+/// [name]. `{{placeholder}}` tokens in [body] are filled in by
+/// `add_from_template`.
+///      [name]: the name of the template
+///      [body]: the task text of the template
+///
+/// Returns:
+///      Future of unit, or an error in maintenance mode
+/// Panics:
+///      [caller] is the anonymous identity
+///      [caller] is not a registered user
+///      [body] exceeds [MAX_TODO_CHARS]
+///      The user already has [MAX_TEMPLATES_PER_USER] other templates
+#[update(name = "set_task_template", guard = "check_arg_size")]
+fn set_task_template(name: String, body: Option<String>) -> Result<(), String> {
+    logged("set_task_template", || {
+        let user = caller();
+        check_writable()?;
+        log_access("set_task_template", &user);
+
+        let user_str = owner_key(&user);
+        assert!(TODO_BY_USER.with(|todos_ref| todos_ref.borrow().contains_key(&user_str)));
+        PREFS.with(|prefs_ref| {
+            let mut writer = prefs_ref.borrow_mut();
+            let templates = &mut writer.entry(user_str).or_default().templates;
+            match body {
+                Some(body) => {
+                    assert!(body.chars().count() <= MAX_TODO_CHARS.with(|mtc| *mtc));
+                    if !templates.contains_key(&name) {
+                        assert!(templates.len() < MAX_TEMPLATES_PER_USER.with(|mtpu| *mtpu));
+                    }
+                    templates.insert(name, body);
+                }
+                None => {
+                    templates.remove(&name);
+                }
+            }
+        });
+        Ok(())
+    })
+}
+
+/// Replaces every `{{placeholder}}` token in [body] that [values] has an
+/// entry for. Tokens without a value are left as they are.
+fn expand_template(body: &str, values: &BTreeMap<String, String>) -> String {
+    let mut expanded = String::with_capacity(body.len());
+    let mut rest = body;
+    while let Some(start) = rest.find("{{") {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        match after.find("}}").and_then(|end| values.get(&after[..end]).map(|v| (end, v))) {
+            Some((end, value)) => {
+                expanded.push_str(value);
+                rest = &after[end + 2..];
+            }
+            None => {
+                expanded.push_str("{{");
+                rest = after;
+            }
+        }
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+/// Add a todo for this [caller] from its template [name], as `add_todo`
+/// would, with `{{placeholder}}` tokens filled in from [values]; see
+/// [expand_template].
+///      [name]: the name of the template
+///      [values]: the text to put in place of each placeholder
+///
+/// Returns:
+///      Future of the new todo's id, or an error in maintenance mode, if
+///      there's no such template, if the expanded task exceeds
+///      [MAX_TODO_CHARS], is shorter than [Config::min_todo_chars] or holds
+///      a [Config::blocklist] term, if the canister is at
+///      [Config::max_total_todos], or if the caller is adding faster than
+///      its leaky bucket allows
+/// Panics:
+///      [caller] is the anonymous identity
+///      [caller] is not a registered user
+///      User already has [MAX_TODOS_PER_USER] todos (or its override) and
+///      [UserPrefs::on_full] frees no room
+#[update(name = "add_from_template", guard = "check_arg_size")]
+fn add_from_template(name: String, values: BTreeMap<String, String>) -> Result<u128, String> {
+    logged("add_from_template", || {
+        let user = caller();
+        check_writable()?;
+        log_access("add_from_template", &user);
+
+        let user_str = owner_key(&user);
+        let body = PREFS
+            .with(|prefs_ref| {
+                prefs_ref
+                    .borrow()
+                    .get(&user_str)
+                    .and_then(|p| p.templates.get(&name).cloned())
+            })
+            .ok_or_else(|| "template not found".to_string())?;
+        let task = expand_template(&body, &values);
+        if task.chars().count() > MAX_TODO_CHARS.with(|mtc| *mtc) {
+            return Err("task too long".to_string());
+        }
+        check_min_length(&task)?;
+        check_blocklist(&task)?;
+
+        check_canister_room(&user_str, 1)?;
+        take_add_token(&user_str)?;
+        let todo_id = insert_todo(&user_str, task);
+        reindex_changed(&user_str);
+        Ok(todo_id)
+    })
+}
+
+/// Start a resumable import for this [caller]: stage tasks with
+/// `import_chunk`, then add them all with `commit_import` or drop them
+/// with `abort_import`. Nothing reaches the list before the commit. A
+/// user has at most one open import, and an upgrade drops any open import
+/// along with its staged tasks.
+///
+/// Returns:
+///      Future of the session id, or an error in maintenance mode or if
+///      an import is already open
+/// Panics:
+///      [caller] is the anonymous identity
+#[update(name = "begin_import")]
+fn begin_import() -> Result<u64, String> {
+    logged("begin_import", || {
+        let user = caller();
+        check_writable()?;
+        log_access("begin_import", &user);
+
+        let user_str = owner_key(&user);
+        IMPORT_SESSIONS.with(|sessions_ref| {
+            let mut sessions = sessions_ref.borrow_mut();
+            if sessions.contains_key(&user_str) {
+                return Err("import in progress".to_string());
+            }
+            let session_id = now();
+            sessions.insert(user_str, ImportSession { session_id, staged: vec![] });
+            Ok(session_id)
+        })
+    })
+}
+
+/// Returns an error unless [session_id] is [user_str]'s open import.
+fn check_import_session(user_str: &str, session_id: u64) -> Result<(), String> {
+    IMPORT_SESSIONS.with(|sessions_ref| match sessions_ref.borrow().get(user_str) {
+        Some(session) if session.session_id == session_id => Ok(()),
+        _ => Err("no such import".to_string()),
+    })
+}
+
+/// Stage [tasks] for this [caller]'s import [session_id]. The whole chunk
+/// is checked before any of it is staged. A full chunk may take up to
+/// [MAX_BATCH_ARG_BYTES] encoded.
+///      [session_id]: the id returned by `begin_import`
+///      [tasks]: (encrypted) contents of the todos to add
+///
+/// Returns:
+///      Future of the number of tasks the import staged so far, or an
+///      error in maintenance mode, if [session_id] isn't this [caller]'s
+///      open import, if a task is shorter than [Config::min_todo_chars] or
+///      holds a [Config::blocklist] term, or if the staged tasks would
+///      exceed [Config::max_total_todos]
+/// Panics:
+///      [caller] is the anonymous identity
+///      [tasks] holds more than [MAX_TASKS_PER_IMPORT_CHUNK] tasks
+///      A task exceeds [MAX_TODO_CHARS]
+#[update(name = "import_chunk", guard = "check_batch_arg_size")]
+fn import_chunk(session_id: u64, tasks: Vec<String>) -> Result<u64, String> {
+    logged("import_chunk", || {
+        let user = caller();
+        check_writable()?;
+        log_access("import_chunk", &user);
+        assert!(tasks.len() <= MAX_TASKS_PER_IMPORT_CHUNK.with(|mtpic| *mtpic));
+        let max_todo_chars = MAX_TODO_CHARS.with(|mtc| *mtc);
+        assert!(tasks.iter().all(|task| task.chars().count() <= max_todo_chars));
+        for task in &tasks {
+            check_min_length(task)?;
+            check_blocklist(task)?;
+        }
+
+        let user_str = owner_key(&user);
+        check_import_session(&user_str, session_id)?;
+        IMPORT_SESSIONS.with(|sessions_ref| {
+            let mut sessions = sessions_ref.borrow_mut();
+            let session = sessions.get_mut(&user_str).expect("import session checked above");
+            check_canister_room(&user_str, (session.staged.len() + tasks.len()) as u64)?;
+            session.staged.extend(tasks);
+            Ok(session.staged.len() as u64)
+        })
+    })
+}
+
+/// Close this [caller]'s import [session_id], adding every task it staged
+/// in order. Either all of them are added or none is.
+///      [session_id]: the id returned by `begin_import`
+///
+/// Returns:
+///      Future of the number of todos the import added, or an error in
+///      maintenance mode, if [session_id] isn't this [caller]'s open
+///      import, or if the staged tasks would exceed
+///      [Config::max_total_todos]
+/// Panics:
+///      [caller] is the anonymous identity
+///      The staged tasks would exceed the user's todo limit
+///      The import would be for a new user and [MAX_USERS] is exceeded
+#[update(name = "commit_import")]
+fn commit_import(session_id: u64) -> Result<u64, String> {
+    logged("commit_import", || {
+        let user = caller();
+        check_writable()?;
+        log_access("commit_import", &user);
+
+        let user_str = owner_key(&user);
+        check_import_session(&user_str, session_id)?;
+        let staged = IMPORT_SESSIONS.with(|sessions_ref| {
+            sessions_ref.borrow().get(&user_str).map_or(0, |session| session.staged.len())
+        });
+        check_canister_room(&user_str, staged as u64)?;
+        let session = IMPORT_SESSIONS
+            .with(|sessions_ref| sessions_ref.borrow_mut().remove(&user_str))
+            .expect("import session checked above");
+        for task in session.staged {
+            insert_todo(&user_str, task);
+        }
+        reindex_search(&user_str);
+        Ok(staged as u64)
+    })
+}
+
+/// Close this [caller]'s import [session_id], dropping the tasks it
+/// staged. The list is left as it was.
+///      [session_id]: the id returned by `begin_import`
+///
+/// Returns:
+///      Future of the number of staged tasks dropped, or an error in
+///      maintenance mode or if [session_id] isn't this [caller]'s open
+///      import
+/// Panics:
+///      [caller] is the anonymous identity
+#[update(name = "abort_import")]
+fn abort_import(session_id: u64) -> Result<u64, String> {
+    logged("abort_import", || {
+        let user = caller();
+        check_writable()?;
+        log_access("abort_import", &user);
+
+        let user_str = owner_key(&user);
+        check_import_session(&user_str, session_id)?;
+        let session = IMPORT_SESSIONS
+            .with(|sessions_ref| sessions_ref.borrow_mut().remove(&user_str))
+            .expect("import session checked above");
+        Ok(session.staged.len() as u64)
+    })
+}
+
+/// Returns the day since the epoch that [time] (in nanoseconds) falls on.
+fn day_of(time: u64) -> u64 {
+    time / (24 * 60 * 60 * 1_000_000_000)
+}
+
+/// Counts one completion for [user_str] today, dropping counts older
+/// than [STREAK_HISTORY_DAYS].
+fn record_completion(user_str: &str) {
+    let today = day_of(now());
+    let oldest = today.saturating_sub(STREAK_HISTORY_DAYS.with(|shd| *shd) - 1);
+    COMPLETIONS.with(|completions_ref| {
+        let mut completions = completions_ref.borrow_mut();
+        let days = completions.entry(user_str.to_string()).or_default();
+        *days.entry(today).or_default() += 1;
+        days.retain(|day, _| *day >= oldest);
+    });
+}
+
+/// Returns the number of consecutive [days] with a completion that end
+/// on [today], or on the day before if nothing was completed yet today.
+fn streak_length(days: &BTreeMap<u64, u64>, today: u64) -> u64 {
+    let mut day = if days.contains_key(&today) { today } else { today.saturating_sub(1) };
+    let mut streak = 0;
+    while days.contains_key(&day) {
+        streak += 1;
+        if day == 0 {
+            break;
+        }
+        day -= 1;
+    }
+    streak
+}
+
+/// Returns (a future of) this [caller]'s completion streak and its
+/// completions per day over the last [STREAK_HISTORY_DAYS] days.
+#[query(name = "my_streak")]
+fn my_streak() -> Streak {
+    let user_str = owner_key(&caller());
+    let today = day_of(now());
+    let oldest = today.saturating_sub(STREAK_HISTORY_DAYS.with(|shd| *shd) - 1);
+    COMPLETIONS.with(|completions_ref| {
+        let completions = completions_ref.borrow();
+        let Some(days) = completions.get(&user_str) else {
+            return Streak { current: 0, days: vec![] };
+        };
+        Streak {
+            current: streak_length(days, today),
+            days: days.range(oldest..).map(|(day, count)| (*day, *count)).collect(),
+        }
+    })
+}
+
+/// Returns (a future of) the earliest creation time and the latest
+/// change time over this [caller]'s todos, both none if it has no todos.
+#[query(name = "my_activity_window")]
+fn my_activity_window() -> ActivityWindow {
+    let user_str = owner_key(&caller());
+    TODO_BY_USER.with(|todo_ref| {
+        let todos = todo_ref.borrow();
+        let todos = todos.get(&user_str).map(Vec::as_slice).unwrap_or_default();
+        ActivityWindow {
+            first_created_at: todos.iter().map(|t| t.created_at).min(),
+            last_updated_at: todos.iter().map(|t| t.updated_at).max(),
+        }
+    })
+}
+
+/// Appends the occurrence following the recurring [template] to
+/// [user_str]'s [todos]. The caller checks the user's todo limit.
+fn spawn_next_occurrence(user_str: &str, todos: &mut Vec<Todo>, template: &Todo) {
+    let Some(interval) = template.recurrence else {
+        return;
+    };
+    let id = assign_todo_id(user_str, todos, &template.task.text());
+    todos.push(template.next_occurrence(id, interval));
+    record_created(1);
+    record_change(user_str, ChangeKind::Added, id);
+}
+
+/// Complete this [caller]'s todo with given id. Completing a todo that is
+/// already completed does nothing, so a retried call never spawns a
+/// second occurrence of a recurring todo.
+///      [todo_id]: the id of the todo
+///
+/// Returns:
+///      Future of whether the todo went from open to completed (false if
+///      it was already completed), or an error in maintenance mode, if the
+///      todo doesn't exist, if it's locked, or if its next occurrence
+///      would exceed [Config::max_total_todos]
+/// Panics:
+///      [caller] is the anonymous identity
+///      [caller] is not a registered user
+///      The next occurrence would exceed the user's todo limit
+#[update(name = "complete_todo")]
+fn complete_todo(todo_id: u128) -> Result<bool, String> {
+    logged("complete_todo", || {
+        let user = caller();
+        check_writable()?;
+        log_access("complete_todo", &user);
+
+        let user_str = owner_key(&user);
+        check_unlocked(&user_str, todo_id)?;
+        check_spawn_room(&user_str, todo_id)?;
+        let limit = todo_limit(&user_str);
+        let completed = TODO_BY_USER.with(|todos_ref| {
+            let mut writer = todos_ref.borrow_mut();
+            let todos = writer
+                .get_mut(&user_str)
+                .ok_or_else(|| "todo not found".to_string())?;
+            let todo_count = todos.len();
+            let todo = todos
+                .iter_mut()
+                .find(|t| t.id == todo_id)
+                .ok_or_else(|| "todo not found".to_string())?;
+            if todo.completed {
+                return Ok(false);
+            }
+            if todo.recurrence.is_some() {
+                assert!(todo_count < limit);
+            }
+            todo.completed = true;
+            todo.touch(TodoOp::Completed);
+            record_change(&user_str, ChangeKind::Updated, todo_id);
+            record_completion(&user_str);
+            let template = todo.clone();
+            spawn_next_occurrence(&user_str, todos, &template);
+            Ok::<bool, String>(true)
+        })?;
+        reindex_changed(&user_str);
+        Ok(completed)
+    })
+}
+
+/// Give every one of this [caller]'s todos matching [filter] the
+/// [priority], in one pass. Locked todos are left as they are.
+///      [filter]: which todos to change
+///      [priority]: the new priority
+///
+/// Returns:
+///      Future of the number of todos whose priority changed, or an error
+///      in maintenance mode
+/// Panics:
+///      [caller] is the anonymous identity
+///      [caller] is not a registered user
+#[update(name = "set_priority_where", guard = "check_arg_size")]
+fn set_priority_where(filter: Filter, priority: u8) -> Result<u64, String> {
+    logged("set_priority_where", || {
+        let user = caller();
+        check_writable()?;
+        log_access("set_priority_where", &user);
+
+        let user_str = owner_key(&user);
+        let now = now();
+        let changed: Vec<u128> = TODO_BY_USER.with(|todos_ref| {
+            let mut writer = todos_ref.borrow_mut();
+            writer
+                .get_mut(&user_str)
+                .into_iter()
+                .flatten()
+                .filter(|t| !t.locked && t.priority != priority && filter.matches(t, now))
+                .map(|todo| {
+                    todo.priority = priority;
+                    todo.touch(TodoOp::Updated);
+                    todo.id
+                })
+                .collect()
+        });
+        for todo_id in &changed {
+            record_change(&user_str, ChangeKind::Updated, *todo_id);
+        }
+        Ok(changed.len() as u64)
+    })
+}
+
+/// Rename the tag [from] to [to] on all of this [caller]'s todos. A todo
+/// already carrying [to] just loses [from], so no tag appears twice.
+/// Locked todos are left as they are.
+///      [from]: the tag to rename
+///      [to]: the new name of the tag
+///
+/// Returns:
+///      Future of the number of todos changed, or an error in maintenance
+///      mode
+/// Panics:
+///      [caller] is the anonymous identity
+///      [caller] is not a registered user
+///      [to] exceeds [Config::max_tag_chars]
+///      A changed todo has more than [Config::max_tags_per_todo] tags
+#[update(name = "rename_tag", guard = "check_arg_size")]
+fn rename_tag(from: String, to: String) -> Result<u64, String> {
+    logged("rename_tag", || {
+        let user = caller();
+        check_writable()?;
+        log_access("rename_tag", &user);
+        let (max_tags, max_tag_chars) = CONFIG.with(|config_ref| {
+            let config = config_ref.borrow();
+            (config.max_tags_per_todo, config.max_tag_chars)
+        });
+        assert!(to.chars().count() <= max_tag_chars);
+        if from == to {
+            return Ok(0);
+        }
+
+        let user_str = owner_key(&user);
+        let changed: Vec<u128> = TODO_BY_USER.with(|todos_ref| {
+            let mut writer = todos_ref.borrow_mut();
+            let mut changed = vec![];
+            for todo in writer.get_mut(&user_str).into_iter().flatten() {
+                let Some(pos) = todo.tags.iter().position(|t| *t == from) else {
+                    continue;
+                };
+                if todo.locked {
+                    continue;
+                }
+                if todo.tags.contains(&to) {
+                    todo.tags.remove(pos);
+                } else {
+                    todo.tags[pos] = to.clone();
+                }
+                assert!(todo.tags.len() <= max_tags);
+                todo.touch(TodoOp::Updated);
+                changed.push(todo.id);
+            }
+            changed
+        });
+        for todo_id in &changed {
+            record_change(&user_str, ChangeKind::Updated, *todo_id);
+        }
+        Ok(changed.len() as u64)
+    })
+}
+
+/// Checks a todo.txt [file] the way `import_todotxt` would, without
+/// storing anything. Lines `import_todotxt` would skip are invalid.
+///      [file]: the raw todo.txt content
+///
+/// Returns:
+///      Future of the valid and invalid line counts and whether the valid
+///      ones would overflow this [caller]'s todo limit or
+///      [Config::max_total_todos]
+#[query(name = "preview_import_todotxt", guard = "check_batch_arg_size")]
+fn preview_import_todotxt(file: String) -> ImportPreview {
+    let lines = parse_todotxt_file(&file);
+    let valid = lines.iter().flatten().count() as u64;
+    let user_str = owner_key(&caller());
+    let would_exceed_quota =
+        valid > todo_room(&user_str) as u64 || check_canister_room(&user_str, valid).is_err();
+    ImportPreview {
+        valid,
+        invalid: lines.len() as u64 - valid,
+        would_exceed_quota,
+    }
+}
+
+/// Returns the names of the canister's methods, in the order of the
+/// service in the `.did` file, which is compiled in so the list can't
+/// drift from the interface.
+#[query(name = "list_methods")]
+fn list_methods() -> Vec<String> {
+    let did = include_str!("../icp_todo_api_backend.did");
+    did.lines()
+        .skip_while(|line| !line.starts_with("service"))
+        .skip(1)
+        .take_while(|line| !line.starts_with('}'))
+        .filter_map(|line| line.trim().split_once(':'))
+        .map(|(name, _)| name.to_string())
+        .collect()
+}
+
+/// Returns (a future of) whether this [caller] owns a todo with given id,
+/// without sending the todo itself.
+///      [todo_id]: the id to look for
+///
+/// Panics:
+///      [caller] is the anonymous identity
+#[query(name = "todo_exists")]
+fn todo_exists(todo_id: u128) -> bool {
+    assert_not_anonymous();
+    let user_str = owner_key(&caller());
+    TODO_BY_USER.with(|todo_ref| {
+        todo_ref
+            .borrow()
+            .get(&user_str)
+            .is_some_and(|todos| todos.iter().any(|t| t.id == todo_id))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const START: u64 = 1_700_000_000_000_000_000;
+
+    fn user(n: u8) -> Principal {
+        Principal::from_slice(&[n; 10])
+    }
+
+    /// Makes [user(n)] the caller and returns it.
+    fn act_as(n: u8) -> Principal {
+        set_caller(user(n));
+        user(n)
+    }
+
+    /// Starts the clock at [START], makes [user(0)] the admin and
+    /// [user(1)] the caller.
+    fn setup() {
+        set_now(START);
+        ADMIN.with(|admin_ref| *admin_ref.borrow_mut() = Some(user(0)));
+        act_as(1);
+    }
+
+    fn config(change: impl FnOnce(&mut Config)) {
+        CONFIG.with(|config_ref| change(&mut config_ref.borrow_mut()));
+    }
+
+    /// Adds [task] for the caller and returns the new todo's id.
+    fn add(task: &str) -> u128 {
+        add_todo(task.to_string()).unwrap();
+        stored().last().unwrap().id
+    }
+
+    /// The caller's todos as stored, in list order.
+    fn stored() -> Vec<Todo> {
+        let user_str = owner_key(&caller());
+        TODO_BY_USER.with(|todos_ref| todos_ref.borrow().get(&user_str).cloned().unwrap_or_default())
+    }
+
+    fn todo(todo_id: u128) -> Todo {
+        stored().into_iter().find(|t| t.id == todo_id).unwrap()
+    }
+
+    fn tasks(todos: &[Todo]) -> Vec<String> {
+        todos.iter().map(|t| t.task.text().into_owned()).collect()
+    }
+
+    fn ids(todos: &[Todo]) -> Vec<u128> {
+        todos.iter().map(|t| t.id).collect()
+    }
+
+    #[test]
+    fn add_tag_adds_once() {
+        setup();
+        let todo_id = add("water plants");
+        add_tag(todo_id, "home".to_string()).unwrap();
+        add_tag(todo_id, "home".to_string()).unwrap();
+        add_tag(todo_id, "daily".to_string()).unwrap();
+        assert_eq!(todo(todo_id).tags, vec!["home", "daily"]);
+    }
+
+    #[test]
+    fn remove_tag_ignores_missing_tags() {
+        setup();
+        let todo_id = add("water plants");
+        add_tag(todo_id, "home".to_string()).unwrap();
+        add_tag(todo_id, "daily".to_string()).unwrap();
+        remove_tag(todo_id, "home".to_string()).unwrap();
+        assert_eq!(todo(todo_id).tags, vec!["daily"]);
+        remove_tag(todo_id, "home".to_string()).unwrap();
+        assert_eq!(todo(todo_id).tags, vec!["daily"]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn add_tag_enforces_tag_cap() {
+        setup();
+        config(|c| c.max_tags_per_todo = 2);
+        let todo_id = add("water plants");
+        add_tag(todo_id, "a".to_string()).unwrap();
+        add_tag(todo_id, "b".to_string()).unwrap();
+        add_tag(todo_id, "c".to_string()).unwrap();
+    }
+
+    #[test]
+    fn read_only_blocks_writes_but_not_reads() {
+        setup();
+        let todo_id = add("water plants");
+        act_as(0);
+        set_read_only(true);
+        act_as(1);
+        assert_eq!(add_todo("feed cat".to_string()), Err("maintenance mode".to_string()));
+        assert_eq!(delete_todo(todo_id), Err("maintenance mode".to_string()));
+        assert_eq!(add_tag(todo_id, "home".to_string()), Err("maintenance mode".to_string()));
+        assert_eq!(get_todos().len(), 1);
+        assert!(health().read_only);
+
+        act_as(0);
+        set_read_only(false);
+        act_as(1);
+        add("feed cat");
+        delete_todo(todo_id).unwrap();
+        assert_eq!(tasks(&get_todos()), vec!["feed cat"]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn set_read_only_is_admin_only() {
+        setup();
+        set_read_only(true);
+    }
+
+    /// Adds [n] todos numbered from 0 for the caller.
+    fn fill(n: usize) {
+        for i in 0..n {
+            add_todo(format!("task {}", i)).unwrap();
+        }
+    }
+
+    #[test]
+    fn user_limit_override_lifts_the_default_cap() {
+        setup();
+        act_as(0);
+        set_user_limit(user(1), 501);
+        act_as(1);
+        fill(501);
+        assert_eq!(stored().len(), 501);
+    }
+
+    #[test]
+    #[should_panic]
+    fn users_without_override_keep_the_default_cap() {
+        setup();
+        act_as(0);
+        set_user_limit(user(1), 501);
+        act_as(2);
+        fill(501);
+    }
+
+    #[test]
+    fn admin_find_owner_traces_ids() {
+        setup();
+        add("water plants");
+        act_as(2);
+        let todo_id = add("feed cat");
+        act_as(0);
+        assert_eq!(admin_find_owner(todo_id), Some(user(2).to_text()));
+        assert_eq!(admin_find_owner(999), None);
+    }
+
+    #[test]
+    #[should_panic]
+    fn admin_find_owner_is_admin_only() {
+        setup();
+        let todo_id = add("water plants");
+        admin_find_owner(todo_id);
+    }
+
+    #[test]
+    fn first_add_is_preceded_by_the_welcome_todo() {
+        setup();
+        config(|c| c.seed_first_todo = Some("Welcome!".to_string()));
+        add("water plants");
+        add("feed cat");
+        assert_eq!(tasks(&stored()), vec!["Welcome!", "water plants", "feed cat"]);
+
+        act_as(2);
+        add("call mum");
+        assert_eq!(tasks(&stored()), vec!["Welcome!", "call mum"]);
+    }
+
+    #[test]
+    fn no_welcome_todo_unless_configured() {
+        setup();
+        add("water plants");
+        assert_eq!(tasks(&stored()), vec!["water plants"]);
+    }
+
+    fn patch(id: u128) -> TodoPatch {
+        TodoPatch {
+            id,
+            task: None,
+            priority: None,
+            due_at: None,
+            completed: None,
+        }
+    }
+
+    #[test]
+    fn patch_todo_applies_every_present_field() {
+        setup();
+        let todo_id = add("water plants");
+        patch_todo(TodoPatch {
+            task: Some("water the plants".to_string()),
+            priority: Some(3),
+            due_at: Some(Some(START + 10)),
+            ..patch(todo_id)
+        })
+        .unwrap();
+        let patched = todo(todo_id);
+        assert_eq!(patched.task.text(), "water the plants");
+        assert_eq!(patched.priority, 3);
+        assert_eq!(patched.due_at, Some(START + 10));
+        assert!(!patched.completed);
+
+        patch_todo(TodoPatch { due_at: Some(None), completed: Some(true), ..patch(todo_id) }).unwrap();
+        let patched = todo(todo_id);
+        assert_eq!(patched.priority, 3);
+        assert_eq!(patched.due_at, None);
+        assert!(patched.completed);
+    }
+
+    #[test]
+    fn patch_todo_with_an_invalid_field_changes_nothing() {
+        setup();
+        config(|c| c.min_todo_chars = 3);
+        let todo_id = add("water plants");
+        let result = patch_todo(TodoPatch {
+            task: Some("ab".to_string()),
+            priority: Some(3),
+            completed: Some(true),
+            ..patch(todo_id)
+        });
+        assert_eq!(result, Err("task too short".to_string()));
+        let unchanged = todo(todo_id);
+        assert_eq!(unchanged.task.text(), "water plants");
+        assert_eq!(unchanged.priority, 0);
+        assert!(!unchanged.completed);
+    }
+
+    #[test]
+    fn patch_todo_reports_missing_ids() {
+        setup();
+        add("water plants");
+        act_as(2);
+        let theirs = add("feed cat");
+        act_as(1);
+        let not_found = Err("todo not found".to_string());
+        assert_eq!(patch_todo(TodoPatch { priority: Some(3), ..patch(400) }), not_found);
+        assert_eq!(patch_todo(TodoPatch { priority: Some(3), ..patch(theirs) }), not_found);
+        act_as(2);
+        assert_eq!(todo(theirs).priority, 0);
+    }
+
+    #[test]
+    fn hashed_ids_are_stable_across_identical_imports() {
+        setup();
+        config(|c| c.id_strategy = IdStrategy::Hashed);
+        let first: Vec<u128> = ["a", "b", "a"].iter().map(|task| add(task)).collect();
+        TODO_BY_USER.with(|todos_ref| todos_ref.borrow_mut().clear());
+        let second: Vec<u128> = ["a", "b", "a"].iter().map(|task| add(task)).collect();
+        assert_eq!(first, second);
+        assert_ne!(first[0], first[2]);
+    }
+
+    #[test]
+    fn hashed_id_collisions_are_rehashed_with_a_salt() {
+        setup();
+        config(|c| c.id_strategy = IdStrategy::Hashed);
+        let user_str = principal_key(&user(1));
+        let existing = vec![Todo::new(hashed_todo_id(&user_str, 1, "b", 0), "a".to_string())];
+        assert_eq!(assign_todo_id(&user_str, &existing, "b"), hashed_todo_id(&user_str, 1, "b", 1));
+    }
+
+    fn rate() -> (u64, u64) {
+        let rate = completion_rate();
+        (rate.completed, rate.total)
+    }
+
+    #[test]
+    fn completion_rate_counts_completed_out_of_total() {
+        setup();
+        assert_eq!(rate(), (0, 0));
+        let first = add("water plants");
+        let second = add("feed cat");
+        complete_todo(first).unwrap();
+        assert_eq!(rate(), (1, 2));
+        complete_todo(second).unwrap();
+        assert_eq!(rate(), (2, 2));
+    }
+
+    #[test]
+    fn completion_rate_counts_the_last_seven_days() {
+        const DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+        setup();
+        let day0 = START - START % DAY;
+        set_now(day0);
+        let first = add("water plants");
+        complete_todo(first).unwrap();
+        set_now(day0 + 3 * DAY);
+        let second = add("feed cat");
+        complete_todo(second).unwrap();
+        let week = || completion_rate().completed_last_7_days;
+        assert_eq!(week(), 2);
+        set_now(day0 + 7 * DAY - 1);
+        assert_eq!(week(), 2);
+        set_now(day0 + 7 * DAY);
+        assert_eq!(week(), 1);
+        delete_todo(second).unwrap();
+        assert_eq!(week(), 1);
+        set_now(day0 + 10 * DAY);
+        assert_eq!(week(), 0);
+        assert_eq!(rate(), (1, 1));
+    }
+
+    #[test]
+    fn duplicate_principal_keys_are_merged() {
+        setup();
+        let canonical = principal_key(&user(1));
+        let odd = canonical.to_uppercase();
+        assert_ne!(odd, canonical);
+        assert_eq!(normalize_key(&odd), canonical);
+        let first = add("water plants");
+        pin_todo(first).unwrap();
+        TODO_BY_USER.with(|todos_ref| {
+            let todo = Todo::new(900, "feed cat".to_string());
+            todos_ref.borrow_mut().insert(odd.clone(), vec![todo]);
+        });
+        PINS.with(|pins_ref| pins_ref.borrow_mut().insert(odd.clone(), vec![900, first]));
+        USER_LIMITS.with(|limits_ref| limits_ref.borrow_mut().insert(odd.clone(), 600));
+        PREFS.with(|prefs_ref| prefs_ref.borrow_mut().insert(odd.clone(), UserPrefs::default()));
+        SHARES.with(|shares_ref| {
+            let mut shares = shares_ref.borrow_mut();
+            shares.insert(odd.clone(), vec![principal_key(&user(2))]);
+            shares.insert(principal_key(&user(3)), vec![odd.clone(), canonical.clone()]);
+        });
+        COMPLETIONS.with(|completions_ref| {
+            completions_ref.borrow_mut().insert(odd.clone(), BTreeMap::from([(3, 2)]));
+        });
+        let version = todos_version();
+
+        merge_duplicate_keys();
+        let keys: Vec<PrincipalName> = TODO_BY_USER.with(|todos_ref| todos_ref.borrow().keys().cloned().collect());
+        assert_eq!(keys, vec![canonical.clone()]);
+        assert_eq!(tasks(&stored()), vec!["feed cat", "water plants"]);
+        assert_eq!(PINS.with(|pins_ref| pins_ref.borrow()[&canonical].clone()), vec![900, first]);
+        assert_eq!(todo_limit(&canonical), 600);
+        assert!(PREFS.with(|prefs_ref| !prefs_ref.borrow().contains_key(&odd)));
+        SHARES.with(|shares_ref| {
+            let shares = shares_ref.borrow();
+            assert_eq!(shares[&canonical], vec![principal_key(&user(2))]);
+            assert_eq!(shares[&principal_key(&user(3))], vec![canonical.clone()]);
+        });
+        assert_eq!(COMPLETIONS.with(|completions_ref| completions_ref.borrow()[&canonical][&3]), 2);
+        assert!(CHANGES.with(|changes_ref| !changes_ref.borrow().contains_key(&odd)));
+        assert!(todos_version() > version);
+    }
+
+    #[test]
+    fn admin_reassign_id_moves_a_todo_to_a_free_id() {
+        setup();
+        let todo_id = add("water plants");
+        act_as(0);
+        admin_reassign_id(user(1), todo_id, 40).unwrap();
+        assert_eq!(NEXT_TODO.with(|counter_ref| *counter_ref.borrow()), 40);
+        act_as(1);
+        assert_eq!(todo(40).task.text(), "water plants");
+        assert!(!todo_exists(todo_id));
+    }
+
+    #[test]
+    fn admin_reassign_id_rejects_a_used_id() {
+        setup();
+        let first = add("water plants");
+        let second = add("feed cat");
+        act_as(0);
+        assert_eq!(admin_reassign_id(user(1), first, second), Err("id already in use".to_string()));
+        assert_eq!(admin_reassign_id(user(1), 999, 40), Err("todo not found".to_string()));
+        act_as(1);
+        assert_eq!(todo(first).task.text(), "water plants");
+    }
+
+    #[test]
+    fn a_linked_alias_reads_the_primary_list() {
+        setup();
+        add("water plants");
+        link_principal(user(2)).unwrap();
+        act_as(2);
+        assert!(get_todos().is_empty());
+        accept_link(user(1)).unwrap();
+        assert_eq!(tasks(&get_todos()), vec!["water plants"]);
+        add("feed cat");
+        act_as(1);
+        assert_eq!(tasks(&get_todos()), vec!["water plants", "feed cat"]);
+    }
+
+    #[test]
+    fn links_need_the_alias_to_accept_the_offer() {
+        setup();
+        add("water plants");
+        act_as(2);
+        assert_eq!(accept_link(user(1)), Err("no pending link".to_string()));
+    }
+
+    #[test]
+    fn link_cycles_and_chains_are_rejected() {
+        setup();
+        add("water plants");
+        link_principal(user(2)).unwrap();
+        act_as(2);
+        accept_link(user(1)).unwrap();
+        act_as(3);
+        add("feed cat");
+        // Linking either end of 2 -> 1 under 3 would make a chain.
+        assert_eq!(link_principal(user(1)), Err("principal is already linked".to_string()));
+        assert_eq!(link_principal(user(2)), Err("principal is already linked".to_string()));
+        assert_eq!(link_principal(user(3)), Err("cannot link a principal to itself".to_string()));
+    }
+
+    #[test]
+    fn cancel_recurrence_keeps_the_todo_and_spawned_occurrences() {
+        setup();
+        let todo_id = add("water plants");
+        add("feed cat");
+        set_recurrence(todo_id, 10).unwrap();
+        assert_eq!(ids(&get_recurring_todos()), vec![todo_id]);
+        complete_todo(todo_id).unwrap();
+        let spawned = stored().last().unwrap().id;
+        assert_eq!(ids(&get_recurring_todos()), vec![todo_id, spawned]);
+
+        cancel_recurrence(todo_id).unwrap();
+        assert!(todo(todo_id).recurrence.is_none());
+        assert_eq!(ids(&get_recurring_todos()), vec![spawned]);
+        assert_eq!(todo(spawned).recurrence, Some(10));
+    }
+
+    #[test]
+    fn long_task_texts_are_stored_deflated() {
+        let long = "water the plants on the balcony ".repeat(20);
+        let text = TaskText::from(long.clone());
+        assert!(text.compressed);
+        assert!(text.bytes.len() < long.len());
+        assert_eq!(text.text(), long);
+    }
+
+    #[test]
+    fn short_task_texts_stay_plain() {
+        let text = TaskText::from("water plants".to_string());
+        assert!(!text.compressed);
+        assert_eq!(text.bytes, b"water plants");
+        assert_eq!(text.text(), "water plants");
+    }
+
+    #[test]
+    fn compressed_task_texts_go_over_the_wire_as_plain_text() {
+        let long = "feed the cat ".repeat(40);
+        let bytes = candid::encode_one(TaskText::from(long.clone())).unwrap();
+        assert_eq!(candid::decode_one::<String>(&bytes).unwrap(), long);
+    }
+
+    #[test]
+    fn get_todo_ids_lists_get_all_todos() {
+        setup();
+        let first = add("water plants");
+        add("feed cat");
+        let third = add("call mum");
+        complete_todo(first).unwrap();
+        archive_completed().unwrap();
+        reorder_todos(vec![third]).unwrap();
+        assert_eq!(get_todo_ids(), ids(&get_all_todos()));
+        assert_eq!(get_todo_ids().len(), 2);
+    }
+
+    #[test]
+    fn admin_delete_user_clears_every_per_user_structure() {
+        setup();
+        config(|c| c.add_bucket_capacity = 10);
+        let todo_id = add("water plants");
+        add("feed cat");
+        update_todo(TodoUpdate { id: todo_id, task: "water the plants".to_string() }).unwrap();
+        pin_todo(todo_id).unwrap();
+        share_todos(user(2)).unwrap();
+        set_prefs(UserPrefs { public: true, ..UserPrefs::default() }).unwrap();
+        complete_todo(todo_id).unwrap();
+        delete_todo(todo_id).unwrap();
+        let session_id = begin_import().unwrap();
+        import_chunk(session_id, vec!["pay rent".to_string()]).unwrap();
+        act_as(2);
+        accept_transfer(user(1)).unwrap();
+
+        act_as(0);
+        set_user_limit(user(1), 10);
+        assert_eq!(admin_delete_user(user(1)), 1);
+        let key = principal_key(&user(1));
+        assert!(TODO_BY_USER.with(|r| !r.borrow().contains_key(&key)));
+        assert!(USER_LIMITS.with(|r| !r.borrow().contains_key(&key)));
+        assert!(PREFS.with(|r| !r.borrow().contains_key(&key)));
+        assert!(CHANGES.with(|r| !r.borrow().contains_key(&key)));
+        assert!(TOMBSTONES.with(|r| !r.borrow().contains_key(&key)));
+        assert!(TASK_HISTORY.with(|r| !r.borrow().contains_key(&key)));
+        assert!(PINS.with(|r| !r.borrow().contains_key(&key)));
+        assert!(COMPLETIONS.with(|r| !r.borrow().contains_key(&key)));
+        assert!(IMPORT_SESSIONS.with(|r| !r.borrow().contains_key(&key)));
+        assert!(ADD_BUCKETS.with(|r| !r.borrow().contains_key(&key)));
+        assert!(SHARES.with(|r| r.borrow().is_empty()));
+        assert!(SHARED_WITH.with(|r| r.borrow().is_empty()));
+        assert!(ACCEPTED_TRANSFERS.with(|r| r.borrow().is_empty()));
+        assert_eq!(user_count(), 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn admin_delete_user_is_admin_only() {
+        setup();
+        add("water plants");
+        admin_delete_user(user(1));
+    }
+
+    #[test]
+    fn tasks_shorter_than_the_minimum_are_rejected() {
+        setup();
+        config(|c| c.min_todo_chars = 3);
+        assert_eq!(add_todo("ab".to_string()), Err("task too short".to_string()));
+        assert_eq!(add_todo("  ab  ".to_string()), Err("task too short".to_string()));
+        let todo_id = add("abc");
+        assert_eq!(update_todo(TodoUpdate { id: todo_id, task: "ab".to_string() }), Err("task too short".to_string()));
+        update_todo(TodoUpdate { id: todo_id, task: "xyz".to_string() }).unwrap();
+        assert_eq!(tasks(&stored()), vec!["xyz"]);
+    }
+
+    #[test]
+    fn admin_restore_brings_back_a_snapshot() {
+        setup();
+        add("water plants");
+        add("feed cat");
+        act_as(0);
+        let snapshot = admin_export_all();
+        act_as(1);
+        add("call mum");
+        act_as(2);
+        add("pay rent");
+
+        act_as(0);
+        assert_eq!(
+            admin_restore(snapshot.clone()).err(),
+            Some("restore requires maintenance mode".to_string())
+        );
+        set_read_only(true);
+        let counts = admin_restore(snapshot).unwrap();
+        assert_eq!((counts.users, counts.todos), (1, 2));
+        assert!(get_config().read_only);
+        act_as(1);
+        assert_eq!(tasks(&get_todos()), vec!["water plants", "feed cat"]);
+        assert_eq!(count_todos(), 2);
+        act_as(2);
+        assert!(get_todos().is_empty());
+    }
+
+    #[test]
+    fn get_todos_v2_hides_completed_unless_asked() {
+        setup();
+        let first = add("water plants");
+        add("feed cat");
+        complete_todo(first).unwrap();
+        assert_eq!(tasks(&get_todos_v2(false)), vec!["feed cat"]);
+        assert_eq!(tasks(&get_todos_v2(true)), vec!["water plants", "feed cat"]);
+    }
+
+    #[test]
+    fn lifetime_counters_track_adds_and_deletes() {
+        setup();
+        let first = add("water plants");
+        let second = add("feed cat");
+        add("call mum");
+        delete_todo(first).unwrap();
+        delete_todo(second).unwrap();
+        add("pay rent");
+        act_as(0);
+        let metrics = metrics();
+        assert_eq!((metrics.users, metrics.todos), (1, 2));
+        assert_eq!((metrics.lifetime_created, metrics.lifetime_deleted), (4, 2));
+    }
+
+    #[test]
+    fn access_log_stores_salted_principal_hashes() {
+        setup();
+        config(|c| c.access_log = true);
+        LOG_SALT.with(|salt_ref| *salt_ref.borrow_mut() = vec![7; 32]);
+        add("water plants");
+        act_as(0);
+        let log = admin_access_log();
+        assert_eq!(log.len(), 1);
+        assert_eq!(log[0].method, "add_todo");
+        assert_eq!(log[0].at, START);
+        assert_eq!(Some(log[0].principal_hash), principal_hash(&user(1)));
+        let unsalted = Sha256::digest(user(1).as_slice());
+        assert_ne!(log[0].principal_hash.to_be_bytes(), unsalted[..8]);
+        assert!(admin_export_all().log_salt.is_none());
+    }
+
+    #[test]
+    fn access_log_waits_for_a_salt() {
+        setup();
+        config(|c| c.access_log = true);
+        add("water plants");
+        assert!(principal_hash(&user(1)).is_none());
+        assert!(ACCESS_LOG.with(|log_ref| log_ref.borrow().is_empty()));
+    }
+
+    #[test]
+    fn access_log_keeps_only_the_latest_entries() {
+        setup();
+        config(|c| c.access_log = true);
+        LOG_SALT.with(|salt_ref| *salt_ref.borrow_mut() = vec![7; 32]);
+        let max_log = MAX_ACCESS_LOG.with(|mal| *mal);
+        for n in 0..=max_log {
+            set_now(START + n as u64);
+            log_access("add_todo", &user(1));
+        }
+        let log = ACCESS_LOG.with(|log_ref| log_ref.borrow().clone());
+        assert_eq!(log.len(), max_log);
+        assert_eq!(log[0].at, START + 1);
+    }
+
+    #[test]
+    fn merge_todos_joins_texts_and_tags() {
+        setup();
+        let keep = add("water plants");
+        let merge = add("and the herbs");
+        add_tag(keep, "home".to_string()).unwrap();
+        add_tag(merge, "home".to_string()).unwrap();
+        add_tag(merge, "garden".to_string()).unwrap();
+        let survivor = merge_todos(keep, merge).unwrap();
+        assert_eq!(survivor.id, keep);
+        assert_eq!(survivor.task.text(), "water plants\nand the herbs");
+        assert_eq!(survivor.tags, vec!["home", "garden"]);
+        assert_eq!(ids(&stored()), vec![keep]);
+        assert_eq!(merge_todos(keep, keep).err(), Some("cannot merge a todo into itself".to_string()));
+    }
+
+    #[test]
+    #[should_panic]
+    fn merge_todos_rejects_texts_over_the_limit() {
+        setup();
+        let keep = add(&"a".repeat(600));
+        let merge = add(&"b".repeat(600));
+        let _ = merge_todos(keep, merge);
+    }
+
+    fn todo_with(id: u128, priority: u8, created_at: u64, due_at: Option<u64>) -> Todo {
+        Todo {
+            priority,
+            created_at,
+            due_at,
+            ..Todo::new(id, format!("task {}", id))
+        }
+    }
+
+    #[test]
+    fn next_task_prefers_priority_then_age() {
+        set_now(START);
+        let todos = vec![
+            todo_with(1, 1, START - 30, None),
+            todo_with(2, 3, START - 10, None),
+            todo_with(3, 3, START - 20, None),
+        ];
+        assert_eq!(pick_next_task(&todos, START).map(|t| t.id), Some(3));
+    }
+
+    #[test]
+    fn next_task_skips_completed_and_prefers_todos_not_overdue() {
+        set_now(START);
+        let mut done = todo_with(1, 9, START - 30, None);
+        done.completed = true;
+        let todos = vec![done, todo_with(2, 5, START - 20, Some(START - 1)), todo_with(3, 1, START - 10, None)];
+        assert_eq!(pick_next_task(&todos, START).map(|t| t.id), Some(3));
+        assert_eq!(pick_next_task(&todos[..2], START).map(|t| t.id), Some(2));
+        assert!(pick_next_task(&todos[..1], START).is_none());
+    }
+
+    #[test]
+    fn next_task_is_none_without_todos() {
+        setup();
+        assert!(next_task().is_none());
+        add("water plants");
+        assert_eq!(next_task().map(|t| t.task.text().into_owned()), Some("water plants".to_string()));
+    }
+
+    #[test]
+    fn oversized_raw_arguments_are_rejected() {
+        let max_bytes = MAX_ARG_BYTES.with(|mab| *mab);
+        let task = "x".repeat(MAX_TODO_CHARS.with(|mtc| *mtc) * 4);
+        let largest = candid::encode_one(task).unwrap();
+        assert!(check_arg_bytes(largest.len(), max_bytes).is_ok());
+        assert!(check_arg_bytes(max_bytes, max_bytes).is_ok());
+        assert_eq!(check_arg_bytes(max_bytes + 1, max_bytes), Err("argument too large".to_string()));
+    }
+
+    #[test]
+    fn snooze_pushes_an_existing_due_date_back() {
+        setup();
+        let todo_id = add("water plants");
+        patch_todo(TodoPatch { due_at: Some(Some(START + 5)), ..patch(todo_id) }).unwrap();
+        assert_eq!(snooze_todo(todo_id, 60), Ok(START + 5 + 60_000_000_000));
+        assert_eq!(todo(todo_id).due_at, Some(START + 5 + 60_000_000_000));
+    }
+
+    #[test]
+    fn snooze_schedules_an_undated_todo_from_now() {
+        setup();
+        let todo_id = add("water plants");
+        set_now(START + 100);
+        assert_eq!(snooze_todo(todo_id, 60), Ok(START + 100 + 60_000_000_000));
+        assert_eq!(snooze_todo(400, 60), Err("todo not found".to_string()));
+    }
+
+    #[test]
+    fn get_grouped_partitions_the_list() {
+        setup();
+        let done = add("water plants");
+        let late = add("feed cat");
+        add("call mum");
+        complete_todo(done).unwrap();
+        patch_todo(TodoPatch { due_at: Some(Some(START + 5)), ..patch(late) }).unwrap();
+        set_now(START + 10);
+        let grouped = get_grouped();
+        assert_eq!(tasks(&grouped.completed), vec!["water plants"]);
+        assert_eq!(tasks(&grouped.overdue), vec!["feed cat"]);
+        assert_eq!(tasks(&grouped.active), vec!["call mum"]);
+    }
+
+    #[test]
+    fn writes_need_no_cycles_by_default() {
+        setup();
+        assert_eq!(get_config().write_cycles, 0);
+        assert!(check_writable().is_ok());
+        add("water plants");
+    }
+
+    #[test]
+    fn public_todos_are_readable_only_while_public() {
+        setup();
+        add("water plants");
+        act_as(2);
+        assert!(get_public_todos(user(1)).is_empty());
+        act_as(1);
+        set_prefs(UserPrefs { public: true, ..UserPrefs::default() }).unwrap();
+        act_as(2);
+        assert_eq!(tasks(&get_public_todos(user(1))), vec!["water plants"]);
+        act_as(1);
+        set_prefs(UserPrefs::default()).unwrap();
+        act_as(2);
+        assert!(get_public_todos(user(1)).is_empty());
+    }
+
+    fn naive_search(needle: &str) -> Vec<u128> {
+        let needle = needle.to_lowercase();
+        ids(&stored().into_iter().filter(|t| t.task.text().to_lowercase().contains(&needle)).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn cached_search_matches_the_naive_search() {
+        setup();
+        let first = add("Water Plants");
+        let second = add("feed the CAT");
+        add("water the lawn");
+        update_todo(TodoUpdate { id: second, task: "feed the plants".to_string() }).unwrap();
+        delete_todo(first).unwrap();
+        add("PLANT tomatoes");
+        let merged = add("and cats");
+        merge_todos(second, merged).unwrap();
+        for needle in ["plant", "WATER", "cat", "the", "nothing"] {
+            assert_eq!(ids(&search_todos(needle.to_string(), false)), naive_search(needle));
+        }
+    }
+
+    fn kinds(changes: &ChangesSince) -> Vec<(u64, ChangeKind, u128)> {
+        changes.changes.iter().map(|e| (e.seq, e.kind, e.todo_id)).collect()
+    }
+
+    #[test]
+    fn changes_since_lists_writes_in_order() {
+        setup();
+        let first = add("water plants");
+        update_todo(TodoUpdate { id: first, task: "water the plants".to_string() }).unwrap();
+        let page = changes_since(0);
+        assert_eq!(kinds(&page), vec![(1, ChangeKind::Added, first), (2, ChangeKind::Updated, first)]);
+        assert_eq!(page.latest_seq, 2);
+        assert!(!page.truncated);
+
+        delete_todo(first).unwrap();
+        let page = changes_since(page.latest_seq);
+        assert_eq!(kinds(&page), vec![(3, ChangeKind::Deleted, first)]);
+        assert!(changes_since(page.latest_seq).changes.is_empty());
+    }
+
+    #[test]
+    fn changes_since_flags_a_trimmed_feed() {
+        setup();
+        let todo_id = add("water plants");
+        let max_changes = MAX_CHANGES_PER_USER.with(|mcpu| *mcpu) as u64;
+        for _ in 0..max_changes {
+            add_tag(todo_id, "home".to_string()).unwrap();
+            remove_tag(todo_id, "home".to_string()).unwrap();
+        }
+        let page = changes_since(0);
+        assert!(page.truncated);
+        assert_eq!(page.changes.len() as u64, max_changes);
+        assert!(!changes_since(page.latest_seq - 10).truncated);
+        let ahead = changes_since(u64::MAX);
+        assert!(!ahead.truncated && ahead.changes.is_empty());
+    }
+
+    #[test]
+    fn a_rejected_add_burns_no_id() {
+        setup();
+        act_as(0);
+        set_user_limit(user(1), 1);
+        act_as(1);
+        let first = add("water plants");
+        let rejected = std::panic::catch_unwind(|| add_todo("feed cat".to_string()));
+        assert!(rejected.is_err());
+        act_as(2);
+        assert_eq!(add("call mum"), first + 1);
+    }
+
+    #[test]
+    fn attachments_add_once_and_remove() {
+        setup();
+        let todo_id = add("file taxes");
+        add_attachment(todo_id, "sha256:abc".to_string()).unwrap();
+        add_attachment(todo_id, "sha256:abc".to_string()).unwrap();
+        add_attachment(todo_id, "https://assets.ic0.app/w2".to_string()).unwrap();
+        assert_eq!(todo(todo_id).attachments, vec!["sha256:abc", "https://assets.ic0.app/w2"]);
+        remove_attachment(todo_id, "sha256:abc".to_string()).unwrap();
+        remove_attachment(todo_id, "sha256:missing".to_string()).unwrap();
+        assert_eq!(todo(todo_id).attachments, vec!["https://assets.ic0.app/w2"]);
+        assert!(add_attachment(todo_id, " ".to_string()).is_err());
+    }
+
+    #[test]
+    #[should_panic]
+    fn attachments_are_capped() {
+        setup();
+        let todo_id = add("file taxes");
+        for n in 0..=MAX_ATTACHMENTS_PER_TODO.with(|mapt| *mapt) {
+            add_attachment(todo_id, format!("sha256:{n}")).unwrap();
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn overlong_attachment_references_are_rejected() {
+        setup();
+        let todo_id = add("file taxes");
+        let reference = "x".repeat(MAX_ATTACHMENT_CHARS.with(|mac| *mac) + 1);
+        add_attachment(todo_id, reference).unwrap();
+    }
+
+    #[test]
+    fn get_todos_after_pages_without_gaps_or_repeats() {
+        setup();
+        fill(4);
+        act_as(2);
+        fill(3);
+        act_as(1);
+        fill(3);
+        let expected = ids(&stored());
+        let mut seen = vec![];
+        let mut cursor = None;
+        loop {
+            let page = get_todos_after(cursor, 3);
+            if page.is_empty() {
+                break;
+            }
+            assert!(page.len() <= 3);
+            cursor = page.last().map(|t| t.id);
+            seen.extend(ids(&page));
+        }
+        assert_eq!(seen, expected);
+        assert!(get_todos_after(None, 0).is_empty());
+    }
+
+    #[test]
+    fn get_todos_after_is_stable_under_inserts() {
+        setup();
+        fill(4);
+        let first = get_todos_after(None, 2);
+        add("late arrival");
+        let rest = get_todos_after(first.last().map(|t| t.id), 10);
+        assert_eq!(tasks(&first), vec!["task 0", "task 1"]);
+        assert_eq!(tasks(&rest), vec!["task 2", "task 3", "late arrival"]);
+    }
+
+    fn set_priority(todo_id: u128, priority: u8) {
+        patch_todo(TodoPatch {
+            priority: Some(priority),
+            ..patch(todo_id)
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn get_todos_honours_the_default_sort() {
+        setup();
+        fill(4);
+        let todo_ids = ids(&stored());
+        for (todo_id, priority) in todo_ids.iter().zip([1, 3, 2, 3]) {
+            set_priority(*todo_id, priority);
+        }
+        assert_eq!(tasks(&get_todos()), vec!["task 0", "task 1", "task 2", "task 3"]);
+
+        set_prefs(UserPrefs {
+            default_sort: Some(SortSpec {
+                key: SortKey::Priority,
+                descending: true,
+            }),
+            ..UserPrefs::default()
+        })
+        .unwrap();
+        assert_eq!(tasks(&get_todos()), vec!["task 1", "task 3", "task 2", "task 0"]);
+    }
+
+    #[test]
+    fn count_by_tag_counts_overlaps_and_untagged() {
+        setup();
+        let chores = add("do dishes");
+        let errand = add("buy milk");
+        add("read book");
+        let labelled = add("label jars");
+        add_tag(chores, "home".to_string()).unwrap();
+        add_tag(chores, "daily".to_string()).unwrap();
+        add_tag(errand, "home".to_string()).unwrap();
+        add_tag(labelled, "untagged".to_string()).unwrap();
+        let counts = count_by_tag();
+        assert_eq!(
+            counts.into_iter().collect::<Vec<_>>(),
+            vec![
+                (None, 1),
+                (Some("daily".to_string()), 1),
+                (Some("home".to_string()), 2),
+                (Some("untagged".to_string()), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_malformed_snapshot_restores_empty_and_is_kept() {
+        setup();
+        let garbage: Vec<u8> = b"DIDL not really".iter().copied().cycle().take(1 << 16).collect();
+        FAKE_STABLE.with(|stable_ref| *stable_ref.borrow_mut() = garbage.clone());
+        restore_state(user(0));
+        assert!(health().data_lost);
+        assert!(health().lost_snapshot);
+        assert_eq!(ADMIN.with(|admin_ref| *admin_ref.borrow()), Some(user(0)));
+
+        add("written after the loss");
+        act_as(0);
+        checkpoint_now().unwrap();
+        assert!(!health().data_lost);
+        TODO_BY_USER.with(|todos_ref| todos_ref.borrow_mut().clear());
+        LOST_SNAPSHOT.with(|lost_ref| lost_ref.borrow_mut().clear());
+        restore_state(user(0));
+        act_as(1);
+        assert_eq!(tasks(&get_todos()), vec!["written after the loss"]);
+        assert_eq!(LOST_SNAPSHOT.with(|lost_ref| lost_ref.borrow().clone()), garbage);
+    }
+
+    #[test]
+    fn stable_memory_without_a_snapshot_is_a_fresh_install() {
+        for blank in [vec![], vec![0; 1 << 16]] {
+            setup();
+            FAKE_STABLE.with(|stable_ref| *stable_ref.borrow_mut() = blank);
+            restore_state(user(0));
+            assert!(!health().data_lost);
+            assert!(!health().lost_snapshot);
+            assert_eq!(ADMIN.with(|admin_ref| *admin_ref.borrow()), Some(user(0)));
+        }
+    }
+
+    #[test]
+    fn snapshots_in_the_first_format_still_restore() {
+        #[derive(CandidType)]
+        struct FirstFormat {
+            counter: u128,
+            todos: BTreeMap<PrincipalName, Vec<Todo>>,
+            admin: Option<Principal>,
+            config: Config,
+        }
+        setup();
+        add("water plants");
+        let old = FirstFormat {
+            counter: NEXT_TODO.with(|counter_ref| *counter_ref.borrow()),
+            todos: TODO_BY_USER.with(|todos_ref| todos_ref.borrow().clone()),
+            admin: Some(user(0)),
+            config: Config::default(),
+        };
+        TODO_BY_USER.with(|todos_ref| todos_ref.borrow_mut().clear());
+        let mut writer = StableWriter::with_memory(stable_memory(), 0);
+        candid::write_args(&mut writer, (old,)).unwrap();
+        restore_state(user(2));
+        assert!(!health().data_lost);
+        assert_eq!(tasks(&stored()), vec!["water plants"]);
+        assert_eq!(ADMIN.with(|admin_ref| *admin_ref.borrow()), Some(user(0)));
+    }
+
+    #[test]
+    fn restore_lost_snapshot_brings_back_what_an_upgrade_dropped() {
+        setup();
+        add("water plants");
+        save_state().unwrap();
+        let bytes = FAKE_STABLE.with(|stable_ref| stable_ref.borrow().clone());
+        TODO_BY_USER.with(|todos_ref| todos_ref.borrow_mut().clear());
+        // As if an older build had failed to decode these bytes.
+        LOST_SNAPSHOT.with(|lost_ref| *lost_ref.borrow_mut() = bytes);
+        add("feed cat");
+
+        act_as(0);
+        assert!(restore_lost_snapshot().is_err());
+        set_read_only(true);
+        restore_lost_snapshot().unwrap();
+        assert!(!health().lost_snapshot);
+        assert_eq!(restore_lost_snapshot().err(), Some("no lost snapshot".to_string()));
+        act_as(1);
+        assert_eq!(tasks(&get_todos()), vec!["water plants"]);
+    }
+
+    #[test]
+    fn a_saved_snapshot_restores() {
+        setup();
+        add("water plants");
+        save_state().unwrap();
+        TODO_BY_USER.with(|todos_ref| todos_ref.borrow_mut().clear());
+        restore_state(user(0));
+        assert!(!health().data_lost);
+        assert_eq!(tasks(&stored()), vec!["water plants"]);
+    }
+
+    #[test]
+    fn validate_task_names_each_rejection() {
+        setup();
+        config(|c| {
+            c.min_todo_chars = 3;
+            c.blocklist = vec!["Spam".to_string()];
+        });
+        let too_long = "x".repeat(MAX_TODO_CHARS.with(|mtc| *mtc) + 1);
+        assert_eq!(validate_task(too_long), Err("task too long".to_string()));
+        assert_eq!(validate_task("  a ".to_string()), Err("task too short".to_string()));
+        assert_eq!(validate_task("buy spam".to_string()), Err("blocked content".to_string()));
+        assert_eq!(validate_task("buy eggs".to_string()), Ok(()));
+        assert!(stored().is_empty());
+    }
+
+    #[test]
+    fn pins_keep_pin_order_and_unpin() {
+        setup();
+        fill(3);
+        let todo_ids = ids(&stored());
+        pin_todo(todo_ids[2]).unwrap();
+        pin_todo(todo_ids[0]).unwrap();
+        pin_todo(todo_ids[2]).unwrap();
+        assert_eq!(tasks(&get_pinned()), vec!["task 2", "task 0"]);
+        unpin_todo(todo_ids[2]).unwrap();
+        unpin_todo(todo_ids[1]).unwrap();
+        assert_eq!(tasks(&get_pinned()), vec!["task 0"]);
+        assert!(pin_todo(400).is_err());
+    }
+
+    #[test]
+    #[should_panic]
+    fn pins_are_capped() {
+        setup();
+        let cap = MAX_PINS_PER_USER.with(|mppu| *mppu);
+        fill(cap + 1);
+        for todo_id in ids(&stored()) {
+            pin_todo(todo_id).unwrap();
+        }
+    }
+
+    #[test]
+    fn transfer_all_to_needs_the_recipients_consent() {
+        setup();
+        fill(2);
+        assert_eq!(transfer_all_to(user(2)), Err("transfer not accepted".to_string()));
+
+        act_as(2);
+        add("own task");
+        accept_transfer(user(1)).unwrap();
+        act_as(1);
+        assert_eq!(transfer_all_to(user(2)), Ok(2));
+        assert!(stored().is_empty());
+        act_as(2);
+        assert_eq!(tasks(&stored()), vec!["own task", "task 0", "task 1"]);
+        act_as(1);
+        add("again");
+        assert_eq!(transfer_all_to(user(2)), Err("transfer not accepted".to_string()));
+    }
+
+    #[test]
+    fn a_third_party_cannot_redirect_a_transfer() {
+        setup();
+        add("water plants");
+        act_as(2);
+        accept_transfer(user(1)).unwrap();
+        act_as(3);
+        accept_transfer(user(1)).unwrap();
+        act_as(1);
+        assert_eq!(transfer_all_to(user(2)), Ok(1));
+        act_as(2);
+        assert_eq!(tasks(&stored()), vec!["water plants"]);
+    }
+
+    /// Runs one failing and one succeeding call at [level] and returns the
+    /// lines they logged.
+    fn log_lines_at(level: LogLevel) -> Vec<String> {
+        config(|c| c.log_level = level);
+        FAKE_LOG.with(|log_ref| log_ref.borrow_mut().clear());
+        add_todo("a".to_string()).unwrap_err();
+        add("water plants");
+        FAKE_LOG.with(|log_ref| log_ref.borrow().clone())
+    }
+
+    #[test]
+    fn log_level_gates_what_is_logged() {
+        setup();
+        config(|c| c.min_todo_chars = 3);
+        LOG_SALT.with(|salt_ref| *salt_ref.borrow_mut() = vec![7; 32]);
+        assert!(log_lines_at(LogLevel::Off).is_empty());
+
+        let lines = log_lines_at(LogLevel::Error);
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0], "add_todo: failed: task too short");
+
+        assert_eq!(log_lines_at(LogLevel::Info)[1], "add_todo: ok");
+
+        let hash = principal_hash(&user(1)).unwrap();
+        let lines = log_lines_at(LogLevel::Debug);
+        assert_eq!(lines[0], format!("add_todo by {:016x}: failed: task too short", hash));
+        assert_eq!(lines[1], format!("add_todo by {:016x}: ok", hash));
+    }
+
+    #[test]
+    fn get_todos_by_last_op_filters_by_the_latest_change() {
+        setup();
+        let added = add("buy milk");
+        let updated = add("call mum");
+        let completed = add("water plants");
+        add_tag(updated, "family".to_string()).unwrap();
+        complete_todo(completed).unwrap();
+        assert_eq!(ids(&get_todos_by_last_op(TodoOp::Added)), vec![added]);
+        assert_eq!(ids(&get_todos_by_last_op(TodoOp::Updated)), vec![updated]);
+        assert_eq!(ids(&get_todos_by_last_op(TodoOp::Completed)), vec![completed]);
+    }
+
+    #[test]
+    fn reorder_todos_fully_and_partially() {
+        setup();
+        fill(4);
+        let todo_ids = ids(&stored());
+        reorder_todos(vec![todo_ids[3], todo_ids[1], todo_ids[0], todo_ids[2]]).unwrap();
+        assert_eq!(tasks(&stored()), vec!["task 3", "task 1", "task 0", "task 2"]);
+        reorder_todos(vec![todo_ids[2]]).unwrap();
+        assert_eq!(tasks(&stored()), vec!["task 2", "task 3", "task 1", "task 0"]);
+    }
+
+    #[test]
+    fn reorder_todos_rejects_unowned_ids() {
+        setup();
+        fill(2);
+        act_as(2);
+        let theirs = add("not yours");
+        act_as(1);
+        let before = ids(&stored());
+        assert_eq!(reorder_todos(vec![theirs, before[1]]), Err("todo not found".to_string()));
+        assert_eq!(ids(&stored()), before);
+    }
+
+    #[test]
+    fn total_chars_sums_across_users() {
+        setup();
+        add("água");
+        add("feed cat");
+        act_as(2);
+        add("call mum");
+        act_as(0);
+        assert_eq!(total_chars(), 4 + 8 + 8);
+    }
+
+    #[test]
+    fn upsert_todo_inserts_then_updates() {
+        setup();
+        let todo_id = upsert_todo("gh-17".to_string(), "fix login".to_string()).unwrap();
+        assert_eq!(todo(todo_id).external_key.as_deref(), Some("gh-17"));
+        assert_eq!(upsert_todo("gh-17".to_string(), "fix login page".to_string()), Ok(todo_id));
+        assert_eq!(tasks(&stored()), vec!["fix login page"]);
+        assert_ne!(upsert_todo("gh-18".to_string(), "fix logout".to_string()), Ok(todo_id));
+        assert_eq!(stored().len(), 2);
+    }
+
+    #[test]
+    fn self_report_counts_and_stable_size_grows() {
+        setup();
+        fill(2);
+        act_as(2);
+        add("call mum");
+        act_as(0);
+        let before = self_report();
+        assert_eq!((before.users, before.todos), (2, 3));
+
+        act_as(1);
+        for n in 0..200 {
+            add(&format!("{} {}", n, "padding ".repeat(100)));
+        }
+        act_as(0);
+        checkpoint_now().unwrap();
+        let after = self_report();
+        assert!(after.stable_pages > before.stable_pages);
+        assert_eq!(after.todos, 203);
+    }
+
+    #[test]
+    fn case_sensitive_search_matches_exact_case_only() {
+        setup();
+        let acronym = add("renew NASA badge");
+        let word = add("look up nasal spray");
+        assert_eq!(ids(&search_todos("NASA".to_string(), true)), vec![acronym]);
+        assert_eq!(ids(&search_todos("NASA".to_string(), false)), vec![acronym, word]);
+    }
+
+    #[test]
+    fn checkpoint_now_writes_the_current_state() {
+        setup();
+        add("water plants");
+        act_as(0);
+        checkpoint_now().unwrap();
+        let saved = candid::encode_one(read_state().unwrap()).unwrap();
+        assert_eq!(saved, candid::encode_one(snapshot_state()).unwrap());
+    }
+
+    #[test]
+    fn restore_checkpoint_undoes_later_writes() {
+        setup();
+        let kept = add("water plants");
+        act_as(0);
+        checkpoint_now().unwrap();
+        act_as(1);
+        add("feed cat");
+        delete_todo(kept).unwrap();
+
+        act_as(0);
+        assert!(restore_checkpoint().is_err());
+        set_read_only(true);
+        let counts = restore_checkpoint().unwrap();
+        assert_eq!((counts.users, counts.todos), (1, 1));
+        act_as(1);
+        assert_eq!(tasks(&get_todos()), vec!["water plants"]);
+        assert_eq!(search_todos("plants".to_string(), false).len(), 1);
+    }
+
+    #[test]
+    fn created_histogram_buckets_by_creation_time() {
+        const DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+        setup();
+        let day0 = START - START % DAY;
+        for at in [day0, day0 + DAY - 1, day0 + 3 * DAY + 5] {
+            set_now(at);
+            add("task");
+        }
+        assert_eq!(created_histogram(24 * 60 * 60), Ok(vec![(day0, 2), (day0 + 3 * DAY, 1)]));
+        assert!(created_histogram(0).is_err());
+    }
+
+    #[test]
+    fn locked_todos_reject_edits_until_unlocked() {
+        setup();
+        let todo_id = add("sign lease");
+        set_todo_locked(todo_id, true).unwrap();
+        let locked = Err("todo locked".to_string());
+        assert_eq!(update_todo(TodoUpdate { id: todo_id, task: "sign nothing".to_string() }), locked);
+        assert_eq!(delete_todo(todo_id), locked);
+        assert_eq!(complete_todo(todo_id), Err("todo locked".to_string()));
+        assert_eq!(tasks(&stored()), vec!["sign lease"]);
+
+        set_todo_locked(todo_id, false).unwrap();
+        update_todo(TodoUpdate { id: todo_id, task: "sign new lease".to_string() }).unwrap();
+        complete_todo(todo_id).unwrap();
+        delete_todo(todo_id).unwrap();
+        assert!(stored().is_empty());
+    }
+
+    fn notified() -> Vec<(Principal, Principal, u128)> {
+        FAKE_NOTIFIED.with(|notified_ref| notified_ref.borrow_mut().drain(..).collect())
+    }
+
+    #[test]
+    fn the_due_scan_notifies_each_newly_due_todo_once() {
+        setup();
+        let notifier = user(9);
+        let soon = add("pay rent");
+        let later = add("renew passport");
+        let done = add("book dentist");
+        patch_todo(TodoPatch { due_at: Some(Some(START + 10)), ..patch(soon) }).unwrap();
+        patch_todo(TodoPatch { due_at: Some(Some(START + 100)), ..patch(later) }).unwrap();
+        patch_todo(TodoPatch { due_at: Some(Some(START + 10)), ..patch(done) }).unwrap();
+        complete_todo(done).unwrap();
+        config(|c| c.notifier_canister = Some(notifier));
+        DUE_SCANNED_UNTIL.with(|until_ref| *until_ref.borrow_mut() = START);
+
+        set_now(START + 50);
+        scan_due_todos();
+        assert_eq!(notified(), vec![(notifier, user(1), soon)]);
+        scan_due_todos();
+        assert!(notified().is_empty());
+        set_now(START + 100);
+        scan_due_todos();
+        assert_eq!(notified(), vec![(notifier, user(1), later)]);
+    }
+
+    #[test]
+    fn the_due_scan_does_nothing_without_a_notifier() {
+        setup();
+        let todo_id = add("pay rent");
+        patch_todo(TodoPatch { due_at: Some(Some(START + 10)), ..patch(todo_id) }).unwrap();
+        set_now(START + 50);
+        scan_due_todos();
+        assert!(notified().is_empty());
+        assert_eq!(DUE_SCANNED_UNTIL.with(|until_ref| *until_ref.borrow()), 0);
+    }
+
+    #[test]
+    fn deduplicate_my_todos_keeps_the_oldest_open_copy() {
+        setup();
+        let oldest = add("Buy milk");
+        set_now(START + 1);
+        add(" buy MILK ");
+        let done = add("buy milk");
+        complete_todo(done).unwrap();
+        let other = add("call mum");
+        add("call mum");
+        let archived = add("call mum");
+        TODO_BY_USER.with(|todos_ref| {
+            let mut todos = todos_ref.borrow_mut();
+            let todos = todos.values_mut().next().unwrap();
+            todos.iter_mut().find(|t| t.id == archived).unwrap().archived = true;
+        });
+
+        assert_eq!(deduplicate_my_todos(), Ok(2));
+        assert_eq!(ids(&stored()), vec![oldest, done, other, archived]);
+        assert_eq!(deduplicate_my_todos(), Ok(0));
+    }
+
+    #[test]
+    fn grouped_by_tag_lists_multi_tag_todos_under_each_tag() {
+        setup();
+        let chores = add("do dishes");
+        let errand = add("buy milk");
+        let loose = add("read book");
+        let labelled = add("label jars");
+        add_tag(chores, "home".to_string()).unwrap();
+        add_tag(chores, "daily".to_string()).unwrap();
+        add_tag(errand, "home".to_string()).unwrap();
+        add_tag(labelled, "untagged".to_string()).unwrap();
+        let groups: Vec<(Option<String>, Vec<u128>)> = get_todos_grouped_by_tag()
+            .into_iter()
+            .map(|(tag, todos)| (tag, ids(&todos)))
+            .collect();
+        assert_eq!(
+            groups,
+            vec![
+                (None, vec![loose]),
+                (Some("daily".to_string()), vec![chores]),
+                (Some("home".to_string()), vec![chores, errand]),
+                (Some("untagged".to_string()), vec![labelled]),
+            ]
+        );
+    }
+
+    #[test]
+    fn update_todo_reports_missing_ids() {
+        setup();
+        let todo_id = add("water plants");
+        assert_eq!(update_todo(TodoUpdate { id: todo_id, task: "water the plants".to_string() }), Ok(()));
+        delete_todo(todo_id).unwrap();
+        add("feed cat");
+        assert_eq!(
+            update_todo(TodoUpdate { id: todo_id, task: "water them again".to_string() }),
+            Err("todo not found".to_string())
+        );
+        assert_eq!(tasks(&stored()), vec!["feed cat"]);
+    }
+
+    #[test]
+    fn viewers_discover_and_lose_shared_lists() {
+        setup();
+        add("water plants");
+        share_todos(user(2)).unwrap();
+        act_as(3);
+        add("feed cat");
+        share_todos(user(2)).unwrap();
+
+        act_as(2);
+        assert_eq!(shared_with_me(), vec![principal_key(&user(1)), principal_key(&user(3))]);
+        act_as(1);
+        unshare_todos(user(2)).unwrap();
+        act_as(2);
+        assert_eq!(shared_with_me(), vec![principal_key(&user(3))]);
+    }
+
+    #[test]
+    fn tag_limits_are_reported_and_enforced_on_import() {
+        setup();
+        config(|c| {
+            c.max_tags_per_todo = 2;
+            c.max_tag_chars = 5;
+        });
+        let reported = get_config();
+        assert_eq!((reported.max_tags_per_todo, reported.max_tag_chars), (2, 5));
+        let counts = import_todotxt("call mum +kin\nplan trip +a +b +c\nfix bike +workshop\n".to_string()).unwrap();
+        assert_eq!((counts.imported, counts.skipped), (1, 2));
+    }
+
+    #[test]
+    #[should_panic]
+    fn add_tag_enforces_tag_length() {
+        setup();
+        config(|c| c.max_tag_chars = 5);
+        let todo_id = add("fix bike");
+        add_tag(todo_id, "workshop".to_string()).unwrap();
+    }
+
+    #[test]
+    #[should_panic]
+    fn rename_tag_enforces_tag_length() {
+        setup();
+        config(|c| c.max_tag_chars = 5);
+        let todo_id = add("fix bike");
+        add_tag(todo_id, "shop".to_string()).unwrap();
+        rename_tag("shop".to_string(), "workshop".to_string()).unwrap();
+    }
+
+    #[test]
+    fn archive_completed_hides_completed_todos_everywhere() {
+        setup();
+        let first = add("water plants");
+        let open = add("feed cat");
+        let second = add("call mum");
+        complete_todo(first).unwrap();
+        complete_todo(second).unwrap();
+        set_prefs(UserPrefs { public: true, ..UserPrefs::default() }).unwrap();
+        share_todos(user(2)).unwrap();
+
+        assert_eq!(archive_completed(), Ok(2));
+        assert_eq!(ids(&get_todos()), vec![open]);
+        assert_eq!(stored().len(), 3);
+        act_as(2);
+        assert_eq!(ids(&get_public_todos(user(1))), vec![open]);
+        assert_eq!(ids(&get_shared_todos(user(1))), vec![open]);
+    }
+
+    #[test]
+    fn admin_get_todo_finds_one_todo() {
+        setup();
+        let todo_id = add("water plants");
+        act_as(0);
+        assert_eq!(admin_get_todo(user(1), todo_id).map(|t| t.id), Some(todo_id));
+        assert!(admin_get_todo(user(1), todo_id + 1).is_none());
+        assert!(admin_get_todo(user(2), todo_id).is_none());
+    }
+
+    #[test]
+    #[should_panic]
+    fn admin_get_todo_is_admin_only() {
+        setup();
+        let todo_id = add("water plants");
+        admin_get_todo(user(1), todo_id);
+    }
+
+    #[test]
+    fn the_add_bucket_allows_bursts_and_throttles_floods() {
+        setup();
+        config(|c| {
+            c.add_bucket_capacity = 3;
+            c.add_refill_per_min = 60;
+        });
+        fill(3);
+        assert_eq!(add_todo("one too many".to_string()), Err("rate limited".to_string()));
+        set_now(START + 1_000_000_000);
+        add("a second later");
+        assert_eq!(add_todo("one too many".to_string()), Err("rate limited".to_string()));
+        assert_eq!(stored().len(), 4);
+    }
+
+    #[test]
+    fn the_add_bucket_refills_under_frequent_calls() {
+        setup();
+        config(|c| {
+            c.add_bucket_capacity = 1;
+            c.add_refill_per_min = 1_000;
+        });
+        let user_str = owner_key(&user(1));
+        take_add_token(&user_str).unwrap();
+        // One add per 60 ms, polled every 40 µs for 80 ms
+        let mut granted = 0;
+        for step in 1..=2_000 {
+            set_now(START + step * 40_000);
+            granted += take_add_token(&user_str).is_ok() as u32;
+        }
+        assert_eq!(granted, 1);
+    }
+
+    #[test]
+    fn get_todos_projected_omits_parts_not_asked_for() {
+        setup();
+        let todo_id = add("file taxes");
+        add_tag(todo_id, "money".to_string()).unwrap();
+        add_attachment(todo_id, "sha256:abc".to_string()).unwrap();
+        set_priority(todo_id, 3);
+
+        let lean = &get_todos_projected(Projection {
+            include_tags: false,
+            include_attachments: false,
+            include_schedule: false,
+        })[0];
+        assert_eq!((lean.id, lean.task.text().into_owned()), (todo_id, "file taxes".to_string()));
+        assert!(lean.tags.is_none() && lean.attachments.is_none());
+        assert!(lean.priority.is_none() && lean.due_at.is_none() && lean.recurrence.is_none());
+
+        let full = &get_todos_projected(Projection {
+            include_tags: true,
+            include_attachments: true,
+            include_schedule: true,
+        })[0];
+        assert_eq!(full.tags, Some(vec!["money".to_string()]));
+        assert_eq!(full.attachments, Some(vec!["sha256:abc".to_string()]));
+        assert_eq!((full.priority, full.due_at, full.recurrence), (Some(3), Some(None), Some(None)));
+    }
+
+    #[test]
+    fn deleted_ids_show_as_tombstones_within_the_retention_window() {
+        setup();
+        let todo_id = add("water plants");
+        add("feed cat");
+        set_now(START + 5);
+        delete_todo(todo_id).unwrap();
+        let tombstones = changes_since(0).tombstones;
+        assert_eq!(tombstones.iter().map(|t| (t.id, t.at)).collect::<Vec<_>>(), vec![(todo_id, START + 5)]);
+
+        let retention = TOMBSTONE_RETENTION_SECS.with(|trs| *trs) * 1_000_000_000;
+        set_now(START + 5 + retention);
+        assert_eq!(changes_since(0).tombstones.len(), 1);
+        set_now(START + 6 + retention);
+        assert!(changes_since(0).tombstones.is_empty());
+    }
+
+    #[test]
+    fn the_default_cap_fits_the_memory_budget() {
+        assert_eq!(canister_cap(0), 500_000);
+        assert!(within_memory_budget(0));
+        assert!(within_memory_budget(500_000));
+        assert!(!within_memory_budget(500_001));
+    }
+
+    #[test]
+    fn user_limits_may_rise_up_to_the_canister_cap() {
+        let raised = BTreeMap::from([(principal_key(&user(1)), 501)]);
+        assert!(fits_canister_cap(&raised, 0));
+        assert!(fits_canister_cap(&raised, 501));
+        assert!(!fits_canister_cap(&raised, 500));
+    }
+
+    #[test]
+    #[should_panic]
+    fn set_user_limit_rejects_limits_past_the_canister_cap() {
+        setup();
+        act_as(0);
+        set_user_limit(user(1), 500_001);
+    }
+
+    #[test]
+    #[should_panic]
+    fn set_config_rejects_a_cap_below_a_user_limit() {
+        setup();
+        act_as(0);
+        set_user_limit(user(1), 501);
+        set_config(Config { max_total_todos: 500, ..Config::default() });
+    }
+
+    #[test]
+    fn the_default_cap_bounds_raised_limits() {
+        setup();
+        act_as(0);
+        set_user_limit(user(1), 600);
+        act_as(1);
+        TOTAL_TODOS.with(|total_ref| *total_ref.borrow_mut() = canister_cap(0) - 1);
+        add("water plants");
+        assert_eq!(add_todo("feed cat".to_string()), Err("canister full".to_string()));
+    }
+
+    #[test]
+    fn revert_todo_restores_an_earlier_task() {
+        setup();
+        let todo_id = add("draft");
+        update_todo(TodoUpdate { id: todo_id, task: "second draft".to_string() }).unwrap();
+        update_todo(TodoUpdate { id: todo_id, task: "final".to_string() }).unwrap();
+        assert_eq!(get_task_history(todo_id), vec!["draft", "second draft"]);
+
+        revert_todo(todo_id, 0).unwrap();
+        assert_eq!(todo(todo_id).task.text(), "draft");
+        assert_eq!(get_task_history(todo_id), vec!["draft", "second draft", "final"]);
+        assert_eq!(revert_todo(todo_id, 3), Err("history index out of range".to_string()));
+        assert_eq!(todo(todo_id).task.text(), "draft");
+    }
+
+    #[test]
+    fn get_todos_by_ids_keeps_request_order_and_skips_strangers() {
+        setup();
+        fill(3);
+        let todo_ids = ids(&stored());
+        act_as(2);
+        let theirs = add("not yours");
+        act_as(1);
+        let found = get_todos_by_ids(vec![todo_ids[2], theirs, 400, todo_ids[0]]);
+        assert_eq!(ids(&found), vec![todo_ids[2], todo_ids[0]]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn get_todos_by_ids_caps_the_id_count() {
+        setup();
+        get_todos_by_ids((0..=MAX_IDS_PER_BATCH.with(|mipb| *mipb) as u128).collect());
+    }
+
+    #[test]
+    fn a_full_list_rejects_adds_by_default() {
+        setup();
+        act_as(0);
+        set_user_limit(user(1), 2);
+        act_as(1);
+        let done = add("water plants");
+        add("feed cat");
+        complete_todo(done).unwrap();
+        assert!(std::panic::catch_unwind(|| add_todo("call mum".to_string())).is_err());
+        assert_eq!(tasks(&stored()), vec!["water plants", "feed cat"]);
+    }
+
+    #[test]
+    fn drop_oldest_completed_makes_room_while_it_can() {
+        setup();
+        act_as(0);
+        set_user_limit(user(1), 3);
+        act_as(1);
+        let older = add("water plants");
+        set_prefs(UserPrefs { on_full: FullPolicy::DropOldestCompleted, ..UserPrefs::default() }).unwrap();
+        set_now(START + 1);
+        let newer = add("feed cat");
+        add("call mum");
+        complete_todo(newer).unwrap();
+        complete_todo(older).unwrap();
+
+        add("pay rent");
+        assert_eq!(tasks(&stored()), vec!["feed cat", "call mum", "pay rent"]);
+        add("fix bike");
+        assert_eq!(tasks(&stored()), vec!["call mum", "pay rent", "fix bike"]);
+        assert!(std::panic::catch_unwind(|| add_todo("one too many".to_string())).is_err());
+        assert_eq!(stored().len(), 3);
+    }
+
+    #[test]
+    fn active_and_completed_counts_add_up() {
+        setup();
+        fill(5);
+        let todo_ids = ids(&stored());
+        complete_todo(todo_ids[1]).unwrap();
+        complete_todo(todo_ids[3]).unwrap();
+        assert_eq!((count_active(), count_completed()), (3, 2));
+        assert_eq!(count_active() + count_completed(), stored().len() as u64);
+    }
+
+    #[test]
+    fn external_keys_are_unique_per_user() {
+        setup();
+        let first = add("fix login");
+        let second = add("fix logout");
+        set_external_key(first, Some("gh-17".to_string())).unwrap();
+        assert_eq!(set_external_key(second, Some("gh-17".to_string())), Err("duplicate external key".to_string()));
+        set_external_key(first, Some("gh-17".to_string())).unwrap();
+        act_as(2);
+        let theirs = add("unrelated");
+        set_external_key(theirs, Some("gh-17".to_string())).unwrap();
+
+        act_as(1);
+        delete_todo(first).unwrap();
+        set_external_key(second, Some("gh-17".to_string())).unwrap();
+        assert_eq!(todo(second).external_key.as_deref(), Some("gh-17"));
+    }
+
+    #[test]
+    fn reassigned_ids_keep_their_external_keys() {
+        setup();
+        let todo_id = add("fix login");
+        set_external_key(todo_id, Some("gh-17".to_string())).unwrap();
+        act_as(0);
+        admin_reassign_id(user(1), todo_id, 400).unwrap();
+        act_as(1);
+        assert_eq!(upsert_todo("gh-17".to_string(), "fix login page".to_string()), Ok(400));
+        assert_eq!(tasks(&stored()), vec!["fix login page"]);
+    }
+
+    #[test]
+    fn my_role_tells_admins_users_and_strangers_apart() {
+        setup();
+        add("water plants");
+        assert!(matches!(my_role(), Role::User));
+        act_as(0);
+        assert!(matches!(my_role(), Role::Admin));
+        act_as(2);
+        assert!(matches!(my_role(), Role::Anonymous));
+        set_caller(Principal::anonymous());
+        assert!(matches!(my_role(), Role::Anonymous));
+    }
+
+    #[test]
+    fn export_chunks_reassemble_every_user() {
+        setup();
+        for n in 1..=3 {
+            act_as(n);
+            let todo_id = add(&format!("task of {}", n));
+            update_todo(TodoUpdate { id: todo_id, task: format!("chore of {}", n) }).unwrap();
+            pin_todo(todo_id).unwrap();
+            complete_todo(todo_id).unwrap();
+        }
+        share_todos(user(1)).unwrap();
+        act_as(0);
+        set_user_limit(user(2), 10);
+        let first = admin_export_chunk(None, 2);
+        let second = admin_export_chunk(first.next_cursor.clone(), 2);
+        assert!(second.next_cursor.is_none());
+        assert!(first.state.access_log.is_some() && second.state.access_log.is_none());
+
+        let full = admin_export_all();
+        let mut merged = first.state;
+        merged.todos.extend(second.state.todos);
+        assert_eq!(merged.todos.len(), 3);
+        fn merge<V>(into: &mut Option<BTreeMap<PrincipalName, V>>, from: Option<BTreeMap<PrincipalName, V>>) {
+            into.get_or_insert_with(BTreeMap::new).extend(from.unwrap_or_default());
+        }
+        merge(&mut merged.pins, second.state.pins);
+        merge(&mut merged.shares, second.state.shares);
+        merge(&mut merged.user_limits, second.state.user_limits);
+        merge(&mut merged.completions, second.state.completions);
+        merge(&mut merged.task_history, second.state.task_history);
+        merge(&mut merged.todo_order, second.state.todo_order);
+        let encoded = |state: &CanisterState| {
+            candid::encode_args((
+                &state.todos,
+                &state.pins,
+                &state.shares,
+                &state.user_limits,
+                &state.completions,
+                &state.task_history,
+                &state.todo_order,
+            ))
+            .unwrap()
+        };
+        assert_eq!(encoded(&merged), encoded(&full));
+    }
+
+    #[test]
+    #[should_panic]
+    fn export_chunks_must_hold_a_user() {
+        setup();
+        act_as(0);
+        admin_export_chunk(None, 0);
+    }
+
+    #[test]
+    fn clear_completed_deletes_completed_and_frees_quota() {
+        setup();
+        act_as(0);
+        set_user_limit(user(1), 3);
+        act_as(1);
+        fill(3);
+        let todo_ids = ids(&stored());
+        complete_todo(todo_ids[0]).unwrap();
+        complete_todo(todo_ids[2]).unwrap();
+        assert_eq!(clear_completed(), Ok(2));
+        assert_eq!(tasks(&stored()), vec!["task 1"]);
+        add("room again");
+        add("and again");
+    }
+
+    #[test]
+    fn clear_completed_archives_under_its_own_name() {
+        setup();
+        config(|c| {
+            c.clear_archives = true;
+            c.log_level = LogLevel::Info;
+            c.access_log = true;
+        });
+        LOG_SALT.with(|salt_ref| *salt_ref.borrow_mut() = vec![7; 32]);
+        let done = add("water plants");
+        let open = add("feed cat");
+        complete_todo(done).unwrap();
+        FAKE_LOG.with(|log_ref| log_ref.borrow_mut().clear());
+
+        assert_eq!(clear_completed(), Ok(1));
+        assert_eq!(ids(&get_todos()), vec![open]);
+        assert_eq!(stored().len(), 2);
+        assert_eq!(FAKE_LOG.with(|log_ref| log_ref.borrow().clone()), vec!["clear_completed: ok"]);
+        act_as(0);
+        assert_eq!(admin_access_log().last().unwrap().method, "clear_completed");
+    }
+
+    #[test]
+    fn is_hex_color_accepts_only_rrggbb() {
+        assert!(is_hex_color("#1E90ff"));
+        for bad in ["1E90FF", "#1E90F", "#1E90FFF", "#1G90FF", "", "#"] {
+            assert!(!is_hex_color(bad), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn set_todo_color_sets_rejects_and_clears() {
+        setup();
+        let todo_id = add("water plants");
+        set_todo_color(todo_id, Some("#228B22".to_string())).unwrap();
+        assert_eq!(todo(todo_id).color.as_deref(), Some("#228B22"));
+        assert_eq!(set_todo_color(todo_id, Some("green".to_string())), Err("invalid color".to_string()));
+        assert_eq!(todo(todo_id).color.as_deref(), Some("#228B22"));
+        set_todo_color(todo_id, None).unwrap();
+        assert!(todo(todo_id).color.is_none());
+    }
+
+    #[test]
+    fn admin_search_spans_owners() {
+        setup();
+        add("Report SPAM link");
+        add("water plants");
+        act_as(2);
+        add("more spam here");
+        act_as(0);
+        let mut hits: Vec<(String, String)> = admin_search("spam".to_string())
+            .into_iter()
+            .map(|(owner, todo)| (owner, todo.task.text().into_owned()))
+            .collect();
+        hits.sort();
+        let mut expected = vec![
+            (principal_key(&user(1)), "Report SPAM link".to_string()),
+            (principal_key(&user(2)), "more spam here".to_string()),
+        ];
+        expected.sort();
+        assert_eq!(hits, expected);
+    }
+
+    #[test]
+    #[should_panic]
+    fn admin_search_is_admin_only() {
+        setup();
+        add("spam");
+        admin_search("spam".to_string());
+    }
+
+    #[test]
+    fn todos_version_moves_on_writes_only() {
+        setup();
+        let todo_id = add("water plants");
+        let mut seen = vec![todos_version()];
+        get_todos();
+        search_todos("water".to_string(), false);
+        assert_eq!(todos_version(), seen[0]);
+        update_todo(TodoUpdate { id: todo_id, task: "water the plants".to_string() }).unwrap();
+        seen.push(todos_version());
+        add("feed cat");
+        seen.push(todos_version());
+        delete_todo(todo_id).unwrap();
+        seen.push(todos_version());
+        let mut distinct = seen.clone();
+        distinct.dedup();
+        assert_eq!(distinct, seen);
+    }
+
+    #[test]
+    fn import_todotxt_maps_the_todotxt_fields() {
+        setup();
+        let file = "\
+(A) 2024-01-02 Call mum +family @phone
+x 2024-01-03 2024-01-01 Pay rent +home
+
+(C) Fix bike @garage +errands
+(B)
+";
+        let counts = import_todotxt(file.to_string()).unwrap();
+        assert_eq!((counts.imported, counts.skipped), (3, 1));
+        let todos = stored();
+        assert_eq!(tasks(&todos), vec!["Call mum", "Pay rent", "Fix bike"]);
+        assert_eq!(todos.iter().map(|t| t.priority).collect::<Vec<_>>(), vec![26, 0, 24]);
+        assert_eq!(todos.iter().map(|t| t.completed).collect::<Vec<_>>(), vec![false, true, false]);
+        assert_eq!(todos[0].tags, vec!["family", "@phone"]);
+        assert_eq!(todos[2].tags, vec!["@garage", "errands"]);
+    }
+
+    #[test]
+    fn todotxt_look_alikes_in_task_text_round_trip() {
+        setup();
+        let todo_id = add("x (B) 2024-01-01 costs +5 @home \\o/ and +x");
+        set_priority(todo_id, 2);
+        for tag in ["home", "@phone", "+odd"] {
+            add_tag(todo_id, tag.to_string()).unwrap();
+        }
+        complete_todo(todo_id).unwrap();
+        let before = todo(todo_id);
+        let exported = export_todotxt();
+
+        act_as(2);
+        import_todotxt(exported.clone()).unwrap();
+        let after = stored().pop().unwrap();
+        assert_eq!(after.task.text(), before.task.text());
+        assert_eq!(after.tags, before.tags);
+        assert_eq!((after.priority, after.completed), (before.priority, before.completed));
+        assert_eq!(export_todotxt(), exported);
+    }
+
+    #[test]
+    fn a_full_todotxt_file_fits_the_batch_argument_bound() {
+        let line = "x".repeat(MAX_TODO_CHARS.with(|mtc| *mtc) * 4);
+        let file = vec![line; MAX_TODO_PER_USER.with(|mtpu| *mtpu)].join("\n");
+        let encoded = candid::encode_one(file).unwrap().len();
+        assert!(check_arg_bytes(encoded, MAX_BATCH_ARG_BYTES.with(|mbab| *mbab)).is_ok());
+        assert!(check_arg_bytes(encoded, MAX_ARG_BYTES.with(|mab| *mab)).is_err());
+    }
+
+    #[test]
+    fn export_todotxt_round_trips_through_import() {
+        setup();
+        let file = "(A) Call mum +family @phone\nx Pay rent +home\nBuy milk & eggs (organic)\n";
+        import_todotxt(file.to_string()).unwrap();
+        let exported = export_todotxt();
+        assert_eq!(exported, file);
+
+        act_as(2);
+        import_todotxt(exported.clone()).unwrap();
+        assert_eq!(export_todotxt(), exported);
+    }
+
+    #[test]
+    fn add_from_template_expands_known_placeholders_only() {
+        setup();
+        add("water plants");
+        set_task_template("call".to_string(), Some("Call {{who}} about {{what}}".to_string())).unwrap();
+        let values = BTreeMap::from([("who".to_string(), "mum".to_string())]);
+        let todo_id = add_from_template("call".to_string(), values).unwrap();
+        assert_eq!(todo(todo_id).task.text(), "Call mum about {{what}}");
+        assert!(add_from_template("missing".to_string(), BTreeMap::new()).is_err());
+    }
+
+    #[test]
+    fn add_from_template_checks_the_expanded_length() {
+        setup();
+        add("water plants");
+        set_task_template("long".to_string(), Some("{{x}}{{x}}".to_string())).unwrap();
+        let half = "y".repeat(MAX_TODO_CHARS.with(|mtc| *mtc) / 2 + 1);
+        let values = BTreeMap::from([("x".to_string(), half)]);
+        assert!(add_from_template("long".to_string(), values).is_err());
+        assert_eq!(stored().len(), 1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn anonymous_writes_are_rejected_by_default() {
+        setup();
+        set_caller(Principal::anonymous());
+        add_todo("scribble".to_string()).ok();
+    }
+
+    #[test]
+    #[should_panic]
+    fn anonymous_reads_are_rejected_by_default() {
+        setup();
+        set_caller(Principal::anonymous());
+        get_todos();
+    }
+
+    #[test]
+    fn allowed_anonymous_gets_its_own_list() {
+        setup();
+        config(|c| c.allow_anonymous = true);
+        add("private note");
+        set_caller(Principal::anonymous());
+        add("scribble");
+        assert_eq!(tasks(&get_todos()), vec!["scribble"]);
+        act_as(1);
+        assert_eq!(tasks(&get_todos()), vec!["private note"]);
+    }
+
+    #[test]
+    fn a_committed_import_keeps_every_chunk() {
+        setup();
+        add("existing");
+        let session_id = begin_import().unwrap();
+        assert_eq!(import_chunk(session_id, vec!["a".to_string(), "b".to_string()]), Ok(2));
+        assert_eq!(import_chunk(session_id, vec!["c".to_string()]), Ok(3));
+        assert_eq!(tasks(&stored()), vec!["existing"]);
+        assert_eq!(commit_import(session_id), Ok(3));
+        assert_eq!(tasks(&stored()), vec!["existing", "a", "b", "c"]);
+        assert!(import_chunk(session_id, vec!["d".to_string()]).is_err());
+    }
+
+    #[test]
+    fn an_aborted_import_leaves_the_list_unchanged() {
+        setup();
+        add("existing");
+        let session_id = begin_import().unwrap();
+        assert!(begin_import().is_err());
+        import_chunk(session_id, vec!["a".to_string(), "b".to_string()]).unwrap();
+        assert_eq!(abort_import(session_id), Ok(2));
+        assert_eq!(tasks(&stored()), vec!["existing"]);
+        assert!(begin_import().is_ok());
+    }
+
+    #[test]
+    fn an_aborted_import_keeps_completed_todos_a_full_list_could_drop() {
+        setup();
+        act_as(0);
+        set_user_limit(user(1), 2);
+        act_as(1);
+        let done = add("water plants");
+        add("feed cat");
+        complete_todo(done).unwrap();
+        set_prefs(UserPrefs { on_full: FullPolicy::DropOldestCompleted, ..UserPrefs::default() }).unwrap();
+        let session_id = begin_import().unwrap();
+        import_chunk(session_id, vec!["pay rent".to_string()]).unwrap();
+        abort_import(session_id).unwrap();
+        assert_eq!(tasks(&stored()), vec!["water plants", "feed cat"]);
+    }
+
+    #[test]
+    fn an_upgrade_drops_an_open_import_without_adding_its_chunks() {
+        setup();
+        add("existing");
+        let session_id = begin_import().unwrap();
+        import_chunk(session_id, vec!["a".to_string(), "b".to_string()]).unwrap();
+        upgrade();
+        assert_eq!(commit_import(session_id), Err("no such import".to_string()));
+        assert_eq!(tasks(&stored()), vec!["existing"]);
+        assert!(begin_import().is_ok());
+    }
+
+    #[test]
+    fn a_full_import_chunk_fits_the_batch_argument_bound() {
+        let task = "x".repeat(MAX_TODO_CHARS.with(|mtc| *mtc) * 4);
+        let chunk = vec![task; MAX_TASKS_PER_IMPORT_CHUNK.with(|mtpic| *mtpic)];
+        let encoded = candid::encode_args((u64::MAX, chunk)).unwrap().len();
+        assert!(check_arg_bytes(encoded, MAX_BATCH_ARG_BYTES.with(|mbab| *mbab)).is_ok());
+    }
+
+    #[test]
+    fn equal_sort_keys_come_out_in_id_order() {
+        setup();
+        fill(4);
+        let todo_ids = ids(&stored());
+        set_priority(todo_ids[1], 2);
+        reorder_todos(vec![todo_ids[3], todo_ids[1], todo_ids[2], todo_ids[0]]).unwrap();
+        for descending in [false, true] {
+            let mut todos = stored();
+            sort_todos(&mut todos, SortSpec { key: SortKey::Priority, descending });
+            let others = vec![todo_ids[0], todo_ids[2], todo_ids[3]];
+            let expected = if descending {
+                [vec![todo_ids[1]], others].concat()
+            } else {
+                [others, vec![todo_ids[1]]].concat()
+            };
+            assert_eq!(ids(&todos), expected);
+        }
+    }
+
+    #[test]
+    fn admin_modified_between_finds_changes_in_range() {
+        setup();
+        let early = add("water plants");
+        set_now(START + 10);
+        let inside = add("feed cat");
+        act_as(2);
+        set_now(START + 20);
+        let theirs = add("call mum");
+        act_as(1);
+        set_now(START + 30);
+        update_todo(TodoUpdate { id: early, task: "water the plants".to_string() }).unwrap();
+
+        act_as(0);
+        let mut hits: Vec<(String, u128)> =
+            admin_modified_between(START + 10, START + 20).into_iter().map(|(owner, t)| (owner, t.id)).collect();
+        hits.sort();
+        let mut expected = vec![(principal_key(&user(1)), inside), (principal_key(&user(2)), theirs)];
+        expected.sort();
+        assert_eq!(hits, expected);
+    }
+
+    #[test]
+    #[should_panic]
+    fn admin_modified_between_is_admin_only() {
+        setup();
+        add("water plants");
+        admin_modified_between(0, u64::MAX);
+    }
+
+    #[test]
+    fn my_streak_counts_consecutive_completion_days() {
+        const DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+        setup();
+        fill(5);
+        let todo_ids = ids(&stored());
+        // Days 0 and 2..=4 have completions; day 1 breaks the streak.
+        for (todo_id, day) in todo_ids.iter().zip([0, 2, 3, 3, 4]) {
+            set_now(START + day * DAY);
+            complete_todo(*todo_id).unwrap();
+        }
+        let first = day_of(START);
+        let streak = my_streak();
+        assert_eq!(streak.current, 3);
+        assert_eq!(streak.days, vec![(first, 1), (first + 2, 1), (first + 3, 2), (first + 4, 1)]);
+
+        set_now(START + 5 * DAY);
+        assert_eq!(my_streak().current, 3);
+        set_now(START + 6 * DAY);
+        assert_eq!(my_streak().current, 0);
+    }
+
+    #[test]
+    fn repair_state_fixes_ids_once() {
+        setup();
+        fill(3);
+        let user_str = owner_key(&user(1));
+        TODO_BY_USER.with(|todos_ref| {
+            let mut todos = todos_ref.borrow_mut();
+            let todos = todos.get_mut(&user_str).unwrap();
+            todos[1].id = todos[0].id;
+            todos[2].id = 300;
+        });
+        NEXT_TODO.with(|counter_ref| *counter_ref.borrow_mut() = 1);
+
+        act_as(0);
+        let report = repair_state();
+        assert_eq!((report.ids_reassigned, report.counter_before), (1, 1));
+        assert!(report.counter_after > 300);
+        act_as(1);
+        let mut todo_ids = ids(&stored());
+        todo_ids.sort();
+        todo_ids.dedup();
+        assert_eq!(todo_ids.len(), 3);
+
+        act_as(0);
+        let again = repair_state();
+        assert_eq!((again.ids_reassigned, again.counter_before, again.counter_after), (0, report.counter_after, report.counter_after));
+        act_as(1);
+        assert!(add("fresh") > 300);
+    }
+
+    /// Runs [pre_upgrade], drops all heap state and runs [post_upgrade].
+    fn upgrade() {
+        // With a salt already set, post_upgrade needs no timer to draw one.
+        LOG_SALT.with(|salt_ref| *salt_ref.borrow_mut() = vec![7; 32]);
+        pre_upgrade();
+        TODO_BY_USER.with(|todos_ref| todos_ref.borrow_mut().clear());
+        VERSIONS.with(|versions_ref| versions_ref.borrow_mut().clear());
+        USER_STATS.with(|stats_ref| stats_ref.borrow_mut().clear());
+        TOTAL_TODOS.with(|total_ref| *total_ref.borrow_mut() = 0);
+        SEARCH_CACHE.with(|cache_ref| cache_ref.borrow_mut().clear());
+        EXTERNAL_KEYS.with(|keys_ref| keys_ref.borrow_mut().clear());
+        UNINDEXED.with(|unindexed_ref| unindexed_ref.borrow_mut().clear());
+        IMPORT_SESSIONS.with(|sessions_ref| sessions_ref.borrow_mut().clear());
+        UPGRADES.with(|upgrades_ref| upgrades_ref.borrow_mut().clear());
+        post_upgrade();
+    }
+
+    #[test]
+    fn cached_counts_track_writes_and_survive_upgrades() {
+        setup();
+        let first = add("water plants");
+        let second = add("feed cat");
+        add("call mum");
+        update_todo(TodoUpdate { id: second, task: "feed the cat".to_string() }).unwrap();
+        delete_todo(first).unwrap();
+        complete_todo(second).unwrap();
+        let checked = |count: u64| {
+            assert_eq!(count_todos(), count);
+            assert_eq!(count_todos(), stored().len() as u64);
+            assert_eq!(user_stats(&owner_key(&user(1))).chars_used, 12 + 8);
+        };
+        checked(2);
+        let version = todos_version();
+
+        upgrade();
+        checked(2);
+        assert_eq!(todos_version(), version);
+        assert_eq!(ids(&search_todos("cat".to_string(), false)), vec![second]);
+        add("pay rent");
+        assert_eq!(count_todos(), 3);
+        assert_ne!(todos_version(), version);
+    }
+
+    #[test]
+    fn blocklist_rejects_terms_ignoring_case() {
+        setup();
+        // The filter is opt-in, so nothing is blocked by default.
+        add("buy spam");
+        config(|c| c.blocklist = vec!["Spam".to_string()]);
+        assert_eq!(add_todo("buy SPAM".to_string()), Err("blocked content".to_string()));
+        let clean = add("buy milk");
+        assert_eq!(
+            update_todo(TodoUpdate { id: clean, task: "buy sPaM".to_string() }),
+            Err("blocked content".to_string())
+        );
+        assert_eq!(tasks(&stored()), vec!["buy spam", "buy milk"]);
+    }
+
+    #[test]
+    fn activity_window_spans_first_creation_to_last_change() {
+        setup();
+        let window = my_activity_window();
+        assert_eq!((window.first_created_at, window.last_updated_at), (None, None));
+
+        let first = add("water plants");
+        set_now(START + 10);
+        add("feed cat");
+        set_now(START + 20);
+        complete_todo(first).unwrap();
+        let window = my_activity_window();
+        assert_eq!(window.first_created_at, Some(START));
+        assert_eq!(window.last_updated_at, Some(START + 20));
+
+        act_as(2);
+        let window = my_activity_window();
+        assert_eq!((window.first_created_at, window.last_updated_at), (None, None));
+    }
+
+    #[test]
+    fn completing_twice_spawns_one_occurrence() {
+        setup();
+        let todo_id = add("water plants");
+        set_recurrence(todo_id, 60).unwrap();
+        assert_eq!(complete_todo(todo_id), Ok(true));
+        assert_eq!(complete_todo(todo_id), Ok(false));
+        let todos = stored();
+        assert_eq!(tasks(&todos), vec!["water plants", "water plants"]);
+        assert!(todos[0].completed);
+        assert!(!todos[1].completed);
+        assert_eq!(complete_todo(999), Err("todo not found".to_string()));
+    }
+
+    #[test]
+    fn someday_lists_open_undated_todos_only() {
+        setup();
+        let scheduled = add("pay rent");
+        patch_todo(TodoPatch { due_at: Some(Some(START + 10)), ..patch(scheduled) }).unwrap();
+        add("learn piano");
+        let done = add("read a book");
+        complete_todo(done).unwrap();
+        add("visit Rome");
+        assert_eq!(tasks(&get_someday()), vec!["learn piano", "visit Rome"]);
+    }
+
+    #[test]
+    fn set_priority_where_changes_matching_todos_only() {
+        setup();
+        let home = add("water plants");
+        let work = add("send report");
+        let done = add("feed cat");
+        add_tag(home, "home".to_string()).unwrap();
+        add_tag(done, "home".to_string()).unwrap();
+        add_tag(work, "work".to_string()).unwrap();
+        complete_todo(done).unwrap();
+        let open_home = Filter {
+            tag: Some("home".to_string()),
+            overdue: None,
+            completed: Some(false),
+        };
+        assert_eq!(set_priority_where(open_home.clone(), 3), Ok(1));
+        assert_eq!(set_priority_where(open_home, 3), Ok(0));
+        assert_eq!(todo(home).priority, 3);
+        assert_eq!(todo(work).priority, 0);
+        assert_eq!(todo(done).priority, 0);
+    }
+
+    #[test]
+    fn a_full_canister_rejects_adds_from_every_user() {
+        setup();
+        config(|c| c.max_total_todos = 3);
+        let first = add("water plants");
+        add("feed cat");
+        act_as(2);
+        add("pay rent");
+        for n in 1..=3 {
+            act_as(n);
+            assert_eq!(add_todo("call mum".to_string()), Err("canister full".to_string()));
+            assert_eq!(import_todotxt("call mum".to_string()).map(|_| ()), Err("canister full".to_string()));
+        }
+        act_as(1);
+        delete_todo(first).unwrap();
+        act_as(3);
+        add("call mum");
+        assert_eq!(add_todo("call dad".to_string()), Err("canister full".to_string()));
+    }
+
+    #[test]
+    fn rename_tag_renames_and_dedupes() {
+        setup();
+        let plain = add("water plants");
+        let both = add("feed cat");
+        let other = add("send report");
+        add_tag(plain, "hme".to_string()).unwrap();
+        add_tag(both, "home".to_string()).unwrap();
+        add_tag(both, "hme".to_string()).unwrap();
+        add_tag(other, "work".to_string()).unwrap();
+        assert_eq!(rename_tag("hme".to_string(), "home".to_string()), Ok(2));
+        assert_eq!(todo(plain).tags, vec!["home"]);
+        assert_eq!(todo(both).tags, vec!["home"]);
+        assert_eq!(todo(other).tags, vec!["work"]);
+        assert_eq!(rename_tag("hme".to_string(), "home".to_string()), Ok(0));
+    }
+
+    #[test]
+    fn preview_import_todotxt_counts_without_storing() {
+        setup();
+        config(|c| c.min_todo_chars = 3);
+        let preview = preview_import_todotxt("water plants\n(A) feed cat +home\n".to_string());
+        assert_eq!((preview.valid, preview.invalid, preview.would_exceed_quota), (2, 0, false));
+
+        let preview = preview_import_todotxt("water plants\n\na\nx \n".to_string());
+        assert_eq!((preview.valid, preview.invalid, preview.would_exceed_quota), (1, 2, false));
+        assert_eq!(import_todotxt("water plants\n\na\nx \n".to_string()).map(|c| (c.imported, c.skipped)), Ok((1, 2)));
+
+        config(|c| c.max_total_todos = 2);
+        let preview = preview_import_todotxt("feed cat\npay rent".to_string());
+        assert_eq!((preview.valid, preview.invalid, preview.would_exceed_quota), (2, 0, true));
+        assert_eq!(tasks(&stored()), vec!["water plants"]);
+    }
+
+    #[test]
+    fn preview_import_todotxt_counts_the_welcome_todo() {
+        setup();
+        config(|c| c.seed_first_todo = Some("welcome".to_string()));
+        act_as(0);
+        set_user_limit(user(1), 2);
+        act_as(1);
+        assert!(!preview_import_todotxt("feed cat\n".to_string()).would_exceed_quota);
+        assert!(preview_import_todotxt("feed cat\npay rent\n".to_string()).would_exceed_quota);
+        assert!(std::panic::catch_unwind(|| import_todotxt("feed cat\npay rent\n".to_string())).is_err());
+    }
+
+    #[test]
+    fn a_fake_clock_drives_overdue_and_recurrence() {
+        const HOUR: u64 = 60 * 60 * 1_000_000_000;
+        setup();
+        let todo_id = add("water plants");
+        patch_todo(TodoPatch { due_at: Some(Some(START + HOUR)), ..patch(todo_id) }).unwrap();
+        set_recurrence(todo_id, 24 * HOUR).unwrap();
+        assert!(get_grouped().overdue.is_empty());
+        set_now(START + HOUR);
+        assert!(get_grouped().overdue.is_empty());
+        set_now(START + HOUR + 1);
+        assert_eq!(ids(&get_grouped().overdue), vec![todo_id]);
+
+        set_now(START + 2 * HOUR);
+        complete_todo(todo_id).unwrap();
+        let next = stored().pop().unwrap();
+        assert_ne!(next.id, todo_id);
+        assert_eq!(next.due_at, Some(START + 25 * HOUR));
+        assert_eq!(next.created_at, START + 2 * HOUR);
+        assert!(get_grouped().overdue.is_empty());
+        set_now(START + 26 * HOUR);
+        assert_eq!(ids(&get_grouped().overdue), vec![next.id]);
+    }
+
+    #[test]
+    fn annotations_follow_due_dates_at_the_fake_clock() {
+        const SEC: u64 = 1_000_000_000;
+        setup();
+        let late = add("pay rent");
+        set_now(START + 30 * SEC);
+        let soon = add("feed cat");
+        let later = add("call mum");
+        let done = add("water plants");
+        let due = |todo_id, due_at| {
+            patch_todo(TodoPatch { due_at: Some(Some(due_at)), ..patch(todo_id) }).unwrap();
+        };
+        due(late, START + 50 * SEC);
+        due(soon, START + 3600 * SEC);
+        due(later, START + 3 * 24 * 3600 * SEC);
+        due(done, START + 50 * SEC);
+        complete_todo(done).unwrap();
+
+        set_now(START + 100 * SEC);
+        let flags: Vec<(u128, bool, bool, u64)> = get_todos_annotated()
+            .into_iter()
+            .map(|a| (a.todo.id, a.is_overdue, a.is_due_soon, a.age_secs))
+            .collect();
+        assert_eq!(
+            flags,
+            vec![
+                (late, true, false, 100),
+                (soon, false, true, 70),
+                (later, false, false, 70),
+                (done, false, false, 70),
+            ]
+        );
+    }
+
+    #[test]
+    fn ids_stay_contiguous_across_rejected_adds() {
+        setup();
+        config(|c| {
+            c.max_total_todos = 2;
+            c.min_todo_chars = 3;
+        });
+        let first = add("water plants");
+        assert_eq!(add_todo("a".to_string()), Err("task too short".to_string()));
+        assert_eq!(add("feed cat"), first + 1);
+        assert_eq!(add_todo("call mum".to_string()), Err("canister full".to_string()));
+        delete_todo(first).unwrap();
+        assert_eq!(add("call mum"), first + 2);
+        assert_eq!(ids(&stored()), vec![first + 1, first + 2]);
+    }
+
+    #[test]
+    fn list_methods_matches_the_exported_endpoints() {
+        // list_methods reads the `.did`; every endpoint in the source must
+        // be there exactly once, and nothing else.
+        let source = include_str!("lib.rs");
+        let mut endpoints: Vec<String> = source
+            .lines()
+            .filter_map(|line| {
+                line.strip_prefix("#[query(name = \"")
+                    .or_else(|| line.strip_prefix("#[update(name = \""))
+            })
+            .filter_map(|rest| rest.split_once('"'))
+            .map(|(name, _)| name.to_string())
+            .collect();
+        endpoints.sort();
+        let methods = list_methods();
+        let mut sorted = methods.clone();
+        sorted.sort();
+        assert_eq!(sorted, endpoints);
+        for known in ["add_todo", "get_todos", "list_methods", "todo_exists"] {
+            assert!(methods.iter().any(|m| m == known), "{} missing", known);
+        }
+    }
+
+    #[test]
+    fn focus_mode_shows_only_the_top_open_todos() {
+        setup();
+        fill(4);
+        let todo_ids = ids(&stored());
+        set_priority(todo_ids[2], 3);
+        patch_todo(TodoPatch { due_at: Some(Some(START + 10)), ..patch(todo_ids[3]) }).unwrap();
+        complete_todo(todo_ids[0]).unwrap();
+        assert_eq!(get_todos().len(), 4);
+
+        set_prefs(UserPrefs { focus: Some(2), ..Default::default() }).unwrap();
+        assert_eq!(tasks(&get_todos()), vec!["task 2", "task 3"]);
+        assert_eq!(get_all_todos().len(), 4);
+
+        set_prefs(UserPrefs::default()).unwrap();
+        assert_eq!(tasks(&get_todos()), vec!["task 0", "task 1", "task 2", "task 3"]);
+    }
+
+    #[test]
+    fn upgrades_are_recorded_with_the_crate_version() {
+        setup();
+        // What init records; init itself needs the timer API.
+        record_upgrade();
+        set_now(START + 5);
+        upgrade();
+        act_as(0);
+        let history: Vec<(String, u64)> = upgrade_history().into_iter().map(|e| (e.version, e.at)).collect();
+        let version = env!("CARGO_PKG_VERSION").to_string();
+        assert_eq!(history, vec![(version.clone(), START), (version, START + 5)]);
+
+        let max_history = MAX_UPGRADE_HISTORY.with(|muh| *muh);
+        for _ in 0..max_history {
+            record_upgrade();
+        }
+        let history = upgrade_history();
+        assert_eq!(history.len(), max_history);
+        assert!(history.iter().all(|e| e.at == START + 5));
+    }
+
+    #[test]
+    fn exports_are_byte_identical_and_restore_the_list_order() {
+        setup();
+        fill(3);
+        let todo_ids = ids(&stored());
+        let reordered = vec![todo_ids[2], todo_ids[0], todo_ids[1]];
+        reorder_todos(reordered.clone()).unwrap();
+        let todotxt = export_todotxt();
+        assert_eq!(todotxt, "task 0\ntask 1\ntask 2\n");
+        assert_eq!(export_todotxt(), todotxt);
+
+        act_as(0);
+        let snapshot = admin_export_all();
+        assert_eq!(candid::encode_one(&snapshot).unwrap(), candid::encode_one(admin_export_all()).unwrap());
+        let user_str = principal_key(&user(1));
+        assert_eq!(ids(&snapshot.todos[&user_str]), todo_ids);
+        assert_eq!(snapshot.todo_order.as_ref().unwrap()[&user_str], reordered);
+        let chunk = admin_export_chunk(None, 1).state;
+        assert_eq!(ids(&chunk.todos[&user_str]), todo_ids);
+        assert_eq!(chunk.todo_order.unwrap()[&user_str], reordered);
+
+        act_as(1);
+        reorder_todos(todo_ids.clone()).unwrap();
+        act_as(0);
+        set_read_only(true);
+        admin_restore(snapshot).unwrap();
+        act_as(1);
+        assert_eq!(ids(&stored()), reordered);
+    }
+
+    #[test]
+    fn todo_exists_checks_ownership() {
+        setup();
+        let mine = add("water plants");
+        act_as(2);
+        let theirs = add("feed cat");
+        act_as(1);
+        assert!(todo_exists(mine));
+        assert!(!todo_exists(theirs));
+        assert!(!todo_exists(400));
+        delete_todo(mine).unwrap();
+        assert!(!todo_exists(mine));
+    }
+
+    #[test]
+    #[should_panic]
+    fn todo_exists_rejects_anonymous_callers() {
+        setup();
+        set_caller(Principal::anonymous());
+        todo_exists(0);
+    }
+
+    #[test]
+    fn per_todo_edits_report_ids_the_caller_does_not_own() {
+        setup();
+        add("water plants");
+        act_as(2);
+        let theirs = add("feed cat");
+        act_as(1);
+        for todo_id in [theirs, 400] {
+            let not_found = Err("todo not found".to_string());
+            assert_eq!(add_tag(todo_id, "home".to_string()), not_found);
+            assert_eq!(remove_tag(todo_id, "home".to_string()), not_found);
+            assert_eq!(add_attachment(todo_id, "sha256:abc".to_string()), not_found);
+            assert_eq!(remove_attachment(todo_id, "sha256:abc".to_string()), not_found);
+            assert_eq!(set_recurrence(todo_id, 60), not_found);
+            assert_eq!(cancel_recurrence(todo_id), not_found);
+            assert_eq!(set_todo_locked(todo_id, true), not_found);
+        }
+        act_as(2);
+        let untouched = todo(theirs);
+        assert!(untouched.tags.is_empty() && untouched.recurrence.is_none() && !untouched.locked);
+    }
+
+    #[test]
+    fn every_per_todo_mutator_refuses_a_locked_todo() {
+        setup();
+        let todo_id = add("sign lease");
+        add_tag(todo_id, "home".to_string()).unwrap();
+        add_attachment(todo_id, "sha256:abc".to_string()).unwrap();
+        set_todo_locked(todo_id, true).unwrap();
+        let before = todo(todo_id).updated_at;
+        assert_eq!(snooze_todo(todo_id, 60), Err("todo locked".to_string()));
+        let locked = Err("todo locked".to_string());
+        assert_eq!(add_tag(todo_id, "work".to_string()), locked);
+        assert_eq!(remove_tag(todo_id, "home".to_string()), locked);
+        assert_eq!(add_attachment(todo_id, "sha256:def".to_string()), locked);
+        assert_eq!(remove_attachment(todo_id, "sha256:abc".to_string()), locked);
+        assert_eq!(set_recurrence(todo_id, 60), locked);
+        assert_eq!(cancel_recurrence(todo_id), locked);
+        assert_eq!(set_external_key(todo_id, Some("gh-17".to_string())), locked);
+        let unchanged = todo(todo_id);
+        assert_eq!(unchanged.tags, vec!["home"]);
+        assert_eq!(unchanged.attachments, vec!["sha256:abc"]);
+        assert!(unchanged.recurrence.is_none() && unchanged.external_key.is_none());
+        assert_eq!(unchanged.updated_at, before);
+
+        set_todo_locked(todo_id, false).unwrap();
+        add_tag(todo_id, "work".to_string()).unwrap();
+        set_recurrence(todo_id, 60).unwrap();
+        set_external_key(todo_id, Some("gh-17".to_string())).unwrap();
+        assert!(snooze_todo(todo_id, 60).is_ok());
+    }
+
+    type IndexSnapshot = (Vec<(u128, String, u64, bool, Option<String>)>, (u64, u64, u64), Vec<(String, u128)>, u64);
+
+    fn index_snapshot(user_str: &str) -> IndexSnapshot {
+        let entries = SEARCH_CACHE.with(|cache_ref| {
+            cache_ref.borrow()[user_str]
+                .iter()
+                .map(|(&id, e)| (id, e.lowered.clone(), e.chars, e.completed, e.external_key.clone()))
+                .collect()
+        });
+        let stats = USER_STATS.with(|stats_ref| {
+            let s = stats_ref.borrow()[user_str];
+            (s.todos, s.completed, s.chars_used)
+        });
+        let keys = EXTERNAL_KEYS.with(|keys_ref| {
+            keys_ref.borrow()[user_str].iter().map(|(k, &id)| (k.clone(), id)).collect()
+        });
+        (entries, stats, keys, TOTAL_TODOS.with(|total_ref| *total_ref.borrow()))
+    }
+
+    #[test]
+    fn single_todo_writes_reindex_only_what_they_touch() {
+        setup();
+        act_as(0);
+        set_user_limit(user(1), 4);
+        act_as(1);
+        let old = add("Water Plants");
+        let cat = add("Feed CAT");
+        let rent = add("pay rent");
+        set_external_key(rent, Some("gh-1".to_string())).unwrap();
+        update_todo(TodoUpdate { id: cat, task: "Feed the Cat".to_string() }).unwrap();
+        complete_todo(old).unwrap();
+        set_recurrence(cat, 60).unwrap();
+        complete_todo(cat).unwrap();
+        set_prefs(UserPrefs { on_full: FullPolicy::DropOldestCompleted, ..UserPrefs::default() }).unwrap();
+        upsert_todo("gh-2".to_string(), "Fix BIKE".to_string()).unwrap();
+        upsert_todo("gh-1".to_string(), "Pay Rent".to_string()).unwrap();
+        let bike = todo_by_external_key(&owner_key(&user(1)), "gh-2").unwrap();
+        merge_todos(rent, bike).unwrap();
+        delete_todo(rent).unwrap();
+        assert!(UNINDEXED.with(|unindexed_ref| unindexed_ref.borrow().is_empty()));
+        assert_eq!(search_todos("the cat".to_string(), false).len(), 2);
+        assert!(search_todos("rent".to_string(), false).is_empty());
+
+        let user_str = owner_key(&user(1));
+        let incremental = index_snapshot(&user_str);
+        reindex_search(&user_str);
+        assert_eq!(index_snapshot(&user_str), incremental);
+        assert_eq!(incremental.1, (2, 1, 24));
+    }
+
+    #[test]
+    fn recurrences_respect_the_canister_cap() {
+        setup();
+        config(|c| c.max_total_todos = 2);
+        let daily = add("water plants");
+        add("feed cat");
+        set_recurrence(daily, 60).unwrap();
+        assert_eq!(complete_todo(daily), Err("canister full".to_string()));
+        assert_eq!(
+            patch_todo(TodoPatch { completed: Some(true), ..patch(daily) }),
+            Err("canister full".to_string())
+        );
+        assert!(!todo(daily).completed);
+        assert_eq!(stored().len(), 2);
+    }
+
+    #[test]
+    fn a_full_canister_still_takes_adds_that_replace_a_completed_todo() {
+        setup();
+        config(|c| c.max_total_todos = 2);
+        act_as(0);
+        set_user_limit(user(1), 2);
+        act_as(1);
+        let done = add("water plants");
+        add("feed cat");
+        complete_todo(done).unwrap();
+        assert_eq!(add_todo("pay rent".to_string()), Err("canister full".to_string()));
+        set_prefs(UserPrefs { on_full: FullPolicy::DropOldestCompleted, ..UserPrefs::default() }).unwrap();
+        add("pay rent");
+        assert_eq!(tasks(&stored()), vec!["feed cat", "pay rent"]);
+        // Nothing left to replace, so the user's own limit stops it
+        assert!(std::panic::catch_unwind(|| add_todo("call mum".to_string())).is_err());
+    }
+
+    #[test]
+    fn admin_mutations_are_access_logged() {
+        setup();
+        let todo_id = add("water plants");
+        act_as(2);
+        add("feed cat");
+        act_as(0);
+        config(|c| c.access_log = true);
+        LOG_SALT.with(|salt_ref| *salt_ref.borrow_mut() = vec![7; 32]);
+        set_config(Config { access_log: true, ..Config::default() });
+        set_user_limit(user(1), 10);
+        admin_reassign_id(user(1), todo_id, 40).unwrap();
+        repair_state();
+        admin_delete_user(user(2));
+        checkpoint_now().unwrap();
+        set_read_only(true);
+        admin_restore(admin_export_all()).unwrap();
+        let latest: Vec<String> = admin_access_log().into_iter().rev().take(2).map(|e| e.method).collect();
+        assert_eq!(latest, vec!["admin_restore", "set_read_only"]);
+
+        // A restore brings back the checkpoint's log, then logs itself
+        restore_checkpoint().unwrap();
+        let log = admin_access_log();
+        let methods: Vec<&str> = log.iter().map(|e| e.method.as_str()).collect();
+        assert_eq!(
+            methods,
+            vec![
+                "set_config",
+                "set_user_limit",
+                "admin_reassign_id",
+                "repair_state",
+                "admin_delete_user",
+                "checkpoint_now",
+                "restore_checkpoint",
+            ]
+        );
+        assert!(log.iter().all(|e| Some(e.principal_hash) == principal_hash(&user(0))));
+    }
+
+    #[test]
+    fn update_todo_takes_the_original_todo_shape() {
+        setup();
+        #[derive(CandidType)]
+        struct OriginalTodo {
+            id: u128,
+            task: String,
+        }
+        let original = candid::encode_one(OriginalTodo { id: 7, task: "feed cat".to_string() }).unwrap();
+        let update: TodoUpdate = candid::decode_one(&original).unwrap();
+        assert_eq!((update.id, update.task.as_str()), (7, "feed cat"));
+
+        // A client sending the whole current record still decodes too
+        let current = candid::encode_one(Todo::new(7, "feed cat".to_string())).unwrap();
+        assert!(candid::decode_one::<TodoUpdate>(&current).is_ok());
+    }
+
+    #[test]
+    fn admin_reassign_id_carries_the_side_tables_along() {
+        setup();
+        let todo_id = add("water plants");
+        update_todo(TodoUpdate { id: todo_id, task: "water the plants".to_string() }).unwrap();
+        pin_todo(todo_id).unwrap();
+        set_external_key(todo_id, Some("gh-17".to_string())).unwrap();
+        act_as(0);
+        admin_reassign_id(user(1), todo_id, 40).unwrap();
+
+        act_as(1);
+        assert_eq!(get_task_history(40), vec!["water plants"]);
+        assert!(get_task_history(todo_id).is_empty());
+        assert_eq!(PINS.with(|pins_ref| pins_ref.borrow()[&owner_key(&user(1))].clone()), vec![40]);
+        assert_eq!(ids(&search_todos("the plants".to_string(), false)), vec![40]);
+        assert_eq!(upsert_todo("gh-17".to_string(), "water the ferns".to_string()), Ok(40));
+        assert_eq!(tasks(&stored()), vec!["water the ferns"]);
+        revert_todo(40, 0).unwrap();
+        assert_eq!(todo(40).task.text(), "water plants");
+    }
+
+    #[test]
+    fn oversized_ingress_is_dropped_before_decoding() {
+        let max_bytes = MAX_ARG_BYTES.with(|mab| *mab);
+        let color = candid::encode_args((1u128, Some("#".repeat(max_bytes)))).unwrap();
+        assert!(!accepts_message("set_todo_color", color.len()));
+        let filter = Filter { tag: Some("t".repeat(max_bytes)), overdue: None, completed: None };
+        let filter = candid::encode_args((filter, 1u8)).unwrap();
+        assert!(!accepts_message("set_priority_where", filter.len()));
+        // A full reorder of the widest ids is a batch, not a single task
+        let ids = vec![u128::MAX; MAX_TODO_PER_USER.with(|mtpu| *mtpu)];
+        let reorder = candid::encode_one(&ids).unwrap();
+        assert!(reorder.len() > max_bytes);
+        assert!(accepts_message("reorder_todos", reorder.len()));
+        let flood = candid::encode_one(vec![u128::MAX; MAX_BATCH_ARG_BYTES.with(|mbab| *mbab) / 8]).unwrap();
+        assert!(!accepts_message("reorder_todos", flood.len()));
+        assert!(accepts_message("set_todo_color", candid::encode_args((1u128, Some("#a0b1c2"))).unwrap().len()));
+    }
+}